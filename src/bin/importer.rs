@@ -1,24 +1,88 @@
 use anyhow::anyhow;
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
 use auxon_sdk::plugin_utils::serde::from_str;
 use auxon_sdk::{init_tracing, plugin_utils::ingest::Config};
-use barectf_parser::{Config as BarectfConfig, Parser};
+use barectf_parser::{Config as BarectfConfig, Packet};
 use clap::Parser as ClapParser;
-use modality_barectf_plugin::{CommonConfig, HasCommonConfig, Sender, PLUGIN_VERSION};
+use futures_util::{future::try_join_all, stream::select_all};
+use modality_barectf_plugin::{
+    load_barectf_configs, merge::packet_sort_key, routing::RoutingDecoder, CommonConfig, ConfigId,
+    HasCommonConfig, Output, OutputFormat, Sender, Sink, SourceId, PLUGIN_VERSION,
+};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use tokio::{fs, io::BufReader};
-use tokio_stream::StreamExt;
-use tokio_util::codec::FramedRead;
-use tracing::{error, info};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+use tokio::{
+    fs,
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader, ReadBuf},
+    time::{Duration, Instant, Sleep},
+};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, FramedRead},
+    sync::CancellationToken,
+};
+use tracing::{debug, error, info, warn};
+
+/// The TSDL metadata file name within a CTF trace directory
+const TSDL_METADATA_FILE_NAME: &str = "metadata";
+
+/// The stream file path meaning standard input
+const STDIN_PATH: &str = "-";
 
 /// Import barectf stream files
 #[derive(Debug, clap::Parser)]
 #[clap(version, about = "Import barectf stream files", long_about = None)]
 struct ImporterOpts {
-    /// The barectf effective-configuration yaml file
+    /// The barectf effective-configuration yaml file, a CTF TSDL metadata file,
+    /// a CTF trace directory, or a tar/zip archive containing them
     config: Option<PathBuf>,
 
-    /// The binary CTF stream file(s)
+    /// An additional barectf configuration file, for traces from other
+    /// firmware variants. The configuration is selected by trace UUID.
+    ///
+    /// Can be supplied multiple times
+    #[clap(long = "additional-config", name = "additional-config")]
+    additional_configs: Vec<PathBuf>,
+
+    /// Write the converted timelines and events to this file instead of
+    /// sending them to Modality
+    #[clap(long, name = "output")]
+    output: Option<PathBuf>,
+
+    /// The output file format: `jsonl`, `csv` or `chrome-trace`.
+    /// The default is based on the output file extension, falling back to `jsonl`.
+    #[clap(long, name = "output-format")]
+    output_format: Option<OutputFormat>,
+
+    /// Keep reading the stream files as they grow, until the user signals shutdown,
+    /// the stream files are idle for `follow-idle-timeout`, or the `stop-event` is received
+    #[clap(long, name = "follow")]
+    follow: bool,
+
+    /// How often to check the followed stream files for new data.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "follow-poll-interval")]
+    follow_poll_interval: Option<String>,
+
+    /// Stop following a stream file once it hasn't grown for this long.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "follow-idle-timeout")]
+    follow_idle_timeout: Option<String>,
+
+    /// An event name to consider as the end of the trace, the import
+    /// stops once it's received
+    #[clap(long, name = "stop-event")]
+    stop_event: Option<String>,
+
+    /// The binary CTF stream file(s), CTF trace directories or tar/zip archives.
+    /// Use `-` to read a stream from stdin, named pipes are read until EOF.
     ///
     /// Can be supplied multiple times
     file: Vec<PathBuf>,
@@ -27,10 +91,28 @@ struct ImporterOpts {
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
 struct ImporterConfig {
-    /// The binary CTF stream(s) file
+    /// The binary CTF stream(s) file or CTF trace directory
     #[serde(deserialize_with = "from_str")]
     file: Option<PathBuf>,
 
+    /// Keep reading the stream files as they grow
+    #[serde(deserialize_with = "from_str")]
+    follow: Option<bool>,
+
+    /// How often to check the followed stream files for new data.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[serde(deserialize_with = "from_str", alias = "follow_poll_interval")]
+    follow_poll_interval: Option<String>,
+
+    /// Stop following a stream file once it hasn't grown for this long.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[serde(deserialize_with = "from_str", alias = "follow_idle_timeout")]
+    follow_idle_timeout: Option<String>,
+
+    /// An event name to consider as the end of the trace
+    #[serde(alias = "stop_event")]
+    stop_event: Option<String>,
+
     #[serde(flatten)]
     common: CommonConfig,
 }
@@ -52,6 +134,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Time domain is handled expclicitly by the plugin
     config.time_domain = None;
 
+    if opts.output.is_some() {
+        config.plugin.common.output.clone_from(&opts.output);
+    }
+    if opts.output_format.is_some() {
+        config.plugin.common.output_format = opts.output_format;
+    }
+
     let bctf_cfg_from_conf_file = match config.plugin.common.envsub_config_path() {
         Ok(maybe_cfg) => maybe_cfg,
         Err(e) => {
@@ -60,34 +149,78 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     };
 
-    let bctf_cfg_path = opts
-        .config
-        .as_ref()
-        .or(bctf_cfg_from_conf_file.as_ref())
-        .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
-    info!(file = %bctf_cfg_path.display(), "Reading effective-configuration yaml");
-    let bctf_cfg_content = fs::read_to_string(&bctf_cfg_path).await.map_err(|e| {
-        anyhow!(
-            "Failed to open barectf effective-configuration yaml file '{}'. {}",
-            bctf_cfg_path.display(),
-            e
-        )
-    })?;
-    let bctf_cfg: BarectfConfig = serde_yaml::from_str(&bctf_cfg_content).map_err(|e| {
-        anyhow!(
-            "Failed to parse barectf effective-configuration yaml file '{}'. {}",
-            bctf_cfg_path.display(),
-            e
-        )
-    })?;
-
-    let stream_paths: Vec<PathBuf> = opts
+    let mut stream_paths: Vec<PathBuf> = opts
         .file
         .iter()
         .chain(config.plugin.file.iter())
         .cloned()
         .collect();
-    if stream_paths.is_empty() {
+
+    let mut cfg_path = opts.config.clone().or(bctf_cfg_from_conf_file);
+
+    // An archive in place of the configuration file; use its configuration
+    // and import its stream files
+    if let Some(path) = cfg_path.as_ref() {
+        if ArchiveFormat::detect(path).await?.is_some() {
+            stream_paths.insert(0, path.clone());
+            cfg_path = None;
+        }
+    }
+
+    // Archives are extracted to temporary directories that live until the import is done
+    let mut archives = Vec::new();
+    let mut non_archive_stream_paths = Vec::new();
+    for path in stream_paths.into_iter() {
+        match ArchiveFormat::detect(&path).await? {
+            Some(format) => archives.push(Archive::extract(path, format).await?),
+            None => non_archive_stream_paths.push(path),
+        }
+    }
+    let mut stream_paths = non_archive_stream_paths;
+    let mut archive_cfg_paths = archives.iter().flat_map(|a| a.configs.iter().cloned());
+
+    let bctf_cfg_path = match cfg_path.as_ref() {
+        // A trace directory in place of the configuration file; use its
+        // metadata file, and import its stream files unless some were given
+        Some(path) if is_dir(path).await => {
+            if stream_paths.is_empty() && archives.is_empty() {
+                stream_paths.push(path.clone());
+            }
+            path.join(TSDL_METADATA_FILE_NAME)
+        }
+        Some(path) => path.clone(),
+        // Fallback to the metadata of the first trace directory, then to the
+        // configuration of the first archive
+        None => {
+            let mut trace_dir_metadata = None;
+            for path in stream_paths.iter() {
+                if is_dir(path).await {
+                    trace_dir_metadata = Some(path.join(TSDL_METADATA_FILE_NAME));
+                    break;
+                }
+            }
+            trace_dir_metadata
+                .or_else(|| archive_cfg_paths.next())
+                .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?
+        }
+    };
+    // Any other configurations from the archives are selected by trace UUID
+    let mut additional_cfg_paths: Vec<PathBuf> = archive_cfg_paths.collect();
+    additional_cfg_paths.extend(opts.additional_configs.iter().cloned());
+    match config.plugin.common.envsub_additional_config_paths() {
+        Ok(paths) => additional_cfg_paths.extend(paths),
+        Err(e) => {
+            error!(%e, "Failed to run envsub on additional configuration paths from reflector configuration file");
+            additional_cfg_paths.extend(config.plugin.common.additional_configs.iter().cloned());
+        }
+    }
+    let bctf_cfgs = load_barectf_configs(&bctf_cfg_path, &additional_cfg_paths).await?;
+
+    let mut stream_inputs = discover_stream_files(stream_paths).await?;
+    for archive in archives.iter() {
+        stream_inputs.extend(archive.stream_inputs());
+    }
+    if stream_inputs.is_empty() {
         return Err(anyhow!("Missing CTF stream file(s). Specify a path to import on the command line or configuration file").into());
     }
 
@@ -106,47 +239,888 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         ),
     ];
 
-    let client = config.connect_and_authenticate_ingest().await?;
-    info!("Connected to Modality backend");
+    let follow = if opts.follow || config.plugin.follow.unwrap_or(false) {
+        let poll_interval = opts
+            .follow_poll_interval
+            .as_ref()
+            .or(config.plugin.follow_poll_interval.as_ref())
+            .map(|to| humantime::Duration::from_str(to))
+            .transpose()
+            .map_err(|e| anyhow!("Invalid follow-poll-interval. {}", e))?;
+        let idle_timeout = opts
+            .follow_idle_timeout
+            .as_ref()
+            .or(config.plugin.follow_idle_timeout.as_ref())
+            .map(|to| humantime::Duration::from_str(to))
+            .transpose()
+            .map_err(|e| anyhow!("Invalid follow-idle-timeout. {}", e))?;
+        Some(FollowOptions {
+            poll_interval: poll_interval
+                .map(|d| d.into())
+                .unwrap_or(DEFAULT_FOLLOW_POLL_INTERVAL),
+            idle_timeout: idle_timeout.map(|d| d.into()),
+        })
+    } else {
+        None
+    };
+
+    let import_opts = ImportOptions {
+        follow,
+        start_event: config.plugin.common.start_event.clone(),
+        stop_event: opts
+            .stop_event
+            .clone()
+            .or_else(|| config.plugin.stop_event.clone()),
+        stop: CancellationToken::new(),
+    };
+
+    if import_opts.follow.is_some() {
+        let stop = import_opts.stop.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                debug!("User signaled shutdown");
+                stop.cancel();
+            }
+        });
+    }
+
+    let output = Output::open(&config).await?;
+
+    let merge_streams =
+        config.plugin.common.merge_streams.unwrap_or(false) && stream_inputs.len() > 1;
+    // The stream files are merged here as they're read, the sender doesn't need to buffer them again
+    config.plugin.common.merge_streams = Some(false);
 
     let mut sender = Sender::new(
-        client,
-        &bctf_cfg,
+        output,
+        &bctf_cfgs[0],
         common_timeline_attrs.into_iter().collect(),
         config,
-    );
+    )?;
+    for cfg in bctf_cfgs.iter().skip(1) {
+        sender.add_config(cfg);
+    }
+
+    // Stream files from a trace directory or an archive each get their own timelines,
+    // as do multiple plain stream files
+    let plain_stream_files = stream_inputs
+        .iter()
+        .filter(|input| input.archive.is_none() && input.trace_dir.is_none())
+        .count();
+    let stream_paths: Vec<(PathBuf, SourceId)> = stream_inputs
+        .into_iter()
+        .map(|input| {
+            let source = match (input.archive, input.trace_dir) {
+                (Some(archive), _) => sender.add_source(vec![
+                    (
+                        "modality_barectf.importer.archive.file_name".into(),
+                        archive
+                            .path
+                            .file_name()
+                            .map(|s| s.to_string_lossy().to_string())
+                            .unwrap_or_else(|| "NA".to_owned())
+                            .into(),
+                    ),
+                    (
+                        "modality_barectf.importer.archive.member_path".into(),
+                        archive.member_path.into(),
+                    ),
+                ]),
+                (None, Some(trace_dir)) => sender.add_source(vec![
+                    (
+                        "modality_barectf.importer.trace_directory".into(),
+                        trace_dir.display().to_string().into(),
+                    ),
+                    (
+                        "modality_barectf.importer.stream.file_name".into(),
+                        input
+                            .path
+                            .file_name()
+                            .map(|s| s.to_string_lossy().to_string())
+                            .unwrap_or_else(|| "NA".to_owned())
+                            .into(),
+                    ),
+                ]),
+                (None, None) if plain_stream_files > 1 => sender.add_source(vec![(
+                    "modality_barectf.importer.stream.file_name".into(),
+                    input.path.display().to_string().into(),
+                )]),
+                (None, None) => SourceId::default(),
+            };
+            (input.path, source)
+        })
+        .collect();
+
+    let import_res = if merge_streams {
+        import_merged_streams(&mut sender, &stream_paths, &bctf_cfgs, &import_opts).await
+    } else {
+        import_streams(&mut sender, &stream_paths, &bctf_cfgs, &import_opts).await
+    };
+
+    // NOTE: doesn't support recovery yet
+    sender.close().await?;
+    import_res?;
+    info!("Finished importing");
+
+    Ok(())
+}
+
+/// Import each stream file, one after the other, or all together when following them
+async fn import_streams<S: Sink>(
+    sender: &mut Sender<ImporterConfig, S>,
+    stream_paths: &[(PathBuf, SourceId)],
+    bctf_cfgs: &[BarectfConfig],
+    import_opts: &ImportOptions,
+) -> Result<(), anyhow::Error> {
+    if import_opts.follow.is_some() && stream_paths.len() > 1 {
+        return import_followed_streams(sender, stream_paths, bctf_cfgs, import_opts).await;
+    }
+
+    for (stream_path, source) in stream_paths.iter() {
+        info!(file = %stream_path.display(), "Importing CTF stream");
+        let mut reader = open_stream(stream_path, bctf_cfgs, import_opts).await?;
+        while let Some((cfg_id, pkt)) = reader.next().await.transpose()? {
+            sender.handle_routed_packet(*source, cfg_id, &pkt).await?;
+            if import_opts.is_stop_packet(&pkt) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Follow all of the stream files at the same time, barectf writes the
+/// stream files of a live trace concurrently
+async fn import_followed_streams<S: Sink>(
+    sender: &mut Sender<ImporterConfig, S>,
+    stream_paths: &[(PathBuf, SourceId)],
+    bctf_cfgs: &[BarectfConfig],
+    import_opts: &ImportOptions,
+) -> Result<(), anyhow::Error> {
+    // Opening a stream file waits for its first bytes, open them all at once
+    let readers = try_join_all(stream_paths.iter().map(|(stream_path, source)| async move {
+        info!(file = %stream_path.display(), "Following CTF stream");
+        let reader = open_stream(stream_path, bctf_cfgs, import_opts).await?;
+        let source = *source;
+        Ok::<_, anyhow::Error>(reader.map(move |res| res.map(|item| (source, item))))
+    }))
+    .await?;
+
+    let mut packets = select_all(readers);
+    while let Some((source, (cfg_id, pkt))) = packets.next().await.transpose()? {
+        sender.handle_routed_packet(source, cfg_id, &pkt).await?;
+        if import_opts.is_stop_packet(&pkt) {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
 
-    // Import each stream file
-    for stream_path in stream_paths.into_iter() {
+/// Read all of the stream files together, always sending the earliest packet next
+async fn import_merged_streams(
+    sender: &mut Sender<ImporterConfig>,
+    stream_paths: &[(PathBuf, SourceId)],
+    bctf_cfgs: &[BarectfConfig],
+    import_opts: &ImportOptions,
+) -> Result<(), anyhow::Error> {
+    let mut readers = Vec::new();
+    for (stream_path, source) in stream_paths.iter() {
         info!(file = %stream_path.display(), "Importing CTF stream");
+        readers.push((
+            *source,
+            open_stream(stream_path, bctf_cfgs, import_opts).await?,
+        ));
+    }
+
+    // Timestamps start over after a restart, so packets are ordered by
+    // how many restarts their stream went through first
+    let mut restarts = vec![0_u64; readers.len()];
+    let mut started = vec![false; readers.len()];
+    let mut heads = Vec::with_capacity(readers.len());
+    for (_, reader) in readers.iter_mut() {
+        heads.push(reader.next().await.transpose()?);
+    }
+
+    while let Some(idx) = heads
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, head)| {
+            head.as_ref()
+                .map(|(_, pkt)| (idx, (restarts[idx], packet_sort_key(pkt))))
+        })
+        .min_by_key(|(_, key)| *key)
+        .map(|(idx, _)| idx)
+    {
+        let (source, reader) = &mut readers[idx];
+        if let Some((cfg_id, pkt)) = heads[idx].take() {
+            sender.handle_routed_packet(*source, cfg_id, &pkt).await?;
+            started[idx] = true;
+            if import_opts.is_stop_packet(&pkt) {
+                return Ok(());
+            }
+        }
+        heads[idx] = reader.next().await.transpose()?;
+        if let Some((_, pkt)) = heads[idx].as_ref() {
+            if started[idx] && import_opts.is_start_packet(pkt) {
+                restarts[idx] += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A stream file to import
+struct StreamInput {
+    path: PathBuf,
+    /// The CTF trace directory the stream file was discovered in
+    trace_dir: Option<PathBuf>,
+    /// The archive the stream file was extracted from
+    archive: Option<ArchiveMember>,
+}
+
+struct ArchiveMember {
+    path: PathBuf,
+    member_path: String,
+}
+
+/// Expand any CTF trace directories into the stream files they contain.
+///
+/// A trace directory (as written by barectf's Linux FS platform) contains
+/// a TSDL `metadata` file and one binary file per data stream.
+async fn discover_stream_files(paths: Vec<PathBuf>) -> Result<Vec<StreamInput>, anyhow::Error> {
+    let mut inputs = Vec::new();
+    for path in paths.into_iter() {
+        if !is_dir(&path).await {
+            inputs.push(StreamInput {
+                path,
+                trace_dir: None,
+                archive: None,
+            });
+            continue;
+        }
+
+        let mut entries = fs::read_dir(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to read trace directory '{}'. {}", path.display(), e))?;
+        let mut stream_files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name == TSDL_METADATA_FILE_NAME || file_name.starts_with('.') {
+                continue;
+            }
+            if is_stream_file(&entry.file_type().await?) {
+                stream_files.push(entry.path());
+            }
+        }
+        stream_files.sort();
+
+        if stream_files.is_empty() {
+            return Err(anyhow!(
+                "Trace directory '{}' doesn't contain any stream files",
+                path.display()
+            ));
+        }
+        debug!(trace_dir = %path.display(), stream_files = stream_files.len(), "Discovered stream files");
+
+        inputs.extend(stream_files.into_iter().map(|stream_path| StreamInput {
+            path: stream_path,
+            trace_dir: Some(path.clone()),
+            archive: None,
+        }));
+    }
+    Ok(inputs)
+}
+
+async fn is_dir(path: &Path) -> bool {
+    fs::metadata(path)
+        .await
+        .map(|m| m.is_dir())
+        .unwrap_or(false)
+}
+
+/// Regular files and named pipes (read until the writer closes it)
+fn is_stream_file(file_type: &std::fs::FileType) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return true;
+        }
+    }
+    file_type.is_file()
+}
+
+/// The offset of the `ustar` magic within a tar header
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+const ZIP_MAGIC: &[u8] = &[0x50, 0x4B, 0x03, 0x04];
 
-        let stream = fs::File::open(&stream_path).await.map_err(|e| {
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    /// A tar archive, possibly compressed
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detect archives by their magic bytes, returns None for anything that
+    /// isn't a regular file containing an archive
+    async fn detect(path: &Path) -> Result<Option<Self>, anyhow::Error> {
+        if !fs::metadata(path)
+            .await
+            .map(|m| m.is_file())
+            .unwrap_or(false)
+        {
+            return Ok(None);
+        }
+
+        let file = fs::File::open(path)
+            .await
+            .map_err(|e| anyhow!("Failed to open file '{}'. {}", path.display(), e))?;
+        let mut header = Vec::new();
+        decompress(path, file)
+            .await?
+            .take((TAR_MAGIC_OFFSET + TAR_MAGIC.len()) as u64)
+            .read_to_end(&mut header)
+            .await
+            .map_err(|e| anyhow!("Failed to read file '{}'. {}", path.display(), e))?;
+
+        Ok(if header.starts_with(ZIP_MAGIC) {
+            Some(ArchiveFormat::Zip)
+        } else if header.get(TAR_MAGIC_OFFSET..) == Some(TAR_MAGIC) {
+            Some(ArchiveFormat::Tar)
+        } else {
+            None
+        })
+    }
+}
+
+/// The contents of a tar or zip archive.
+///
+/// Members named `metadata` (CTF TSDL metadata) and `*.yaml`/`*.yml` members
+/// with a top-level `trace` node (barectf effective-configurations) are
+/// configurations. Members named `stream*`, and members without an extension
+/// next to a `metadata` member, are stream files. Other members are skipped.
+struct Archive {
+    path: PathBuf,
+    /// Where the archive was extracted to, removed on drop
+    dir: tempfile::TempDir,
+    configs: Vec<PathBuf>,
+    /// Stream files, relative to the extraction directory
+    streams: Vec<PathBuf>,
+}
+
+impl Archive {
+    async fn extract(path: PathBuf, format: ArchiveFormat) -> Result<Self, anyhow::Error> {
+        info!(archive = %path.display(), ?format, "Extracting archive");
+        let dir = tempfile::tempdir()
+            .map_err(|e| anyhow!("Failed to create a temporary directory. {}", e))?;
+
+        match format {
+            ArchiveFormat::Tar => {
+                let file = fs::File::open(&path)
+                    .await
+                    .map_err(|e| anyhow!("Failed to open archive '{}'. {}", path.display(), e))?;
+                tokio_tar::Archive::new(decompress(&path, file).await?)
+                    .unpack(dir.path())
+                    .await
+                    .map_err(|e| {
+                        anyhow!("Failed to extract archive '{}'. {}", path.display(), e)
+                    })?;
+            }
+            ArchiveFormat::Zip => {
+                let zip_path = path.clone();
+                let dest = dir.path().to_owned();
+                tokio::task::spawn_blocking(move || {
+                    let file = std::fs::File::open(&zip_path)?;
+                    zip::ZipArchive::new(file)?.extract(dest)
+                })
+                .await?
+                .map_err(|e| anyhow!("Failed to extract archive '{}'. {}", path.display(), e))?;
+            }
+        }
+
+        let mut members = Vec::new();
+        find_files(dir.path(), dir.path(), &mut members).await?;
+        members.sort();
+
+        let (configs, streams) = select_members(dir.path(), members).await;
+
+        debug!(
+            archive = %path.display(),
+            configs = configs.len(),
+            stream_files = streams.len(),
+            "Discovered archive members"
+        );
+        if streams.is_empty() {
+            return Err(anyhow!(
+                "Archive '{}' doesn't contain any stream files",
+                path.display()
+            ));
+        }
+
+        Ok(Self {
+            path,
+            dir,
+            configs,
+            streams,
+        })
+    }
+
+    fn stream_inputs(&self) -> impl Iterator<Item = StreamInput> + '_ {
+        self.streams.iter().map(|member| StreamInput {
+            path: self.dir.path().join(member),
+            trace_dir: None,
+            archive: Some(ArchiveMember {
+                path: self.path.clone(),
+                member_path: member.to_string_lossy().replace('\\', "/"),
+            }),
+        })
+    }
+}
+
+/// Sort the members of an extracted archive into configurations (with the
+/// effective-configuration yaml files first) and stream files
+async fn select_members(root: &Path, members: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let is_metadata =
+        |m: &Path| m.file_name().and_then(|n| n.to_str()) == Some(TSDL_METADATA_FILE_NAME);
+    let trace_dirs: Vec<PathBuf> = members
+        .iter()
+        .filter(|m| is_metadata(m))
+        .map(|m| m.parent().unwrap_or(Path::new("")).to_owned())
+        .collect();
+
+    let mut configs = Vec::new();
+    let mut metadata = Vec::new();
+    let mut streams = Vec::new();
+    for member in members.into_iter() {
+        let extension = member.extension().and_then(|e| e.to_str());
+        if is_metadata(&member) {
+            metadata.push(root.join(member));
+        } else if matches!(extension, Some("yaml") | Some("yml")) {
+            // Skip $include fragments and unrelated yaml files
+            if has_trace_node(&root.join(&member)).await {
+                configs.push(root.join(member));
+            } else {
+                debug!(member = %member.display(), "Skipping yaml archive member without a 'trace' node");
+            }
+        } else if is_stream_member(&member, &trace_dirs) {
+            streams.push(member);
+        } else {
+            debug!(member = %member.display(), "Skipping archive member that isn't a stream file");
+        }
+    }
+    configs.extend(metadata);
+    (configs, streams)
+}
+
+/// Stream files are named `stream*`, or are extension-less files in a CTF trace directory
+fn is_stream_member(member: &Path, trace_dirs: &[PathBuf]) -> bool {
+    let is_named_stream = member
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.starts_with("stream"))
+        .unwrap_or(false);
+    let in_trace_dir = member.extension().is_none()
+        && trace_dirs
+            .iter()
+            .any(|dir| member.parent().unwrap_or(Path::new("")) == dir);
+    is_named_stream || in_trace_dir
+}
+
+/// Returns true if the yaml file has a top-level `trace` node, like barectf configurations
+async fn has_trace_node(path: &Path) -> bool {
+    let Ok(content) = fs::read_to_string(path).await else {
+        return false;
+    };
+    serde_yaml::from_str::<serde_yaml::Value>(&content)
+        .map(|v| v.get("trace").is_some())
+        .unwrap_or(false)
+}
+
+/// Recursively find the regular files in an extracted archive, skipping hidden files
+async fn find_files(
+    root: &Path,
+    dir: &Path,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(|e| anyhow!("Failed to read directory '{}'. {}", dir.display(), e))?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type().await?;
+        if file_type.is_dir() {
+            Box::pin(find_files(root, &entry.path(), files)).await?;
+        } else if file_type.is_file() {
+            if let Ok(member) = entry.path().strip_prefix(root) {
+                files.push(member.to_owned());
+            }
+        }
+    }
+    Ok(())
+}
+
+type PacketStream = Pin<Box<dyn Stream<Item = Result<(ConfigId, Packet), anyhow::Error>> + Send>>;
+
+async fn open_stream(
+    stream_path: &Path,
+    bctf_cfgs: &[BarectfConfig],
+    import_opts: &ImportOptions,
+) -> Result<PacketStream, anyhow::Error> {
+    let is_stdin = stream_path == Path::new(STDIN_PATH);
+    let stream: Box<dyn AsyncRead + Unpin + Send> = if is_stdin {
+        Box::new(io::stdin())
+    } else {
+        Box::new(fs::File::open(stream_path).await.map_err(|e| {
             anyhow!(
                 "Failed to open stream file '{}'. {}",
                 stream_path.display(),
                 e
             )
-        })?;
-
-        let decoder = Parser::new(&bctf_cfg)?.into_packet_decoder();
-        let mut reader = FramedRead::new(BufReader::new(stream), decoder);
-
-        while let Some(pkt_res) = reader.next().await {
-            let pkt = match pkt_res {
-                Ok(p) => p,
-                Err(e) => {
-                    // NOTE: doesn't support recovery yet
-                    sender.close().await?;
-                    return Err(anyhow!("Failed to parse CTF packet from stream. {}", e).into());
+        })?)
+    };
+
+    let decoder = RoutingDecoder::new(bctf_cfgs)?;
+    let packets: PacketStream = match &import_opts.follow {
+        // There's nothing to follow on stdin, it's read until closed
+        Some(follow) if !is_stdin => {
+            let stream = FollowReader::new(stream, follow, import_opts.stop.clone());
+            Box::pin(FramedRead::new(
+                decompress(stream_path, stream).await?,
+                FollowDecoder(decoder),
+            ))
+        }
+        _ => Box::pin(FramedRead::new(
+            decompress(stream_path, stream).await?,
+            decoder,
+        )),
+    };
+    Ok(Box::pin(packets.map(|res| {
+        res.map_err(|e| anyhow!("Failed to parse CTF packet from stream. {}", e))
+    })))
+}
+
+/// Stream compression formats, detected by their magic bytes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    const GZIP_MAGIC: &'static [u8] = &[0x1F, 0x8B];
+    const ZSTD_MAGIC: &'static [u8] = &[0x28, 0xB5, 0x2F, 0xFD];
+    const XZ_MAGIC: &'static [u8] = &[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+
+    fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(Self::GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if header.starts_with(Self::ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if header.starts_with(Self::XZ_MAGIC) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Transparently decompress the stream if it starts with a known compression magic number
+async fn decompress<R>(
+    stream_path: &Path,
+    stream: R,
+) -> Result<Box<dyn AsyncRead + Unpin + Send>, anyhow::Error>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut reader = BufReader::new(stream);
+    let header = reader.fill_buf().await.map_err(|e| {
+        anyhow!(
+            "Failed to read stream file '{}'. {}",
+            stream_path.display(),
+            e
+        )
+    })?;
+    Ok(match Compression::detect(header) {
+        None => Box::new(reader),
+        Some(compression) => {
+            debug!(file = %stream_path.display(), ?compression, "Decompressing CTF stream");
+            match compression {
+                Compression::Gzip => {
+                    let mut decoder = GzipDecoder::new(reader);
+                    // Concatenated gzip files (i.e. appended captures) are one stream
+                    decoder.multiple_members(true);
+                    Box::new(decoder)
                 }
-            };
+                Compression::Zstd => {
+                    let mut decoder = ZstdDecoder::new(reader);
+                    decoder.multiple_members(true);
+                    Box::new(decoder)
+                }
+                Compression::Xz => {
+                    let mut decoder = XzDecoder::new(reader);
+                    decoder.multiple_members(true);
+                    Box::new(decoder)
+                }
+            }
+        }
+    })
+}
+
+/// The default interval between checks for new data in followed stream files
+const DEFAULT_FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+struct FollowOptions {
+    poll_interval: Duration,
+    idle_timeout: Option<Duration>,
+}
+
+struct ImportOptions {
+    follow: Option<FollowOptions>,
+    start_event: Option<String>,
+    stop_event: Option<String>,
+    /// Stops following stream files
+    stop: CancellationToken,
+}
+
+impl ImportOptions {
+    /// Returns true if the packet contains the trace-start event
+    fn is_start_packet(&self, pkt: &Packet) -> bool {
+        self.start_event
+            .as_ref()
+            .map(|start_event| pkt.events.iter().any(|ev| ev.name == *start_event))
+            .unwrap_or(false)
+    }
 
-            sender.handle_packet(&pkt).await?;
+    /// Returns true, and stops following stream files, if the packet contains the stop event
+    fn is_stop_packet(&self, pkt: &Packet) -> bool {
+        let Some(stop_event) = self.stop_event.as_ref() else {
+            return false;
+        };
+        if pkt.events.iter().any(|ev| ev.name == *stop_event) {
+            info!(stop_event, "Received stop event");
+            self.stop.cancel();
+            true
+        } else {
+            false
         }
     }
+}
 
-    sender.close().await?;
-    info!("Finished importing");
+/// Keeps reading a stream file as it grows.
+///
+/// The end of the stream is reported once stopped, or once the file
+/// hasn't grown for the idle timeout.
+struct FollowReader<R> {
+    inner: R,
+    poll_interval: Duration,
+    idle_timeout: Option<Duration>,
+    last_data: Instant,
+    sleep: Pin<Box<Sleep>>,
+    stop: CancellationToken,
+}
 
-    Ok(())
+impl<R> FollowReader<R> {
+    fn new(inner: R, opts: &FollowOptions, stop: CancellationToken) -> Self {
+        Self {
+            inner,
+            poll_interval: opts.poll_interval,
+            idle_timeout: opts.idle_timeout,
+            last_data: Instant::now(),
+            sleep: Box::pin(tokio::time::sleep(opts.poll_interval)),
+            stop,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FollowReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let filled = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            if buf.filled().len() != filled {
+                this.last_data = Instant::now();
+                return Poll::Ready(Ok(()));
+            }
+
+            // At the end of the file, wait for it to grow
+            if this.stop.is_cancelled() {
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(idle_timeout) = this.idle_timeout {
+                if this.last_data.elapsed() >= idle_timeout {
+                    debug!("Followed stream file is idle");
+                    return Poll::Ready(Ok(()));
+                }
+            }
+            this.sleep
+                .as_mut()
+                .reset(Instant::now() + this.poll_interval);
+            ready!(this.sleep.as_mut().poll(cx));
+        }
+    }
+}
+
+/// The writer of a followed stream file can be stopped in the middle
+/// of a packet, ignore an incomplete packet at the end of the stream.
+struct FollowDecoder(RoutingDecoder);
+
+impl Decoder for FollowDecoder {
+    type Item = (ConfigId, Packet);
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.0.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let item = self.0.decode(src)?;
+        if item.is_none() && !src.is_empty() {
+            warn!(
+                bytes = src.len(),
+                "Ignoring incomplete packet at the end of the stream"
+            );
+            src.clear();
+        }
+        Ok(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modality_barectf_plugin::sink::{MemorySink, Record};
+    use tokio::io::AsyncWriteExt;
+
+    const CONFIG_YAML: &[u8] = include_bytes!("../../integration-test/effective_config.yaml");
+    const STREAM: &[u8] = include_bytes!("../../integration-test/ctf_stream");
+    const PACKET_SIZE: usize = 256;
+
+    async fn append(path: &Path, data: &[u8]) {
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .await
+            .unwrap();
+        file.write_all(data).await.unwrap();
+        file.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn selects_archive_members() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: &[(&str, &[u8])] = &[
+            ("README.md", b"# Trace"),
+            ("firmware.elf", b"\x7fELF"),
+            ("config.yaml", CONFIG_YAML),
+            ("include/clocks.yaml", b"clock-types: {}"),
+            ("trace/metadata", b"/* CTF 1.8 */"),
+            ("trace/default", STREAM),
+            ("trace/notes.txt", b"notes"),
+            ("streams/stream_0", STREAM),
+            ("other/data", STREAM),
+        ];
+        let mut members = Vec::new();
+        for (name, data) in files.iter() {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(&path, data).await.unwrap();
+            members.push(PathBuf::from(name));
+        }
+        members.sort();
+
+        let (configs, streams) = select_members(dir.path(), members).await;
+        assert_eq!(
+            configs,
+            vec![
+                dir.path().join("config.yaml"),
+                dir.path().join("trace/metadata")
+            ]
+        );
+        assert_eq!(
+            streams,
+            vec![
+                PathBuf::from("streams/stream_0"),
+                PathBuf::from("trace/default")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn follows_stream_files_concurrently() {
+        let bctf_cfgs: Vec<BarectfConfig> = vec![serde_yaml::from_slice(CONFIG_YAML).unwrap()];
+        let config = Config::<ImporterConfig>::load("MODALITY_BARECTF_TEST_").unwrap();
+        let mut sender =
+            Sender::new(MemorySink::new(), &bctf_cfgs[0], Default::default(), config).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut stream_paths = Vec::new();
+        for name in ["stream_0", "stream_1"] {
+            let path = dir.path().join(name);
+            fs::write(&path, b"").await.unwrap();
+            let source = sender.add_source(vec![(
+                "modality_barectf.importer.stream.file_name".into(),
+                name.into(),
+            )]);
+            stream_paths.push((path, source));
+        }
+
+        let import_opts = ImportOptions {
+            follow: Some(FollowOptions {
+                poll_interval: Duration::from_millis(10),
+                idle_timeout: None,
+            }),
+            start_event: None,
+            stop_event: None,
+            stop: CancellationToken::new(),
+        };
+
+        // Each file gets its first packet, then its second one, alternately
+        let writer = async {
+            for pkt in STREAM.chunks(PACKET_SIZE) {
+                for (path, _) in stream_paths.iter() {
+                    append(path, pkt).await;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+            import_opts.stop.cancel();
+        };
+        let (res, _) = tokio::join!(
+            import_streams(&mut sender, &stream_paths, &bctf_cfgs, &import_opts),
+            writer
+        );
+        res.unwrap();
+
+        let mut timelines = Vec::new();
+        let mut names = Vec::new();
+        for record in sender.sink().records().iter() {
+            match record {
+                Record::Timeline { id, .. } => timelines.push(*id),
+                Record::Event { timeline, name, .. } => {
+                    let file = timelines.iter().position(|tl| tl == timeline).unwrap();
+                    names.push((file, name.as_str()));
+                }
+            }
+        }
+        assert_eq!(timelines.len(), 2);
+        let packet_0 = ["init", "foobar", "floats", "enums", "arrays"];
+        let expected: Vec<(usize, &str)> = packet_0
+            .iter()
+            .map(|n| (0, *n))
+            .chain(packet_0.iter().map(|n| (1, *n)))
+            .chain([(0, "shutdown"), (1, "shutdown")])
+            .collect();
+        assert_eq!(names, expected);
+    }
 }
@@ -1,27 +1,38 @@
 use anyhow::anyhow;
+use auxon_sdk::api::Uuid;
 use auxon_sdk::{
     init_tracing,
     plugin_utils::ingest::Config,
     plugin_utils::serde::from_str,
     reflector_config::{envsub, EnvSubError},
 };
-use barectf_parser::{Config as BarectfConfig, Parser};
+use barectf_parser::Config as BarectfConfig;
 use clap::Parser as ClapParser;
-use modality_barectf_plugin::{CommonConfig, HasCommonConfig, Sender, PLUGIN_VERSION};
+use modality_barectf_plugin::{
+    load_barectf_config, load_barectf_configs, parse_barectf_config,
+    record::{RecordOptions, Recorder},
+    reload::{self, ConfigReloader, Configs, PacketReader, DEFAULT_RELOAD_POLL_INTERVAL},
+    CommonConfig, ConfigId, HasCommonConfig, Output, OutputFormat, Sender, SourceId,
+    PLUGIN_VERSION,
+};
 use rtt_proxy::{
     ProbeConfig, ProxySessionConfig, ProxySessionStatus, RttConfig, Target, TargetConfig,
 };
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
 use tokio::{
     fs,
     io::{self, AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
+    sync::watch,
     time::{Duration, Instant},
 };
-use tokio_stream::StreamExt;
-use tokio_util::codec::FramedRead;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 use url::Url;
 
 /// Collect barectf streams from an RTT proxy service
@@ -48,6 +59,14 @@ struct CollectorOpts {
     #[clap(long, name = "elf-file")]
     elf_file: Option<PathBuf>,
 
+    /// Read the barectf configuration embedded in the ELF file from the given
+    /// section or symbol name.
+    ///
+    /// The content can be the effective-configuration yaml, the CTF TSDL metadata,
+    /// or the 16-byte trace UUID used to check the configuration file.
+    #[clap(long, name = "elf-config-section", requires = "elf-file")]
+    elf_config_section: Option<String>,
+
     /// Set a breakpoint on the address of the given symbol used to signal
     /// when to optionally configure the channel mode and start reading.
     ///
@@ -175,9 +194,49 @@ struct CollectorOpts {
     #[clap(long, name = "connect-timeout")]
     connect_timeout: Option<String>,
 
-    /// The barectf effective-configuration yaml file
+    /// The barectf effective-configuration yaml file, or a CTF TSDL metadata file
     config: Option<PathBuf>,
 
+    /// An additional barectf configuration file, for traces from other
+    /// firmware variants. The configuration is selected by trace UUID.
+    ///
+    /// Can be supplied multiple times
+    #[clap(long = "additional-config", name = "additional-config")]
+    additional_configs: Vec<PathBuf>,
+
+    /// Write the converted timelines and events to this file instead of
+    /// sending them to Modality
+    #[clap(long, name = "output")]
+    output: Option<PathBuf>,
+
+    /// The output file format: `jsonl`, `csv` or `chrome-trace`.
+    /// The default is based on the output file extension, falling back to `jsonl`.
+    #[clap(long, name = "output-format")]
+    output_format: Option<OutputFormat>,
+
+    /// Reload the barectf configuration files when they change or when SIGHUP is received.
+    /// The new configuration is used once the trace restarts.
+    #[clap(long, name = "reload-config")]
+    reload_config: bool,
+
+    /// How often to check the barectf configuration files for changes.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "reload-poll-interval")]
+    reload_poll_interval: Option<String>,
+
+    /// Record the raw packet data received to the given file, to re-import it later
+    #[clap(long, name = "record")]
+    record: Option<PathBuf>,
+
+    /// Start a new recording file once the current one reaches this many bytes
+    #[clap(long, name = "record-rotate-size")]
+    record_rotate_size: Option<u64>,
+
+    /// Start a new recording file periodically.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "record-rotate-interval")]
+    record_rotate_interval: Option<String>,
+
     /// The remote RTT proxy server URL or address:port to connect to.
     ///
     /// The default is `127.0.0.1:8888`.
@@ -207,6 +266,8 @@ struct CollectorConfig {
     attach_under_reset: Option<bool>,
     #[serde(deserialize_with = "from_str", alias = "elf_file")]
     elf_file: Option<PathBuf>,
+    #[serde(alias = "elf_config_section")]
+    elf_config_section: Option<String>,
     #[serde(deserialize_with = "from_str")]
     thumb: Option<bool>,
     breakpoint: Option<String>,
@@ -234,6 +295,16 @@ struct CollectorConfig {
     #[serde(alias = "connect_timeout")]
     connect_timeout: Option<String>,
     remote: Option<String>,
+    #[serde(deserialize_with = "from_str", alias = "reload_config")]
+    reload_config: Option<bool>,
+    #[serde(alias = "reload_poll_interval")]
+    reload_poll_interval: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    record: Option<PathBuf>,
+    #[serde(deserialize_with = "from_str", alias = "record_rotate_size")]
+    record_rotate_size: Option<u64>,
+    #[serde(alias = "record_rotate_interval")]
+    record_rotate_interval: Option<String>,
     #[serde(flatten)]
     common: CommonConfig,
 }
@@ -267,12 +338,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Time domain is handled expclicitly by the plugin
     config.time_domain = None;
 
+    if config.plugin.common.output.is_none() {
+        config.plugin.common.output.clone_from(&opts.output);
+    }
+    if config.plugin.common.output_format.is_none() {
+        config.plugin.common.output_format = opts.output_format;
+    }
+
     if config.plugin.remote.is_none() {
         config.plugin.remote.clone_from(&opts.remote);
     }
     if config.plugin.common.config.is_none() {
         config.plugin.common.config.clone_from(&opts.config);
     }
+    if config.plugin.common.additional_configs.is_empty() {
+        config
+            .plugin
+            .common
+            .additional_configs
+            .clone_from(&opts.additional_configs);
+    }
     if config.plugin.attach_timeout.is_none() {
         config
             .plugin
@@ -315,6 +400,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     if config.plugin.elf_file.is_none() {
         config.plugin.elf_file.clone_from(&opts.elf_file);
     }
+    if config.plugin.elf_config_section.is_none() {
+        config
+            .plugin
+            .elf_config_section
+            .clone_from(&opts.elf_config_section);
+    }
     if config.plugin.thumb.is_none() {
         config.plugin.thumb = Some(opts.thumb);
     }
@@ -370,6 +461,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             .connect_timeout
             .clone_from(&opts.connect_timeout);
     }
+    if config.plugin.reload_config.is_none() {
+        config.plugin.reload_config = Some(opts.reload_config);
+    }
+    if config.plugin.reload_poll_interval.is_none() {
+        config
+            .plugin
+            .reload_poll_interval
+            .clone_from(&opts.reload_poll_interval);
+    }
+    if config.plugin.record.is_none() {
+        config.plugin.record.clone_from(&opts.record);
+    }
+    if config.plugin.record_rotate_size.is_none() {
+        config.plugin.record_rotate_size = opts.record_rotate_size;
+    }
+    if config.plugin.record_rotate_interval.is_none() {
+        config
+            .plugin
+            .record_rotate_interval
+            .clone_from(&opts.record_rotate_interval);
+    }
 
     let bctf_cfg_from_conf_file = match config.plugin.common.envsub_config_path() {
         Ok(maybe_cfg) => maybe_cfg,
@@ -379,25 +491,6 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     };
 
-    let bctf_cfg_path = bctf_cfg_from_conf_file
-        .as_ref()
-        .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
-    info!(file = %bctf_cfg_path.display(), "Reading effective-configuration yaml");
-    let bctf_cfg_content = fs::read_to_string(&bctf_cfg_path).await.map_err(|e| {
-        anyhow!(
-            "Failed to open barectf effective-configuration yaml file '{}'. {}",
-            bctf_cfg_path.display(),
-            e
-        )
-    })?;
-    let bctf_cfg: BarectfConfig = serde_yaml::from_str(&bctf_cfg_content).map_err(|e| {
-        anyhow!(
-            "Failed to parse barectf effective-configuration yaml file '{}'. {}",
-            bctf_cfg_path.display(),
-            e
-        )
-    })?;
-
     let remote_string = if let Some(remote) = config.plugin.remote.as_ref() {
         remote.clone()
     } else {
@@ -444,6 +537,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .transpose()
         .map_err(|e| anyhow!("Invalid no-data-stop-timeout. {}", e))?;
 
+    let reload_poll_interval = config
+        .plugin
+        .reload_poll_interval
+        .as_ref()
+        .map(|to| humantime::Duration::from_str(to))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid reload-poll-interval. {}", e))?;
+
+    let record_rotate_interval = config
+        .plugin
+        .record_rotate_interval
+        .as_ref()
+        .map(|to| humantime::Duration::from_str(to))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid record-rotate-interval. {}", e))?;
+    let recorder = config.plugin.record.as_ref().map(|path| {
+        Recorder::spawn(
+            &RecordOptions {
+                path: path.clone(),
+                rotate_size: config.plugin.record_rotate_size,
+                rotate_interval: record_rotate_interval.map(|d| d.into()),
+            },
+            None,
+        )
+    });
+
     let maybe_elf_file = match config.plugin.envsub_elf_file() {
         Ok(maybe_cfg) => maybe_cfg,
         Err(e) => {
@@ -452,6 +571,115 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         }
     };
 
+    let embedded_cfg = if let Some(section) = &config.plugin.elf_config_section {
+        let elf_file = maybe_elf_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("Reading an embedded configuration requires an ELF file"))?;
+        debug!(elf_file = %elf_file.display(), section, "Reading embedded barectf configuration");
+        let mut file = fs::File::open(elf_file).await?;
+        let data = get_section_or_symbol_data(&mut file, section)
+            .await
+            .ok_or_else(|| {
+                anyhow!(
+                    "Could not locate the section or symbol '{}' in the ELF file '{}'",
+                    section,
+                    elf_file.display()
+                )
+            })?;
+        Some(EmbeddedConfig::from_data(&data, elf_file).await?)
+    } else {
+        None
+    };
+
+    let is_embedded_cfg = matches!(embedded_cfg, Some(EmbeddedConfig::Config(_)));
+    let bctf_cfg = match embedded_cfg {
+        Some(EmbeddedConfig::Config(embedded_cfg)) => {
+            if let Some(cfg_path) = bctf_cfg_from_conf_file.as_ref() {
+                let file_cfg = load_barectf_config(cfg_path).await?;
+                if file_cfg.trace.typ.uuid != embedded_cfg.trace.typ.uuid {
+                    warn!(
+                        file = %cfg_path.display(),
+                        "The barectf configuration file doesn't match the configuration embedded in the ELF file, using the embedded configuration"
+                    );
+                }
+            }
+            info!("Using the barectf configuration embedded in the ELF file");
+            *embedded_cfg
+        }
+        Some(EmbeddedConfig::TraceUuid(embedded_uuid)) => {
+            let cfg_path = bctf_cfg_from_conf_file
+                .as_ref()
+                .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
+            let cfg = load_barectf_config(cfg_path).await?;
+            if cfg.trace.typ.uuid != Some(embedded_uuid) {
+                return Err(anyhow!(
+                    "The trace UUID of barectf configuration file '{}' ({}) doesn't match the trace UUID embedded in the ELF file ({})",
+                    cfg_path.display(),
+                    cfg.trace
+                        .typ
+                        .uuid
+                        .map(|id| id.to_string())
+                        .unwrap_or_else(|| "NA".to_owned()),
+                    embedded_uuid
+                )
+                .into());
+            }
+            cfg
+        }
+        None => {
+            let cfg_path = bctf_cfg_from_conf_file
+                .as_ref()
+                .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
+            load_barectf_config(cfg_path).await?
+        }
+    };
+
+    let additional_cfg_paths = match config.plugin.common.envsub_additional_config_paths() {
+        Ok(paths) => paths,
+        Err(e) => {
+            error!(%e, "Failed to run envsub on additional configuration paths from reflector configuration file");
+            config.plugin.common.additional_configs.clone()
+        }
+    };
+    let mut bctf_cfgs = vec![bctf_cfg];
+    for cfg_path in additional_cfg_paths.iter() {
+        bctf_cfgs.push(load_barectf_config(cfg_path).await?);
+    }
+
+    let reload_cfg_path = match bctf_cfg_from_conf_file.as_ref() {
+        Some(cfg_path) if config.plugin.reload_config.unwrap_or(false) => {
+            if is_embedded_cfg {
+                warn!(
+                    "Reloading the barectf configuration embedded in the ELF file isn't supported"
+                );
+                None
+            } else {
+                Some(cfg_path.clone())
+            }
+        }
+        _ => None,
+    };
+    let mut reloader = if let Some(cfg_path) = reload_cfg_path.as_ref() {
+        let cfg_paths: Vec<PathBuf> = std::iter::once(cfg_path.clone())
+            .chain(additional_cfg_paths.iter().cloned())
+            .collect();
+        debug!(
+            files = cfg_paths.len(),
+            "Watching barectf configuration files"
+        );
+        Some(
+            ConfigReloader::new(
+                &cfg_paths,
+                reload_poll_interval
+                    .map(|d| d.into())
+                    .unwrap_or(DEFAULT_RELOAD_POLL_INTERVAL),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
     let maybe_control_block_address =
         if let Some(user_provided_addr) = config.plugin.control_block_address {
             debug!(
@@ -590,19 +818,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         ),
     ];
 
-    let client = config
-        .connect_and_authenticate_ingest()
-        .await
-        .map_err(|e| anyhow!("Failed to connect to modality/reflector. {}", e))?;
-
-    info!("Connected to Modality backend");
+    let output = Output::open(&config).await?;
 
+    let start_event = config.plugin.common.start_event.clone();
     let mut sender = Sender::new(
-        client,
-        &bctf_cfg,
+        output,
+        &bctf_cfgs[0],
         common_timeline_attrs.into_iter().collect(),
         config,
-    );
+    )?;
+    for cfg in bctf_cfgs.iter().skip(1) {
+        sender.add_config(cfg);
+    }
 
     let remote = if let Ok(socket_addr) = remote_string.parse::<SocketAddr>() {
         socket_addr
@@ -632,20 +859,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     };
 
     let mut join_handle = tokio::spawn(async move {
-        let decoder = Parser::new(&bctf_cfg)?.into_packet_decoder();
-        let mut reader = FramedRead::new(BufReader::new(tcp_stream), decoder);
-
-        while let Some(pkt_res) = reader.next().await {
-            let pkt = match pkt_res {
-                Ok(p) => p,
-                Err(e) => {
-                    // NOTE: doesn't support recovery yet
-                    sender.close().await?;
-                    return Err(anyhow!("Failed to parse CTF packet from stream. {}", e));
+        let (cfgs_tx, cfgs_rx) = watch::channel(Arc::new(Configs {
+            first_config: ConfigId::default(),
+            configs: bctf_cfgs,
+        }));
+        let mut reader =
+            PacketReader::new(BufReader::new(tcp_stream), cfgs_rx, start_event, recorder)?;
+
+        loop {
+            tokio::select! {
+                pkt_res = reader.next() => {
+                    let Some(pkt_res) = pkt_res else {
+                        break;
+                    };
+                    let (cfg_id, pkt) = match pkt_res {
+                        Ok(p) => p,
+                        Err(e) => {
+                            // NOTE: doesn't support recovery yet
+                            sender.close().await?;
+                            return Err(anyhow!("Failed to parse CTF packet from stream. {}", e));
+                        }
+                    };
+
+                    sender
+                        .handle_routed_packet(SourceId::default(), cfg_id, &pkt)
+                        .await?;
                 }
-            };
-
-            sender.handle_packet(&pkt).await?;
+                _ = reload::changed(&mut reloader) => {
+                    let Some(cfg_path) = reload_cfg_path.as_ref() else {
+                        continue;
+                    };
+                    match load_barectf_configs(cfg_path, &additional_cfg_paths).await {
+                        Ok(configs) => {
+                            let first_config = sender.add_configs(&configs);
+                            cfgs_tx.send_replace(Arc::new(Configs {
+                                first_config,
+                                configs,
+                            }));
+                        }
+                        Err(e) => {
+                            error!(%e, "Failed to reload the barectf configuration, keeping the current configuration");
+                        }
+                    }
+                }
+            }
         }
 
         sender.close().await?;
@@ -747,6 +1004,78 @@ async fn connect_retry_loop(
     Ok(TcpStream::connect(remote).await?)
 }
 
+/// The barectf configuration data embedded in the ELF file
+enum EmbeddedConfig {
+    Config(Box<BarectfConfig>),
+    TraceUuid(Uuid),
+}
+
+impl EmbeddedConfig {
+    async fn from_data(data: &[u8], elf_file: &Path) -> Result<Self, anyhow::Error> {
+        // A raw UUID can end in NUL bytes, so check for one before trimming
+        if data.len() == UUID_LEN {
+            return Ok(EmbeddedConfig::TraceUuid(Uuid::from_slice(data)?));
+        }
+
+        // Sections and C strings are typically NUL terminated/padded
+        let data = match data.iter().rposition(|b| *b != 0) {
+            Some(last) => &data[..=last],
+            None => data,
+        };
+
+        if let Some(uuid) = std::str::from_utf8(data)
+            .ok()
+            .and_then(|s| Uuid::parse_str(s.trim()).ok())
+        {
+            Ok(EmbeddedConfig::TraceUuid(uuid))
+        } else {
+            parse_barectf_config(data, elf_file)
+                .await
+                .map(|cfg| EmbeddedConfig::Config(Box::new(cfg)))
+        }
+    }
+}
+
+const UUID_LEN: usize = 16;
+
+async fn get_section_or_symbol_data<T: io::AsyncRead + io::AsyncSeek + std::marker::Unpin>(
+    file: &mut T,
+    name: &str,
+) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer).await.ok()?;
+    let binary = goblin::elf::Elf::parse(buffer.as_slice()).ok()?;
+
+    let file_range = |offset: u64, size: u64| {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(size).ok()?)?;
+        buffer.get(start..end).map(|d| d.to_vec())
+    };
+
+    for sh in binary.section_headers.iter() {
+        if binary.shdr_strtab.get_at(sh.sh_name) == Some(name) {
+            if sh.sh_type == goblin::elf::section_header::SHT_NOBITS {
+                return None;
+            }
+            return file_range(sh.sh_offset, sh.sh_size);
+        }
+    }
+
+    for sym in binary.syms.iter() {
+        if binary.strtab.get_at(sym.st_name) != Some(name) || sym.st_size == 0 {
+            continue;
+        }
+        let sh = binary.section_headers.get(sym.st_shndx)?;
+        if sh.sh_type == goblin::elf::section_header::SHT_NOBITS {
+            return None;
+        }
+        let offset = sym.st_value.checked_sub(sh.sh_addr)? + sh.sh_offset;
+        return file_range(offset, sym.st_size);
+    }
+
+    None
+}
+
 async fn get_rtt_symbol<T: io::AsyncRead + io::AsyncSeek + std::marker::Unpin>(
     file: &mut T,
 ) -> Option<u64> {
@@ -0,0 +1,213 @@
+use anyhow::anyhow;
+use auxon_sdk::plugin_utils::serde::from_str;
+use auxon_sdk::{init_tracing, plugin_utils::ingest::Config};
+use clap::Parser as ClapParser;
+use modality_barectf_plugin::{
+    load_barectf_configs,
+    resync::{ResyncDecoder, DEFAULT_MAX_PACKET_SIZE},
+    routing::RoutingDecoder,
+    CommonConfig, HasCommonConfig, Output, OutputFormat, Sender, SourceId, PLUGIN_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio_serial::SerialPortBuilderExt;
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tracing::{debug, error, info};
+
+/// Collect barectf streams from a serial port
+#[derive(Debug, clap::Parser)]
+#[clap(version, about = "Collect barectf streams from a serial port", long_about = None)]
+struct CollectorOpts {
+    /// The serial port baud rate.
+    ///
+    /// The default is 115200.
+    #[clap(long, name = "baud-rate")]
+    baud_rate: Option<u32>,
+
+    /// The size of the largest packet, in bytes. Bytes are skipped to
+    /// resynchronize when a packet exceeds it.
+    ///
+    /// The default is 65536.
+    #[clap(long, name = "max-packet-size")]
+    max_packet_size: Option<usize>,
+
+    /// An additional barectf configuration file, for traces from other
+    /// firmware variants. The configuration is selected by trace UUID.
+    ///
+    /// Can be supplied multiple times
+    #[clap(long = "additional-config", name = "additional-config")]
+    additional_configs: Vec<PathBuf>,
+
+    /// Write the converted timelines and events to this file instead of
+    /// sending them to Modality
+    #[clap(long, name = "output")]
+    output: Option<PathBuf>,
+
+    /// The output file format: `jsonl`, `csv` or `chrome-trace`.
+    /// The default is based on the output file extension, falling back to `jsonl`.
+    #[clap(long, name = "output-format")]
+    output_format: Option<OutputFormat>,
+
+    /// The barectf effective-configuration yaml file, or a CTF TSDL metadata file
+    config: Option<PathBuf>,
+
+    /// The serial port device path (i.e. /dev/ttyUSB0 or COM3)
+    device: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct CollectorConfig {
+    /// The serial port device path (i.e. /dev/ttyUSB0 or COM3)
+    #[serde(deserialize_with = "from_str")]
+    device: Option<String>,
+
+    /// The serial port baud rate.
+    ///
+    /// The default is 115200.
+    #[serde(deserialize_with = "from_str", alias = "baud_rate")]
+    baud_rate: Option<u32>,
+
+    /// The size of the largest packet, in bytes. Bytes are skipped to
+    /// resynchronize when a packet exceeds it.
+    ///
+    /// The default is 65536.
+    #[serde(deserialize_with = "from_str", alias = "max_packet_size")]
+    max_packet_size: Option<usize>,
+
+    #[serde(flatten)]
+    common: CommonConfig,
+}
+
+impl HasCommonConfig for CollectorConfig {
+    fn common_config(&self) -> &CommonConfig {
+        &self.common
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    init_tracing!();
+
+    let opts = CollectorOpts::parse();
+
+    let mut config = Config::<CollectorConfig>::load("MODALITY_BARECTF_")?;
+
+    // Time domain is handled expclicitly by the plugin
+    config.time_domain = None;
+
+    if opts.output.is_some() {
+        config.plugin.common.output.clone_from(&opts.output);
+    }
+    if opts.output_format.is_some() {
+        config.plugin.common.output_format = opts.output_format;
+    }
+
+    let bctf_cfg_from_conf_file = match config.plugin.common.envsub_config_path() {
+        Ok(maybe_cfg) => maybe_cfg,
+        Err(e) => {
+            error!(%e, "Failed to run envsub on effective-configuration yaml path from reflector configuration file");
+            config.plugin.common.config.clone()
+        }
+    };
+
+    let bctf_cfg_path = opts
+        .config
+        .as_ref()
+        .or(bctf_cfg_from_conf_file.as_ref())
+        .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
+    let mut additional_cfg_paths = opts.additional_configs.clone();
+    match config.plugin.common.envsub_additional_config_paths() {
+        Ok(paths) => additional_cfg_paths.extend(paths),
+        Err(e) => {
+            error!(%e, "Failed to run envsub on additional configuration paths from reflector configuration file");
+            additional_cfg_paths.extend(config.plugin.common.additional_configs.iter().cloned());
+        }
+    }
+    let bctf_cfgs = load_barectf_configs(bctf_cfg_path, &additional_cfg_paths).await?;
+
+    let device = opts
+        .device
+        .as_ref()
+        .or(config.plugin.device.as_ref())
+        .cloned()
+        .ok_or_else(|| anyhow!("Missing serial port device path"))?;
+    let baud_rate = opts.baud_rate.or(config.plugin.baud_rate).unwrap_or(115200);
+    let max_packet_size = opts
+        .max_packet_size
+        .or(config.plugin.max_packet_size)
+        .unwrap_or(DEFAULT_MAX_PACKET_SIZE);
+
+    let common_timeline_attrs = vec![
+        (
+            "modality_barectf.plugin.version".into(),
+            PLUGIN_VERSION.into(),
+        ),
+        (
+            "modality_barectf.serial_collector.device".into(),
+            device.clone().into(),
+        ),
+        (
+            "modality_barectf.serial_collector.baud_rate".into(),
+            i64::from(baud_rate).into(),
+        ),
+    ];
+
+    info!(%device, baud_rate, "Opening serial port");
+    let serial_port = tokio_serial::new(&device, baud_rate)
+        .open_native_async()
+        .map_err(|e| anyhow!("Failed to open serial port '{}'. {}", device, e))?;
+
+    let output = Output::open(&config).await?;
+
+    let mut sender = Sender::new(
+        output,
+        &bctf_cfgs[0],
+        common_timeline_attrs.into_iter().collect(),
+        config,
+    )?;
+    for cfg in bctf_cfgs.iter().skip(1) {
+        sender.add_config(cfg);
+    }
+
+    let join_handle = tokio::spawn(async move {
+        let decoder = ResyncDecoder::new(RoutingDecoder::new(&bctf_cfgs)?, max_packet_size);
+        let mut reader = FramedRead::new(serial_port, decoder);
+
+        // A serial port doesn't end, read until the user signals shutdown
+        let ctrl_c = tokio::signal::ctrl_c();
+        tokio::pin!(ctrl_c);
+        loop {
+            let pkt_res = tokio::select! {
+                _ = &mut ctrl_c => {
+                    debug!("User signaled shutdown");
+                    break;
+                }
+                pkt_res = reader.next() => match pkt_res {
+                    Some(pkt_res) => pkt_res,
+                    None => break,
+                },
+            };
+            let (cfg_id, pkt) = match pkt_res {
+                Ok(p) => p,
+                Err(e) => {
+                    sender.close().await?;
+                    return Err(anyhow!("Failed to read CTF packet from serial port. {}", e));
+                }
+            };
+
+            sender
+                .handle_routed_packet(SourceId::default(), cfg_id, &pkt)
+                .await?;
+        }
+
+        sender.close().await?;
+        info!("Finished");
+
+        Ok::<(), anyhow::Error>(())
+    });
+    join_handle.await??;
+
+    Ok(())
+}
@@ -1,22 +1,41 @@
 use anyhow::anyhow;
 use auxon_sdk::plugin_utils::serde::from_str;
 use auxon_sdk::{init_tracing, plugin_utils::ingest::Config};
-use barectf_parser::{Config as BarectfConfig, Parser};
+use barectf_parser::{Config as BarectfConfig, Packet};
 use clap::Parser as ClapParser;
-use modality_barectf_plugin::{CommonConfig, HasCommonConfig, Sender, PLUGIN_VERSION};
+use modality_barectf_plugin::{
+    load_barectf_configs,
+    record::{RecordOptions, Recorder},
+    reload::{self, ConfigReloader, Configs, PacketReader, DEFAULT_RELOAD_POLL_INTERVAL},
+    CommonConfig, ConfigId, HasCommonConfig, Output, OutputFormat, Sender, SourceId,
+    PLUGIN_VERSION,
+};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{future::Future, io, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
 use tokio::{
-    fs,
     io::BufReader,
-    net::TcpStream,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
     time::{Duration, Instant},
 };
-use tokio_stream::StreamExt;
-use tokio_util::codec::FramedRead;
-use tracing::{debug, error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 use url::Url;
 
+/// The number of decoded packets buffered between the connections and the collector
+const CONNECTION_EVENT_CHANNEL_SIZE: usize = 64;
+
+/// The delay before the first reconnection attempt, doubled after each failed attempt
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+const DEFAULT_MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The delay before accepting connections again after accepting failed
+/// (i.e. out of file descriptors), doubled after each failure
+const INITIAL_ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
 /// Collect barectf streams from a TCP connection
 #[derive(Debug, clap::Parser)]
 #[clap(version, about = "Collect barectf streams from a TCP connection", long_about = None)]
@@ -26,10 +45,70 @@ struct CollectorOpts {
     #[clap(long, name = "connect-timeout")]
     connect_timeout: Option<String>,
 
-    /// The barectf effective-configuration yaml file
+    /// The barectf effective-configuration yaml file, or a CTF TSDL metadata file
     config: Option<PathBuf>,
 
+    /// An additional barectf configuration file, for traces from other
+    /// firmware variants. The configuration is selected by trace UUID.
+    ///
+    /// Can be supplied multiple times
+    #[clap(long = "additional-config", name = "additional-config")]
+    additional_configs: Vec<PathBuf>,
+
+    /// Write the converted timelines and events to this file instead of
+    /// sending them to Modality
+    #[clap(long, name = "output")]
+    output: Option<PathBuf>,
+
+    /// The output file format: `jsonl`, `csv` or `chrome-trace`.
+    /// The default is based on the output file extension, falling back to `jsonl`.
+    #[clap(long, name = "output-format")]
+    output_format: Option<OutputFormat>,
+
+    /// Reconnect to the remote(s) when the connection is closed or fails
+    #[clap(long, name = "reconnect")]
+    reconnect: bool,
+
+    /// The maximum delay between reconnection attempts.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "reconnect-max-backoff")]
+    reconnect_max_backoff: Option<String>,
+
+    /// Send a `modality_barectf.reconnect` event on the remote's timelines when it reconnects
+    #[clap(long, name = "reconnect-marker-event")]
+    reconnect_marker_event: bool,
+
+    /// Reload the barectf configuration files when they change or when SIGHUP is received.
+    /// The new configuration is used once the trace restarts.
+    #[clap(long, name = "reload-config")]
+    reload_config: bool,
+
+    /// How often to check the barectf configuration files for changes.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "reload-poll-interval")]
+    reload_poll_interval: Option<String>,
+
+    /// Record the raw packet data received to the given file, to re-import it later.
+    /// With multiple connections, the remote or peer address is added to the file name.
+    #[clap(long, name = "record")]
+    record: Option<PathBuf>,
+
+    /// Start a new recording file once the current one reaches this many bytes
+    #[clap(long, name = "record-rotate-size")]
+    record_rotate_size: Option<u64>,
+
+    /// Start a new recording file periodically.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[clap(long, name = "record-rotate-interval")]
+    record_rotate_interval: Option<String>,
+
+    /// Listen for incoming connections on the given address:port instead of
+    /// connecting to a remote.
+    #[clap(long, name = "listen", conflicts_with = "remote")]
+    listen: Option<String>,
+
     /// The remote TCP server URL or address:port to connect to.
+    /// Multiple comma-separated remotes are collected from concurrently.
     ///
     /// The default is `127.0.0.1:8888`.
     remote: Option<String>,
@@ -44,11 +123,52 @@ struct CollectorConfig {
     connect_timeout: Option<String>,
 
     /// The remote TCP server URL or address:port to connect to.
+    /// Multiple comma-separated remotes are collected from concurrently.
     ///
     /// The default is `127.0.0.1:8888`.
     #[serde(deserialize_with = "from_str")]
     remote: Option<String>,
 
+    /// Listen for incoming connections on the given address:port instead of
+    /// connecting to a remote.
+    #[serde(deserialize_with = "from_str")]
+    listen: Option<String>,
+
+    /// Reconnect to the remote(s) when the connection is closed or fails
+    #[serde(deserialize_with = "from_str")]
+    reconnect: Option<bool>,
+
+    /// The maximum delay between reconnection attempts.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[serde(deserialize_with = "from_str", alias = "reconnect_max_backoff")]
+    reconnect_max_backoff: Option<String>,
+
+    /// Send a `modality_barectf.reconnect` event on the remote's timelines when it reconnects
+    #[serde(deserialize_with = "from_str", alias = "reconnect_marker_event")]
+    reconnect_marker_event: Option<bool>,
+
+    /// Reload the barectf configuration files when they change or when SIGHUP is received.
+    #[serde(deserialize_with = "from_str", alias = "reload_config")]
+    reload_config: Option<bool>,
+
+    /// How often to check the barectf configuration files for changes.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[serde(deserialize_with = "from_str", alias = "reload_poll_interval")]
+    reload_poll_interval: Option<String>,
+
+    /// Record the raw packet data received to the given file, to re-import it later
+    #[serde(deserialize_with = "from_str")]
+    record: Option<PathBuf>,
+
+    /// Start a new recording file once the current one reaches this many bytes
+    #[serde(deserialize_with = "from_str", alias = "record_rotate_size")]
+    record_rotate_size: Option<u64>,
+
+    /// Start a new recording file periodically.
+    /// Accepts durations like "10ms" or "1minute 2seconds 22ms".
+    #[serde(deserialize_with = "from_str", alias = "record_rotate_interval")]
+    record_rotate_interval: Option<String>,
+
     #[serde(flatten)]
     common: CommonConfig,
 }
@@ -70,6 +190,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Time domain is handled expclicitly by the plugin
     config.time_domain = None;
 
+    if opts.output.is_some() {
+        config.plugin.common.output.clone_from(&opts.output);
+    }
+    if opts.output_format.is_some() {
+        config.plugin.common.output_format = opts.output_format;
+    }
+
     let bctf_cfg_from_conf_file = match config.plugin.common.envsub_config_path() {
         Ok(maybe_cfg) => maybe_cfg,
         Err(e) => {
@@ -82,22 +209,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .config
         .as_ref()
         .or(bctf_cfg_from_conf_file.as_ref())
+        .cloned()
         .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
-    info!(file = %bctf_cfg_path.display(), "Reading effective-configuration yaml");
-    let bctf_cfg_content = fs::read_to_string(&bctf_cfg_path).await.map_err(|e| {
-        anyhow!(
-            "Failed to open barectf effective-configuration yaml file '{}'. {}",
-            bctf_cfg_path.display(),
-            e
-        )
-    })?;
-    let bctf_cfg: BarectfConfig = serde_yaml::from_str(&bctf_cfg_content).map_err(|e| {
-        anyhow!(
-            "Failed to parse barectf effective-configuration yaml file '{}'. {}",
-            bctf_cfg_path.display(),
-            e
-        )
-    })?;
+    let mut additional_cfg_paths = opts.additional_configs.clone();
+    match config.plugin.common.envsub_additional_config_paths() {
+        Ok(paths) => additional_cfg_paths.extend(paths),
+        Err(e) => {
+            error!(%e, "Failed to run envsub on additional configuration paths from reflector configuration file");
+            additional_cfg_paths.extend(config.plugin.common.additional_configs.iter().cloned());
+        }
+    }
+    let bctf_cfgs = load_barectf_configs(&bctf_cfg_path, &additional_cfg_paths).await?;
+
+    let listen = opts
+        .listen
+        .as_ref()
+        .or(config.plugin.listen.as_ref())
+        .map(|addr| {
+            addr.parse::<SocketAddr>()
+                .map_err(|e| anyhow!("Invalid listen address '{}'. {}", addr, e))
+        })
+        .transpose()?;
 
     let remote_string = if let Some(remote) = opts.remote.as_ref().or(config.plugin.remote.as_ref())
     {
@@ -106,16 +238,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         "127.0.0.1:8888".to_string()
     };
 
-    let common_timeline_attrs = vec![
-        (
-            "modality_barectf.plugin.version".into(),
-            PLUGIN_VERSION.into(),
-        ),
-        (
-            "modality_barectf.tcp_collector.remote".into(),
-            remote_string.clone().into(),
-        ),
-    ];
+    let mut common_timeline_attrs = vec![(
+        "modality_barectf.plugin.version".into(),
+        PLUGIN_VERSION.into(),
+    )];
+    if let Some(addr) = listen.as_ref() {
+        common_timeline_attrs.push((
+            "modality_barectf.tcp_collector.listen".into(),
+            addr.to_string().into(),
+        ));
+    }
 
     let connect_timeout = opts
         .connect_timeout
@@ -125,85 +257,483 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .transpose()
         .map_err(|e| anyhow!("Invalid connect-timeout. {}", e))?;
 
-    let client = config
-        .connect_and_authenticate_ingest()
-        .await
-        .map_err(|e| anyhow!("Failed to connect to modality/reflector. {}", e))?;
+    let max_reconnect_backoff = if opts.reconnect || config.plugin.reconnect.unwrap_or(false) {
+        let max_backoff = opts
+            .reconnect_max_backoff
+            .as_ref()
+            .or(config.plugin.reconnect_max_backoff.as_ref())
+            .map(|to| humantime::Duration::from_str(to))
+            .transpose()
+            .map_err(|e| anyhow!("Invalid reconnect-max-backoff. {}", e))?;
+        Some(
+            max_backoff
+                .map(|d| d.into())
+                .unwrap_or(DEFAULT_MAX_RECONNECT_BACKOFF),
+        )
+    } else {
+        None
+    };
+    let reconnect_marker_event =
+        opts.reconnect_marker_event || config.plugin.reconnect_marker_event.unwrap_or(false);
+
+    let reload_poll_interval = opts
+        .reload_poll_interval
+        .as_ref()
+        .or(config.plugin.reload_poll_interval.as_ref())
+        .map(|to| humantime::Duration::from_str(to))
+        .transpose()
+        .map_err(|e| anyhow!("Invalid reload-poll-interval. {}", e))?;
+
+    let reloader = if opts.reload_config || config.plugin.reload_config.unwrap_or(false) {
+        let cfg_paths: Vec<PathBuf> = std::iter::once(bctf_cfg_path.clone())
+            .chain(additional_cfg_paths.iter().cloned())
+            .collect();
+        debug!(
+            files = cfg_paths.len(),
+            "Watching barectf configuration files"
+        );
+        Some(
+            ConfigReloader::new(
+                &cfg_paths,
+                reload_poll_interval
+                    .map(|d| d.into())
+                    .unwrap_or(DEFAULT_RELOAD_POLL_INTERVAL),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+
+    let record = match opts.record.as_ref().or(config.plugin.record.as_ref()) {
+        Some(path) => {
+            let rotate_interval = opts
+                .record_rotate_interval
+                .as_ref()
+                .or(config.plugin.record_rotate_interval.as_ref())
+                .map(|to| humantime::Duration::from_str(to))
+                .transpose()
+                .map_err(|e| anyhow!("Invalid record-rotate-interval. {}", e))?;
+            Some(RecordOptions {
+                path: path.clone(),
+                rotate_size: opts.record_rotate_size.or(config.plugin.record_rotate_size),
+                rotate_interval: rotate_interval.map(|d| d.into()),
+            })
+        }
+        None => None,
+    };
 
-    info!("Connected to Modality backend");
+    let output = Output::open(&config).await?;
 
+    let start_event = config.plugin.common.start_event.clone();
     let mut sender = Sender::new(
-        client,
-        &bctf_cfg,
+        output,
+        &bctf_cfgs[0],
         common_timeline_attrs.into_iter().collect(),
         config,
-    );
+    )?;
+    for cfg in bctf_cfgs.iter().skip(1) {
+        sender.add_config(cfg);
+    }
+
+    let listener = match listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow!("Failed to listen on '{}'. {}", addr, e))?;
+            info!(%addr, "Listening for connections");
+            Some(listener)
+        }
+        None => None,
+    };
 
-    let remote = if let Ok(socket_addr) = remote_string.parse::<SocketAddr>() {
-        socket_addr
+    let remotes = if listener.is_none() {
+        remote_string
+            .split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(|r| resolve_remote(r).map(|addr| (r.to_owned(), addr)))
+            .collect::<Result<Vec<_>, _>>()?
     } else {
-        let url = Url::parse(&remote_string)
-            .map_err(|e| anyhow!("Failed to parse remote '{}' as URL. {}", remote_string, e))?;
-        debug!(remote_url = %url);
-        let socket_addrs = url
-            .socket_addrs(|| None)
-            .map_err(|e| anyhow!("Failed to resolve remote URL '{}'. {}", url, e))?;
-        *socket_addrs
-            .first()
-            .ok_or_else(|| anyhow!("Could not resolve URL '{}'", url))?
+        Vec::new()
     };
 
-    let tcp_stream = match connect_timeout {
-        Some(to) if !to.is_zero() => connect_retry_loop(&remote, to.into()).await?,
-        _ => {
-            info!(remote = %remote, "Connecting to to remote");
-            TcpStream::connect(remote).await?
-        }
+    let (events_tx, events_rx) = mpsc::channel(CONNECTION_EVENT_CHANNEL_SIZE);
+    let (cfgs_tx, _) = watch::channel(Arc::new(Configs {
+        first_config: ConfigId::default(),
+        configs: bctf_cfgs,
+    }));
+    let mut collector = Collector {
+        sender,
+        bctf_cfg_path,
+        additional_cfg_paths,
+        reloader,
+        cfgs_tx,
+        start_event,
+        events_tx,
+        events_rx,
+        connections: 0,
+        reconnect_marker_event,
+        record,
+        stop: CancellationToken::new(),
     };
 
-    let mut join_handle = tokio::spawn(async move {
-        let decoder = Parser::new(&bctf_cfg)?.into_packet_decoder();
-        let mut reader = FramedRead::new(BufReader::new(tcp_stream), decoder);
-
-        while let Some(pkt_res) = reader.next().await {
-            let pkt = match pkt_res {
-                Ok(p) => p,
-                Err(e) => {
-                    // NOTE: doesn't support recovery yet
-                    sender.close().await?;
-                    return Err(anyhow!("Failed to parse CTF packet from stream. {}", e));
+    let stop = collector.stop.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            debug!("User signaled shutdown");
+            stop.cancel();
+        }
+    });
+
+    let multiple_remotes = remotes.len() > 1;
+    for (remote_string, remote) in remotes.into_iter() {
+        // Each remote records to its own files, across reconnections
+        let recorder = collector
+            .record
+            .as_ref()
+            .map(|opts| Recorder::spawn(opts, multiple_remotes.then_some(remote_string.as_str())));
+        let source = collector.sender.add_source(vec![(
+            "modality_barectf.tcp_collector.remote".into(),
+            remote_string.into(),
+        )]);
+        collector.spawn_remote_connection(
+            source,
+            remote,
+            connect_timeout.map(|t| t.into()),
+            max_reconnect_backoff,
+            recorder,
+        );
+    }
+
+    // The sender is closed on shutdown too, so buffered packets and output files are flushed
+    let join_handle = tokio::spawn(async move {
+        let res = collector.run(listener).await;
+        collector.sender.close().await?;
+        res?;
+        info!("Finished");
+        Ok::<(), anyhow::Error>(())
+    });
+    join_handle.await??;
+
+    Ok(())
+}
+
+enum ConnectionEvent {
+    Packet(SourceId, ConfigId, Packet),
+    Reconnected(SourceId),
+    Closed(SourceId, SocketAddr, Result<(), anyhow::Error>),
+}
+
+/// Owns the sender, shared by all of the connections.
+///
+/// Each connection is read and decoded by its own task, which forwards
+/// the packets to the collector.
+struct Collector {
+    sender: Sender<CollectorConfig>,
+    bctf_cfg_path: PathBuf,
+    additional_cfg_paths: Vec<PathBuf>,
+    reloader: Option<ConfigReloader>,
+    /// The configuration used by the connections' decoders
+    cfgs_tx: watch::Sender<Arc<Configs>>,
+    /// Connections switch to a reloaded configuration when the trace restarts with this event
+    start_event: Option<String>,
+    events_tx: mpsc::Sender<ConnectionEvent>,
+    events_rx: mpsc::Receiver<ConnectionEvent>,
+    /// Number of active connections
+    connections: usize,
+    /// Send a marker event when a remote reconnects
+    reconnect_marker_event: bool,
+    /// Record the raw packet data of each connection
+    record: Option<RecordOptions>,
+    /// Cancelled when the user signals shutdown
+    stop: CancellationToken,
+}
+
+impl Collector {
+    /// Handle the connections until they're all closed or the user signals
+    /// shutdown, only the latter when listening for incoming connections
+    async fn run(&mut self, listener: Option<TcpListener>) -> Result<(), anyhow::Error> {
+        let mut failed_connections = 0;
+        let mut accept_backoff = INITIAL_ACCEPT_BACKOFF;
+        let mut accept_after = None;
+
+        while listener.is_some() || self.connections != 0 {
+            tokio::select! {
+                res = accept(listener.as_ref(), accept_after) => {
+                    let (tcp_stream, peer) = match res {
+                        Ok(conn) => {
+                            accept_backoff = INITIAL_ACCEPT_BACKOFF;
+                            accept_after = None;
+                            conn
+                        }
+                        Err(e) => {
+                            error!(%e, backoff = ?accept_backoff, "Failed to accept connection");
+                            accept_after = Some(Instant::now() + accept_backoff);
+                            accept_backoff = (accept_backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                            continue;
+                        }
+                    };
+                    info!(%peer, "Accepted connection");
+                    let source = self.sender.add_source(vec![(
+                        "modality_barectf.tcp_collector.peer_address".into(),
+                        peer.to_string().into(),
+                    )]);
+                    let recorder = self
+                        .record
+                        .as_ref()
+                        .map(|opts| Recorder::spawn(opts, Some(&peer.to_string())));
+                    self.spawn_connection(source, peer, tcp_stream, recorder);
+                }
+                ev = self.events_rx.recv() => {
+                    // The collector holds a sender, this can't end
+                    let Some(ev) = ev else {
+                        break;
+                    };
+                    match ev {
+                        ConnectionEvent::Packet(source, cfg_id, pkt) => {
+                            self.sender.handle_routed_packet(source, cfg_id, &pkt).await?;
+                        }
+                        ConnectionEvent::Reconnected(source) => {
+                            self.sender
+                                .handle_source_reconnect(source, self.reconnect_marker_event)
+                                .await?;
+                        }
+                        ConnectionEvent::Closed(source, addr, res) => {
+                            self.connections -= 1;
+                            self.sender.remove_source(source).await?;
+                            match res {
+                                Ok(()) => info!(%addr, "Connection closed"),
+                                Err(e) => {
+                                    error!(%addr, %e, "Connection failed");
+                                    failed_connections += 1;
+                                }
+                            }
+                        }
+                    }
+                }
+                _ = reload::changed(&mut self.reloader) => {
+                    if let Some(cfgs) = self.load_configs().await {
+                        self.apply_configs(cfgs);
+                    }
+                }
+                _ = self.stop.cancelled() => {
+                    // The connections stop reading, keep what they already decoded
+                    while let Ok(ev) = self.events_rx.try_recv() {
+                        if let ConnectionEvent::Packet(source, cfg_id, pkt) = ev {
+                            self.sender.handle_routed_packet(source, cfg_id, &pkt).await?;
+                        }
+                    }
+                    break;
+                }
+            }
+        }
+
+        if failed_connections != 0 {
+            return Err(anyhow!("{} connection(s) failed", failed_connections));
+        }
+        Ok(())
+    }
+
+    /// Connect to a remote and read from it, reconnecting with a backoff
+    /// when `max_reconnect_backoff` is set
+    fn spawn_remote_connection(
+        &mut self,
+        source: SourceId,
+        remote: SocketAddr,
+        connect_timeout: Option<Duration>,
+        max_reconnect_backoff: Option<Duration>,
+        recorder: Option<Recorder>,
+    ) {
+        self.connections += 1;
+        let cfgs_rx = self.cfgs_tx.subscribe();
+        let start_event = self.start_event.clone();
+        let events_tx = self.events_tx.clone();
+        spawn_until_stopped(self.stop.clone(), async move {
+            let mut backoff = INITIAL_RECONNECT_BACKOFF;
+            let mut connected_before = false;
+            let res = loop {
+                let res = match connect_remote(&remote, connect_timeout).await {
+                    Ok(tcp_stream) => {
+                        if connected_before {
+                            info!(%remote, "Reconnected to remote");
+                            if events_tx
+                                .send(ConnectionEvent::Reconnected(source))
+                                .await
+                                .is_err()
+                            {
+                                break Ok(());
+                            }
+                        }
+                        connected_before = true;
+                        backoff = INITIAL_RECONNECT_BACKOFF;
+                        read_connection(
+                            tcp_stream,
+                            source,
+                            cfgs_rx.clone(),
+                            start_event.clone(),
+                            recorder.clone(),
+                            &events_tx,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+
+                let Some(max_backoff) = max_reconnect_backoff else {
+                    break res;
+                };
+                if events_tx.is_closed() {
+                    break res;
                 }
+                match res {
+                    Ok(()) => warn!(%remote, ?backoff, "Connection closed, reconnecting"),
+                    Err(e) => warn!(%remote, %e, ?backoff, "Connection failed, reconnecting"),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
             };
+            let _ = events_tx
+                .send(ConnectionEvent::Closed(source, remote, res))
+                .await;
+        });
+    }
 
-            sender.handle_packet(&pkt).await?;
+    fn spawn_connection(
+        &mut self,
+        source: SourceId,
+        addr: SocketAddr,
+        tcp_stream: TcpStream,
+        recorder: Option<Recorder>,
+    ) {
+        self.connections += 1;
+        let cfgs_rx = self.cfgs_tx.subscribe();
+        let start_event = self.start_event.clone();
+        let events_tx = self.events_tx.clone();
+        spawn_until_stopped(self.stop.clone(), async move {
+            let res = read_connection(
+                tcp_stream,
+                source,
+                cfgs_rx,
+                start_event,
+                recorder,
+                &events_tx,
+            )
+            .await;
+            let _ = events_tx
+                .send(ConnectionEvent::Closed(source, addr, res))
+                .await;
+        });
+    }
+
+    async fn load_configs(&self) -> Option<Vec<BarectfConfig>> {
+        match load_barectf_configs(&self.bctf_cfg_path, &self.additional_cfg_paths).await {
+            Ok(cfgs) => Some(cfgs),
+            Err(e) => {
+                error!(%e, "Failed to reload the barectf configuration, keeping the current configuration");
+                None
+            }
         }
+    }
 
-        sender.close().await?;
-        info!("Finished");
+    /// Register reloaded configurations and hand them to the connections,
+    /// each switches to them at its own trace restart
+    fn apply_configs(&mut self, configs: Vec<BarectfConfig>) {
+        let first_config = self.sender.add_configs(&configs);
+        self.cfgs_tx.send_replace(Arc::new(Configs {
+            first_config,
+            configs,
+        }));
+    }
+}
 
-        Ok(())
+/// Run a connection task until it ends or the user signals shutdown
+fn spawn_until_stopped<F>(stop: CancellationToken, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = stop.cancelled() => (),
+            _ = task => (),
+        }
     });
+}
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            debug!("User signaled shutdown");
-            join_handle.abort();
-        }
-        res = &mut join_handle => {
-            match res? {
-                Ok(_) => {},
-                Err(e) => return Err(e.into()),
-            }
+/// Read and decode the packets of a connection, forwarding them to the collector
+async fn read_connection(
+    tcp_stream: TcpStream,
+    source: SourceId,
+    cfgs_rx: watch::Receiver<Arc<Configs>>,
+    start_event: Option<String>,
+    recorder: Option<Recorder>,
+    events_tx: &mpsc::Sender<ConnectionEvent>,
+) -> Result<(), anyhow::Error> {
+    let mut reader = PacketReader::new(BufReader::new(tcp_stream), cfgs_rx, start_event, recorder)?;
+
+    while let Some(pkt_res) = reader.next().await {
+        let (cfg_id, pkt) =
+            pkt_res.map_err(|e| anyhow!("Failed to parse CTF packet from stream. {}", e))?;
+        if events_tx
+            .send(ConnectionEvent::Packet(source, cfg_id, pkt))
+            .await
+            .is_err()
+        {
+            break;
         }
-    };
+    }
 
     Ok(())
 }
 
-async fn connect_retry_loop(
+async fn connect_remote(
     remote: &SocketAddr,
-    timeout: Duration,
-) -> Result<TcpStream, Box<dyn std::error::Error + Send + Sync>> {
+    connect_timeout: Option<Duration>,
+) -> Result<TcpStream, anyhow::Error> {
+    let tcp_stream = match connect_timeout {
+        Some(to) if !to.is_zero() => connect_retry_loop(remote, to).await,
+        _ => {
+            info!(remote = %remote, "Connecting to to remote");
+            TcpStream::connect(remote).await
+        }
+    };
+    tcp_stream.map_err(|e| anyhow!("Failed to connect to remote '{}'. {}", remote, e))
+}
+
+/// Accept the next incoming connection once `after` has passed, or wait
+/// forever when not listening
+async fn accept(
+    listener: Option<&TcpListener>,
+    after: Option<Instant>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(l) => {
+            if let Some(after) = after {
+                tokio::time::sleep_until(after).await;
+            }
+            l.accept().await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn resolve_remote(remote: &str) -> Result<SocketAddr, anyhow::Error> {
+    if let Ok(socket_addr) = remote.parse::<SocketAddr>() {
+        return Ok(socket_addr);
+    }
+    let url = Url::parse(remote)
+        .map_err(|e| anyhow!("Failed to parse remote '{}' as URL. {}", remote, e))?;
+    debug!(remote_url = %url);
+    let socket_addrs = url
+        .socket_addrs(|| None)
+        .map_err(|e| anyhow!("Failed to resolve remote URL '{}'. {}", url, e))?;
+    socket_addrs
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("Could not resolve URL '{}'", url))
+}
+
+async fn connect_retry_loop(remote: &SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
     info!(remote = %remote, timeout = ?timeout, "Connecting to to remote");
     let inner_timeout = timeout / 4;
     let start = Instant::now();
@@ -220,5 +750,5 @@ async fn connect_retry_loop(
             }
         }
     }
-    Ok(TcpStream::connect(remote).await?)
+    TcpStream::connect(remote).await
 }
@@ -0,0 +1,276 @@
+use anyhow::anyhow;
+use auxon_sdk::plugin_utils::serde::from_str;
+use auxon_sdk::{init_tracing, plugin_utils::ingest::Config};
+use barectf_parser::Config as BarectfConfig;
+use clap::Parser as ClapParser;
+use modality_barectf_plugin::{
+    load_barectf_configs,
+    reorder::{SequenceReorderer, DEFAULT_REORDER_WINDOW},
+    routing::RoutingDecoder,
+    CommonConfig, ConfigId, HasCommonConfig, Output, OutputFormat, Sender, SourceId,
+    PLUGIN_VERSION,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+};
+use tokio::net::UdpSocket;
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+use tracing::{debug, error, info, warn};
+
+/// The largest possible UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Collect barectf streams from UDP datagrams
+#[derive(Debug, clap::Parser)]
+#[clap(version, about = "Collect barectf streams from UDP datagrams", long_about = None)]
+struct CollectorOpts {
+    /// The barectf effective-configuration yaml file, or a CTF TSDL metadata file
+    config: Option<PathBuf>,
+
+    /// An additional barectf configuration file, for traces from other
+    /// firmware variants. The configuration is selected by trace UUID.
+    ///
+    /// Can be supplied multiple times
+    #[clap(long = "additional-config", name = "additional-config")]
+    additional_configs: Vec<PathBuf>,
+
+    /// Write the converted timelines and events to this file instead of
+    /// sending them to Modality
+    #[clap(long, name = "output")]
+    output: Option<PathBuf>,
+
+    /// The output file format: `jsonl`, `csv` or `chrome-trace`.
+    /// The default is based on the output file extension, falling back to `jsonl`.
+    #[clap(long, name = "output-format")]
+    output_format: Option<OutputFormat>,
+
+    /// The maximum number of out-of-order packets held back per stream
+    /// while waiting for a missing packet sequence number.
+    ///
+    /// The default is 16.
+    #[clap(long, name = "reorder-window")]
+    reorder_window: Option<usize>,
+
+    /// The local address:port to receive datagrams on.
+    ///
+    /// The default is `0.0.0.0:8888`.
+    bind: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct CollectorConfig {
+    /// The local address:port to receive datagrams on.
+    ///
+    /// The default is `0.0.0.0:8888`.
+    #[serde(deserialize_with = "from_str")]
+    bind: Option<String>,
+
+    /// The maximum number of out-of-order packets held back per stream
+    /// while waiting for a missing packet sequence number.
+    #[serde(deserialize_with = "from_str", alias = "reorder_window")]
+    reorder_window: Option<usize>,
+
+    #[serde(flatten)]
+    common: CommonConfig,
+}
+
+impl HasCommonConfig for CollectorConfig {
+    fn common_config(&self) -> &CommonConfig {
+        &self.common
+    }
+}
+
+/// The decoding state of each peer sending datagrams
+struct Peer {
+    source: SourceId,
+    decoder: RoutingDecoder,
+    reorderer: SequenceReorderer<ConfigId>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    init_tracing!();
+
+    let opts = CollectorOpts::parse();
+
+    let mut config = Config::<CollectorConfig>::load("MODALITY_BARECTF_")?;
+
+    // Time domain is handled expclicitly by the plugin
+    config.time_domain = None;
+
+    if opts.output.is_some() {
+        config.plugin.common.output.clone_from(&opts.output);
+    }
+    if opts.output_format.is_some() {
+        config.plugin.common.output_format = opts.output_format;
+    }
+
+    let bctf_cfg_from_conf_file = match config.plugin.common.envsub_config_path() {
+        Ok(maybe_cfg) => maybe_cfg,
+        Err(e) => {
+            error!(%e, "Failed to run envsub on effective-configuration yaml path from reflector configuration file");
+            config.plugin.common.config.clone()
+        }
+    };
+
+    let bctf_cfg_path = opts
+        .config
+        .as_ref()
+        .or(bctf_cfg_from_conf_file.as_ref())
+        .ok_or_else(|| anyhow!("Missing barectf effective-configuration yaml file"))?;
+    let mut additional_cfg_paths = opts.additional_configs.clone();
+    match config.plugin.common.envsub_additional_config_paths() {
+        Ok(paths) => additional_cfg_paths.extend(paths),
+        Err(e) => {
+            error!(%e, "Failed to run envsub on additional configuration paths from reflector configuration file");
+            additional_cfg_paths.extend(config.plugin.common.additional_configs.iter().cloned());
+        }
+    }
+    let bctf_cfgs = load_barectf_configs(bctf_cfg_path, &additional_cfg_paths).await?;
+
+    let bind_string = opts
+        .bind
+        .as_ref()
+        .or(config.plugin.bind.as_ref())
+        .cloned()
+        .unwrap_or_else(|| "0.0.0.0:8888".to_string());
+    let bind = bind_string
+        .parse::<SocketAddr>()
+        .map_err(|e| anyhow!("Invalid bind address '{}'. {}", bind_string, e))?;
+
+    let reorder_window = opts
+        .reorder_window
+        .or(config.plugin.reorder_window)
+        .unwrap_or(DEFAULT_REORDER_WINDOW);
+    let start_event = config.plugin.common.start_event.clone();
+
+    let common_timeline_attrs = vec![
+        (
+            "modality_barectf.plugin.version".into(),
+            PLUGIN_VERSION.into(),
+        ),
+        (
+            "modality_barectf.udp_collector.bind".into(),
+            bind.to_string().into(),
+        ),
+    ];
+
+    let socket = UdpSocket::bind(bind)
+        .await
+        .map_err(|e| anyhow!("Failed to bind UDP socket to '{}'. {}", bind, e))?;
+    info!(%bind, "Receiving datagrams");
+
+    let output = Output::open(&config).await?;
+
+    let mut sender = Sender::new(
+        output,
+        &bctf_cfgs[0],
+        common_timeline_attrs.into_iter().collect(),
+        config,
+    )?;
+    for cfg in bctf_cfgs.iter().skip(1) {
+        sender.add_config(cfg);
+    }
+
+    let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+    let res = receive_datagrams(
+        &socket,
+        &mut sender,
+        &mut peers,
+        &bctf_cfgs,
+        reorder_window,
+        start_event.as_deref(),
+    )
+    .await;
+
+    // Send whatever is still waiting on missing packets
+    for peer in peers.values_mut() {
+        for (cfg_id, pkt) in peer.reorderer.drain().into_iter() {
+            sender
+                .handle_routed_packet(peer.source, cfg_id, &pkt)
+                .await?;
+        }
+    }
+    sender.close().await?;
+    res?;
+    info!("Finished");
+
+    Ok(())
+}
+
+/// Receive and decode datagrams until the user signals shutdown
+async fn receive_datagrams(
+    socket: &UdpSocket,
+    sender: &mut Sender<CollectorConfig>,
+    peers: &mut HashMap<SocketAddr, Peer>,
+    bctf_cfgs: &[BarectfConfig],
+    reorder_window: usize,
+    start_event: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let mut buf = vec![0_u8; MAX_DATAGRAM_SIZE];
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        let (len, peer_addr) = tokio::select! {
+            _ = &mut ctrl_c => {
+                debug!("User signaled shutdown");
+                return Ok(());
+            }
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(res) => res,
+                Err(e) => {
+                    // i.e. ICMP port unreachable reported on some platforms, the socket is still usable
+                    warn!(%e, "Failed to receive datagram");
+                    continue;
+                }
+            },
+        };
+
+        let peer = match peers.entry(peer_addr) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                info!(peer = %peer_addr, "Receiving datagrams from new peer");
+                let source = sender.add_source(vec![(
+                    "modality_barectf.udp_collector.peer_address".into(),
+                    peer_addr.to_string().into(),
+                )]);
+                v.insert(Peer {
+                    source,
+                    decoder: RoutingDecoder::new(bctf_cfgs)?,
+                    reorderer: SequenceReorderer::new(reorder_window, start_event),
+                })
+            }
+        };
+
+        // Each datagram contains one or more whole packets
+        let mut datagram = BytesMut::from(&buf[..len]);
+        loop {
+            match peer.decoder.decode(&mut datagram) {
+                Ok(Some((cfg_id, pkt))) => {
+                    for (cfg_id, pkt) in peer.reorderer.push(cfg_id, pkt).into_iter() {
+                        sender
+                            .handle_routed_packet(peer.source, cfg_id, &pkt)
+                            .await?;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!(peer = %peer_addr, %e, "Failed to parse CTF packet from datagram");
+                    datagram.clear();
+                    break;
+                }
+            }
+        }
+        if !datagram.is_empty() {
+            warn!(
+                peer = %peer_addr,
+                bytes = datagram.len(),
+                "Discarding incomplete packet at the end of the datagram"
+            );
+        }
+    }
+}
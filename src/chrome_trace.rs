@@ -0,0 +1,272 @@
+use crate::{
+    output::{attr_json, event_timestamp_ns},
+    pairs::{EventPair, PairMatch, PairMatcher},
+    sink::Sink,
+};
+use anyhow::anyhow;
+use auxon_sdk::api::{AttrVal, TimelineId};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+use tracing::info;
+
+/// All timelines are threads of a single process
+const PID: u64 = 1;
+
+/// Closes the JSON array, written on flush and overwritten by later events
+const TRAILER: &str = "\n]\n";
+
+/// Writes timelines and events as Chrome Trace Event JSON, viewable
+/// in `chrome://tracing` or the Perfetto UI.
+///
+/// Each timeline is a thread, events are instant events, and the
+/// configured begin/end event pairs are begin (`B`) and end (`E`) events.
+/// Events are written as they're sent, using the JSON array format, which
+/// the viewers also load when the closing `]` is missing.
+pub struct ChromeTraceOutput {
+    path: PathBuf,
+    writer: BufWriter<fs::File>,
+    timelines: HashMap<TimelineId, u64>,
+    current_timeline: u64,
+    /// The last timestamp of each timeline, for events without one
+    last_timestamps: HashMap<u64, u64>,
+    pairs: PairMatcher<u64, ()>,
+    /// Whether an event was written, the following ones are comma-separated
+    wrote_event: bool,
+    /// Whether the trailer was written by the last flush
+    closed: bool,
+}
+
+impl ChromeTraceOutput {
+    pub async fn create(path: &Path, event_pairs: Vec<EventPair>) -> Result<Self, anyhow::Error> {
+        let file = fs::File::create(path)
+            .await
+            .map_err(|e| anyhow!("Failed to create output file '{}'. {}", path.display(), e))?;
+        info!(file = %path.display(), "Writing Chrome Trace Event file");
+        let mut out = Self {
+            path: path.to_owned(),
+            writer: BufWriter::new(file),
+            timelines: Default::default(),
+            current_timeline: 0,
+            last_timestamps: Default::default(),
+            pairs: PairMatcher::new(event_pairs),
+            wrote_event: false,
+            closed: false,
+        };
+        out.write("[\n").await?;
+        Ok(out)
+    }
+
+    async fn write_event(&mut self, event: Value) -> Result<(), anyhow::Error> {
+        if self.closed {
+            self.writer
+                .seek(SeekFrom::Current(-(TRAILER.len() as i64)))
+                .await
+                .map_err(|e| self.write_error(e))?;
+            self.closed = false;
+        }
+        if self.wrote_event {
+            self.write(",\n").await?;
+        }
+        self.wrote_event = true;
+        let event = serde_json::to_string(&event)?;
+        self.write(&event).await
+    }
+
+    async fn write(&mut self, data: &str) -> Result<(), anyhow::Error> {
+        self.writer
+            .write_all(data.as_bytes())
+            .await
+            .map_err(|e| self.write_error(e))
+    }
+
+    fn write_error(&self, e: std::io::Error) -> anyhow::Error {
+        anyhow!(
+            "Failed to write to output file '{}'. {}",
+            self.path.display(),
+            e
+        )
+    }
+}
+
+fn args(attrs: &[(&str, AttrVal)]) -> Map<String, Value> {
+    attrs
+        .iter()
+        .map(|(k, v)| (k.to_string(), attr_json(v)))
+        .collect()
+}
+
+/// Chrome trace timestamps are in microseconds
+fn micros(ns: u64) -> f64 {
+    ns as f64 / 1000.0
+}
+
+impl Sink for ChromeTraceOutput {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), anyhow::Error> {
+        let next = self.timelines.len() as u64;
+        self.current_timeline = *self.timelines.entry(id).or_insert(next);
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        _attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        self.write_event(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": PID,
+            "tid": self.current_timeline,
+            "args": { "name": name },
+        }))
+        .await
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        _ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let tid = self.current_timeline;
+        let timestamp_ns = match event_timestamp_ns(&attrs) {
+            Some(ns) => {
+                self.last_timestamps.insert(tid, ns);
+                ns
+            }
+            None => self.last_timestamps.get(&tid).copied().unwrap_or(0),
+        };
+
+        // End events without a begin event show up as instants
+        let ph = match self.pairs.handle(&tid, name, &attrs, || ()) {
+            PairMatch::Begin => "B",
+            PairMatch::End(()) => "E",
+            PairMatch::Unpaired | PairMatch::UnmatchedEnd => "i",
+        };
+        let mut event = json!({
+            "name": name,
+            "ph": ph,
+            "pid": PID,
+            "tid": tid,
+            "ts": micros(timestamp_ns),
+            "args": args(&attrs),
+        });
+        if ph == "i" {
+            // Thread-scoped instant
+            event["s"] = "t".into();
+        }
+        self.write_event(event).await
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        if !self.closed {
+            self.write(TRAILER).await?;
+            self.closed = true;
+        }
+        self.writer.flush().await.map_err(|e| self.write_error(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, CommonConfig, HasCommonConfig, Sender};
+    use auxon_sdk::plugin_utils::ingest::Config;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[serde(default)]
+    struct TestConfig {
+        #[serde(flatten)]
+        common: CommonConfig,
+    }
+
+    impl HasCommonConfig for TestConfig {
+        fn common_config(&self) -> &CommonConfig {
+            &self.common
+        }
+    }
+
+    fn pairs(pairs: &[&str]) -> Vec<EventPair> {
+        pairs.iter().map(|p| p.parse().unwrap()).collect()
+    }
+
+    async fn trace_events(path: &Path) -> Vec<Value> {
+        let trace: Value = serde_json::from_slice(&fs::read(path).await.unwrap()).unwrap();
+        trace.as_array().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn converts_the_integration_test_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.trace.json");
+        let out = ChromeTraceOutput::create(&path, pairs(&["init:shutdown", "foobar:arrays"]))
+            .await
+            .unwrap();
+        let config = Config::<TestConfig>::load("MODALITY_BARECTF_TEST_").unwrap();
+        let mut sender = Sender::new(out, &fixtures::config(), Default::default(), config).unwrap();
+        for pkt in fixtures::packets().iter() {
+            sender.handle_packet(pkt).await.unwrap();
+        }
+        sender.close().await.unwrap();
+
+        let events = trace_events(&path).await;
+        assert_eq!(events[0]["ph"], "M");
+        let summary: Vec<(&str, &str, f64, u64, u64)> = events
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap(),
+                    e["name"].as_str().unwrap(),
+                    e["ts"].as_f64().unwrap(),
+                    e["pid"].as_u64().unwrap(),
+                    e["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("B", "init", 0.0, PID, 0),
+                ("B", "foobar", 0.001, PID, 0),
+                ("i", "floats", 0.002, PID, 0),
+                ("i", "enums", 0.003, PID, 0),
+                ("E", "arrays", 0.004, PID, 0),
+                ("E", "shutdown", 0.005, PID, 0),
+            ]
+        );
+        let foobar = events.iter().find(|e| e["name"] == "foobar").unwrap();
+        assert!(foobar["args"].get("val").is_some());
+    }
+
+    #[tokio::test]
+    async fn events_after_a_flush_reopen_the_trace() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.trace.json");
+        let mut out = ChromeTraceOutput::create(&path, Vec::new()).await.unwrap();
+        out.flush().await.unwrap();
+        assert!(trace_events(&path).await.is_empty());
+
+        out.switch_timeline(TimelineId::allocate()).await.unwrap();
+        out.send_event("a", 0, Vec::new()).await.unwrap();
+        out.flush().await.unwrap();
+        out.send_event("b", 1, Vec::new()).await.unwrap();
+        out.flush().await.unwrap();
+
+        let names: Vec<Value> = trace_events(&path)
+            .await
+            .into_iter()
+            .map(|e| e["name"].clone())
+            .collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+}
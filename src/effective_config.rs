@@ -0,0 +1,842 @@
+//! Produces the barectf effective-configuration from a hand-written barectf 3 yaml
+//! configuration.
+//!
+//! This resolves `$include` (relative to the including file, with barectf's
+//! standard include files built in), field type and log level aliases,
+//! abbreviated field type classes and the defaults barectf would apply.
+
+use anyhow::{anyhow, bail, Error};
+use serde_yaml::{Mapping, Value};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+use tracing::{debug, warn};
+
+/// barectf's standard partial configurations, available to `$include`
+/// without being present on disk
+const STD_INCLUDES: &[(&str, &str)] = &[
+    (
+        "stdint.yaml",
+        r#"
+$field-type-aliases:
+  int8: {class: signed-integer, size: 8, alignment: 8}
+  int16: {class: signed-integer, size: 16, alignment: 8}
+  int32: {class: signed-integer, size: 32, alignment: 8}
+  int64: {class: signed-integer, size: 64, alignment: 8}
+  uint8: {class: unsigned-integer, size: 8, alignment: 8}
+  uint16: {class: unsigned-integer, size: 16, alignment: 8}
+  uint32: {class: unsigned-integer, size: 32, alignment: 8}
+  uint64: {class: unsigned-integer, size: 64, alignment: 8}
+"#,
+    ),
+    (
+        "stdreal.yaml",
+        r#"
+$field-type-aliases:
+  float: {class: real, size: 32, alignment: 32}
+  double: {class: real, size: 64, alignment: 64}
+"#,
+    ),
+    (
+        "stdmisc.yaml",
+        r#"
+$field-type-aliases:
+  str: {class: string}
+  string: {class: string}
+"#,
+    ),
+    (
+        "lttng-ust-log-levels.yaml",
+        r#"
+$log-level-aliases:
+  EMERG: 0
+  EMERGENCY: 0
+  ALERT: 1
+  CRIT: 2
+  CRITICAL: 2
+  ERR: 3
+  ERROR: 3
+  WARNING: 4
+  NOTICE: 5
+  INFO: 6
+  DEBUG_SYSTEM: 7
+  DEBUG_PROGRAM: 8
+  DEBUG_PROCESS: 9
+  DEBUG_MODULE: 10
+  DEBUG_UNIT: 11
+  DEBUG_FUNCTION: 12
+  DEBUG_LINE: 13
+  DEBUG: 14
+"#,
+    ),
+];
+
+/// Keys which only appear in hand-written configurations
+const NON_EFFECTIVE_KEYS: &[&str] = &["$include", "$field-type-aliases", "$log-level-aliases"];
+
+/// Returns true if the configuration uses features that barectf resolves
+/// when producing the effective-configuration
+pub fn needs_resolving(doc: &Value) -> bool {
+    match untagged(doc) {
+        Value::Mapping(m) => m.iter().any(|(k, v)| {
+            matches!(k.as_str(), Some(k) if NON_EFFECTIVE_KEYS.contains(&k)) || needs_resolving(v)
+        }),
+        Value::Sequence(s) => s.iter().any(needs_resolving),
+        _ => false,
+    }
+}
+
+/// Resolve a hand-written barectf configuration into the effective-configuration.
+///
+/// `config_path` is the path of the configuration file, used to resolve includes.
+pub async fn resolve(doc: Value, config_path: &Path) -> Result<Value, Error> {
+    let base_dir = config_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut include_stack = vec![config_path.to_path_buf()];
+    let doc = process_includes(untagged(&doc).clone(), base_dir, "", &mut include_stack).await?;
+
+    let mut root = into_mapping(doc, "")?;
+    let mut trace = into_mapping(
+        root.remove("trace")
+            .ok_or_else(|| anyhow!("missing 'trace' node"))?,
+        "trace",
+    )?;
+    let mut trace_type = into_mapping(
+        trace
+            .remove("type")
+            .ok_or_else(|| anyhow!("trace: missing 'type' node"))?,
+        "trace.type",
+    )?;
+
+    let resolver = Resolver {
+        field_type_aliases: trace_type
+            .remove("$field-type-aliases")
+            .map(|v| into_mapping(v, "trace.type.$field-type-aliases"))
+            .transpose()?
+            .unwrap_or_default(),
+        log_level_aliases: trace_type
+            .remove("$log-level-aliases")
+            .map(|v| into_mapping(v, "trace.type.$log-level-aliases"))
+            .transpose()?
+            .unwrap_or_default(),
+    };
+
+    // Trace type
+    let byte_order = trace_type
+        .get("native-byte-order")
+        .or_else(|| trace_type.get("$default-byte-order"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("trace.type: missing 'native-byte-order'"))?;
+    let byte_order = match byte_order {
+        "le" | "little" | "little-endian" => "little-endian",
+        "be" | "big" | "big-endian" => "big-endian",
+        other => bail!(
+            "trace.type.native-byte-order: unknown byte order '{}'",
+            other
+        ),
+    };
+    trace_type.remove("$default-byte-order");
+    trace_type.insert("native-byte-order".into(), byte_order.into());
+
+    // An 'auto' UUID is still written to the packet headers
+    let has_uuid = trace_type.contains_key("uuid");
+    if trace_type.get("uuid").and_then(Value::as_str) == Some("auto") {
+        warn!("Ignoring the 'auto' trace type UUID, the UUID is only known to the generated code");
+        trace_type.remove("uuid");
+    }
+
+    let mut features = take_mapping(&mut trace_type, "$features", "trace.type")?;
+    resolver.feature(
+        &mut features,
+        "magic-field-type",
+        Some(uint_ft(32, 32)),
+        "trace.type.$features",
+    )?;
+    resolver.feature(
+        &mut features,
+        "data-stream-type-id-field-type",
+        Some(uint_ft(64, 8)),
+        "trace.type.$features",
+    )?;
+    match features.get("uuid-field-type") {
+        Some(Value::Bool(_)) => (),
+        None => {
+            features.insert("uuid-field-type".into(), has_uuid.into());
+        }
+        Some(_) => bail!("trace.type.$features.uuid-field-type: expected a boolean"),
+    }
+    trace_type.insert("$features".into(), Value::Mapping(features));
+
+    // Clock types
+    let mut clock_types = take_mapping(&mut trace_type, "clock-types", "trace.type")?;
+    for (name, clock) in clock_types.iter_mut() {
+        let path = format!("trace.type.clock-types.{}", key_str(name));
+        let clock = as_mapping_mut(clock, &path)?;
+        insert_default(clock, "frequency", 1_000_000_000_u64.into());
+        insert_default(clock, "precision", 0_u64.into());
+        insert_default(clock, "origin-is-unix-epoch", true.into());
+        insert_default(clock, "$c-type", "uint32_t".into());
+    }
+    trace_type.insert("clock-types".into(), Value::Mapping(clock_types));
+
+    // Data stream types
+    let mut data_stream_types = take_mapping(&mut trace_type, "data-stream-types", "trace.type")?;
+    for (name, dst) in data_stream_types.iter_mut() {
+        let path = format!("trace.type.data-stream-types.{}", key_str(name));
+        resolver.data_stream_type(as_mapping_mut(dst, &path)?, &path)?;
+    }
+    trace_type.insert(
+        "data-stream-types".into(),
+        Value::Mapping(data_stream_types),
+    );
+
+    insert_default(&mut trace, "environment", Value::Mapping(Mapping::new()));
+    trace.insert("type".into(), Value::Mapping(trace_type));
+    root.insert("trace".into(), Value::Mapping(trace));
+    Ok(Value::Mapping(root))
+}
+
+struct Resolver {
+    field_type_aliases: Mapping,
+    log_level_aliases: Mapping,
+}
+
+impl Resolver {
+    fn data_stream_type(&self, dst: &mut Mapping, path: &str) -> Result<(), Error> {
+        let has_clock = dst.contains_key("$default-clock-type-name");
+
+        let mut features = take_mapping(dst, "$features", path)?;
+        let features_path = format!("{}.$features", path);
+
+        let mut packet = take_mapping(&mut features, "packet", &features_path)?;
+        let packet_path = format!("{}.packet", features_path);
+        let timestamp_default = has_clock.then(|| uint_ft(64, 8));
+        for (feature, default) in [
+            ("total-size-field-type", Some(uint_ft(64, 8))),
+            ("content-size-field-type", Some(uint_ft(64, 8))),
+            ("beginning-timestamp-field-type", timestamp_default.clone()),
+            ("end-timestamp-field-type", timestamp_default.clone()),
+            (
+                "discarded-event-records-counter-snapshot-field-type",
+                Some(uint_ft(64, 8)),
+            ),
+            ("sequence-number-field-type", None),
+        ] {
+            self.feature(&mut packet, feature, default, &packet_path)?;
+        }
+        features.insert("packet".into(), Value::Mapping(packet));
+
+        let mut event_record = take_mapping(&mut features, "event-record", &features_path)?;
+        let event_record_path = format!("{}.event-record", features_path);
+        self.feature(
+            &mut event_record,
+            "type-id-field-type",
+            Some(uint_ft(64, 8)),
+            &event_record_path,
+        )?;
+        self.feature(
+            &mut event_record,
+            "timestamp-field-type",
+            timestamp_default,
+            &event_record_path,
+        )?;
+        features.insert("event-record".into(), Value::Mapping(event_record));
+        dst.insert("$features".into(), Value::Mapping(features));
+
+        if let Some(members) = dst.get_mut("packet-context-field-type-extra-members") {
+            let members_path = format!("{}.packet-context-field-type-extra-members", path);
+            *members = Value::Sequence(self.members(members, &members_path)?);
+        }
+        for key in [
+            "event-record-common-context-field-type",
+            "event-record-specific-context-field-type",
+        ] {
+            if let Some(ft) = dst.get(key) {
+                let ft = self.field_type(ft, &format!("{}.{}", path, key))?;
+                dst.insert(key.into(), ft);
+            }
+        }
+
+        let mut event_record_types = take_mapping(dst, "event-record-types", path)?;
+        for (name, ert) in event_record_types.iter_mut() {
+            let ert_path = format!("{}.event-record-types.{}", path, key_str(name));
+            if ert.is_null() {
+                *ert = Value::Mapping(Mapping::new());
+            }
+            let ert = as_mapping_mut(ert, &ert_path)?;
+            if let Some(alias) = ert.get("log-level").and_then(Value::as_str) {
+                let level = self.log_level_aliases.get(alias).cloned().ok_or_else(|| {
+                    anyhow!(
+                        "{}.log-level: unknown log level alias '{}'",
+                        ert_path,
+                        alias
+                    )
+                })?;
+                ert.insert("log-level".into(), level);
+            }
+            for key in ["specific-context-field-type", "payload-field-type"] {
+                if let Some(ft) = ert.get(key) {
+                    let ft = self.field_type(ft, &format!("{}.{}", ert_path, key))?;
+                    ert.insert(key.into(), ft);
+                }
+            }
+        }
+        dst.insert(
+            "event-record-types".into(),
+            Value::Mapping(event_record_types),
+        );
+
+        Ok(())
+    }
+
+    /// A feature is either a field type, `true` for the default field type,
+    /// or `false`/`null` to disable it
+    fn feature(
+        &self,
+        features: &mut Mapping,
+        name: &str,
+        default: Option<Value>,
+        path: &str,
+    ) -> Result<(), Error> {
+        let path = format!("{}.{}", path, name);
+        let resolved = match features.remove(name) {
+            None | Some(Value::Bool(true)) => default,
+            Some(Value::Bool(false)) | Some(Value::Null) => None,
+            Some(ft) => Some(self.field_type(&ft, &path)?),
+        };
+        if let Some(ft) = resolved {
+            features.insert(name.into(), ft);
+        }
+        Ok(())
+    }
+
+    fn field_type(&self, ft: &Value, path: &str) -> Result<Value, Error> {
+        let mut ft = self.expand_aliases(ft, path, 0)?;
+        let class = ft
+            .get("class")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("{}: missing field type 'class'", path))?;
+        let class = match class {
+            "unsigned-integer" | "unsigned-int" | "uint" | "int" => "unsigned-integer",
+            "signed-integer" | "signed-int" | "sint" => "signed-integer",
+            "unsigned-enumeration" | "unsigned-enum" | "uenum" | "enum" => "unsigned-enumeration",
+            "signed-enumeration" | "signed-enum" | "senum" => "signed-enumeration",
+            "real" | "float" | "floating-point" => "real",
+            "string" | "str" => "string",
+            "structure" | "struct" => "structure",
+            "static-array" => "static-array",
+            "dynamic-array" => "dynamic-array",
+            other => bail!("{}.class: unknown field type class '{}'", path, other),
+        };
+        ft.insert("class".into(), class.into());
+
+        match class {
+            "unsigned-integer"
+            | "signed-integer"
+            | "unsigned-enumeration"
+            | "signed-enumeration"
+            | "real" => {
+                let size = ft
+                    .get("size")
+                    .and_then(Value::as_u64)
+                    .ok_or_else(|| anyhow!("{}: missing field type 'size'", path))?;
+                let alignment = if size % 8 == 0 { 8_u64 } else { 1 };
+                insert_default(&mut ft, "alignment", alignment.into());
+                if let Some(Value::String(base)) = ft.get("preferred-display-base") {
+                    let base = match base.as_str() {
+                        "bin" | "binary" => "binary",
+                        "oct" | "octal" => "octal",
+                        "dec" | "decimal" => "decimal",
+                        "hex" | "hexadecimal" => "hexadecimal",
+                        other => bail!(
+                            "{}.preferred-display-base: unknown display base '{}'",
+                            path,
+                            other
+                        ),
+                    };
+                    ft.insert("preferred-display-base".into(), base.into());
+                }
+                if class.ends_with("enumeration") && !ft.contains_key("mappings") {
+                    bail!("{}: missing enumeration 'mappings'", path);
+                }
+            }
+            "structure" => {
+                if let Some(members) = ft.get("members") {
+                    let members = self.members(members, &format!("{}.members", path))?;
+                    ft.insert("members".into(), Value::Sequence(members));
+                } else {
+                    ft.insert("members".into(), Value::Sequence(Vec::new()));
+                }
+            }
+            "static-array" | "dynamic-array" => {
+                if class == "static-array" && !ft.contains_key("length") {
+                    bail!("{}: missing static array 'length'", path);
+                }
+                let element = ft
+                    .get("element-field-type")
+                    .ok_or_else(|| anyhow!("{}: missing 'element-field-type'", path))?;
+                let element = self.field_type(element, &format!("{}.element-field-type", path))?;
+                ft.insert("element-field-type".into(), element);
+            }
+            _ => (),
+        }
+
+        Ok(Value::Mapping(ft))
+    }
+
+    /// Expand a field type alias name, or a field type inheriting from one
+    fn expand_aliases(&self, ft: &Value, path: &str, depth: usize) -> Result<Mapping, Error> {
+        if depth > 32 {
+            bail!("{}: field type alias cycle detected", path);
+        }
+        match untagged(ft) {
+            Value::String(alias) => {
+                let aliased = self
+                    .field_type_aliases
+                    .get(alias.as_str())
+                    .ok_or_else(|| anyhow!("{}: unknown field type alias '{}'", path, alias))?;
+                self.expand_aliases(aliased, path, depth + 1)
+            }
+            Value::Mapping(m) => {
+                let mut m = m.clone();
+                let base = m.remove("$inherit").or_else(|| m.remove("inherit"));
+                match base {
+                    Some(base) => {
+                        let mut base =
+                            Value::Mapping(self.expand_aliases(&base, path, depth + 1)?);
+                        update_node(&mut base, Value::Mapping(m));
+                        into_mapping(base, path)
+                    }
+                    None => Ok(m),
+                }
+            }
+            _ => bail!("{}: expected a field type or field type alias name", path),
+        }
+    }
+
+    /// Structure members are `- name: field-type` or `- name: {field-type: ...}`
+    fn members(&self, members: &Value, path: &str) -> Result<Vec<Value>, Error> {
+        let members = members
+            .as_sequence()
+            .ok_or_else(|| anyhow!("{}: expected a sequence of members", path))?;
+        let mut out = Vec::with_capacity(members.len());
+        for (idx, member) in members.iter().enumerate() {
+            let member_path = format!("{}[{}]", path, idx);
+            let member = member
+                .as_mapping()
+                .filter(|m| m.len() == 1)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{}: expected a single 'name: field-type' entry",
+                        member_path
+                    )
+                })?;
+            let (name, val) = member.iter().next().expect("member has one entry");
+            let member_path = format!("{}.{}", member_path, key_str(name));
+
+            let mut member_cfg = match val.as_mapping() {
+                Some(m) if m.contains_key("field-type") => m.clone(),
+                _ => {
+                    let mut m = Mapping::new();
+                    m.insert("field-type".into(), val.clone());
+                    m
+                }
+            };
+            let ft = member_cfg.get("field-type").cloned().unwrap_or(Value::Null);
+            let ft = self.field_type(&ft, &format!("{}.field-type", member_path))?;
+            member_cfg.insert("field-type".into(), ft);
+
+            let mut entry = Mapping::new();
+            entry.insert(name.clone(), Value::Mapping(member_cfg));
+            out.push(Value::Mapping(entry));
+        }
+        Ok(out)
+    }
+}
+
+type ResolveFuture<'a> = Pin<Box<dyn Future<Output = Result<Value, Error>> + Send + 'a>>;
+
+/// Recursively replace `$include` properties with the content of the included
+/// files, the including node's properties take precedence
+fn process_includes<'a>(
+    node: Value,
+    base_dir: &'a Path,
+    path: &'a str,
+    include_stack: &'a mut Vec<PathBuf>,
+) -> ResolveFuture<'a> {
+    Box::pin(async move {
+        match node {
+            Value::Mapping(mut m) => {
+                let includes = match m.remove("$include") {
+                    None => Vec::new(),
+                    Some(Value::String(s)) => vec![s],
+                    Some(Value::Sequence(seq)) => seq
+                        .into_iter()
+                        .map(|v| match v {
+                            Value::String(s) => Ok(s),
+                            _ => Err(anyhow!("{}.$include: expected a file name", path)),
+                        })
+                        .collect::<Result<_, _>>()?,
+                    Some(_) => bail!("{}.$include: expected a file name or a sequence", path),
+                };
+
+                let mut out = Value::Mapping(Mapping::new());
+                for include in includes.iter() {
+                    let included = read_include(include, base_dir, path, include_stack).await?;
+                    update_node(&mut out, included);
+                }
+
+                let mut processed = Mapping::new();
+                for (k, v) in m.into_iter() {
+                    let child_path = child_path(path, &k);
+                    let v = process_includes(v, base_dir, &child_path, include_stack).await?;
+                    processed.insert(k, v);
+                }
+                update_node(&mut out, Value::Mapping(processed));
+                Ok(out)
+            }
+            Value::Sequence(seq) => {
+                let mut out = Vec::with_capacity(seq.len());
+                for (idx, v) in seq.into_iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, idx);
+                    out.push(process_includes(v, base_dir, &item_path, include_stack).await?);
+                }
+                Ok(Value::Sequence(out))
+            }
+            Value::Tagged(t) => process_includes(t.value, base_dir, path, include_stack).await,
+            other => Ok(other),
+        }
+    })
+}
+
+async fn read_include(
+    include: &str,
+    base_dir: &Path,
+    path: &str,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<Value, Error> {
+    let include_path = base_dir.join(include);
+    let (content, include_path) = match tokio::fs::read_to_string(&include_path).await {
+        Ok(content) => (content, include_path),
+        Err(e) => match STD_INCLUDES.iter().find(|(name, _)| *name == include) {
+            Some((_, content)) => (content.to_string(), PathBuf::from(include)),
+            None => bail!(
+                "{}.$include: failed to read included file '{}'. {}",
+                path,
+                include_path.display(),
+                e
+            ),
+        },
+    };
+
+    if include_stack.contains(&include_path) {
+        bail!(
+            "{}.$include: recursive inclusion of '{}'",
+            path,
+            include_path.display()
+        );
+    }
+    debug!(file = %include_path.display(), "Including barectf configuration");
+
+    let doc: Value = serde_yaml::from_str(&content).map_err(|e| {
+        anyhow!(
+            "{}.$include: failed to parse included file '{}'. {}",
+            path,
+            include_path.display(),
+            e
+        )
+    })?;
+
+    let include_dir = include_path
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| base_dir.to_path_buf());
+    include_stack.push(include_path);
+    let res = process_includes(doc, &include_dir, path, include_stack).await;
+    include_stack.pop();
+    res
+}
+
+/// Merge `overlay` into `base` the way barectf does: mappings are merged
+/// recursively, sequences are appended and anything else is replaced
+fn update_node(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (k, v) in overlay.into_iter() {
+                match base.get_mut(&k) {
+                    Some(existing) => update_node(existing, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay)) => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn uint_ft(size: u64, alignment: u64) -> Value {
+    let mut ft = Mapping::new();
+    ft.insert("class".into(), "unsigned-integer".into());
+    ft.insert("size".into(), size.into());
+    ft.insert("alignment".into(), alignment.into());
+    Value::Mapping(ft)
+}
+
+fn insert_default(m: &mut Mapping, key: &str, val: Value) {
+    if !m.contains_key(key) {
+        m.insert(key.into(), val);
+    }
+}
+
+fn untagged(v: &Value) -> &Value {
+    match v {
+        Value::Tagged(t) => untagged(&t.value),
+        other => other,
+    }
+}
+
+fn into_mapping(v: Value, path: &str) -> Result<Mapping, Error> {
+    match v {
+        Value::Mapping(m) => Ok(m),
+        Value::Tagged(t) => into_mapping(t.value, path),
+        _ => Err(anyhow!("{}: expected a mapping", display_path(path))),
+    }
+}
+
+fn as_mapping_mut<'a>(v: &'a mut Value, path: &str) -> Result<&'a mut Mapping, Error> {
+    v.as_mapping_mut()
+        .ok_or_else(|| anyhow!("{}: expected a mapping", display_path(path)))
+}
+
+fn take_mapping(parent: &mut Mapping, key: &str, path: &str) -> Result<Mapping, Error> {
+    match parent.remove(key) {
+        None | Some(Value::Null) => Ok(Mapping::new()),
+        Some(v) => into_mapping(v, &format!("{}.{}", path, key)),
+    }
+}
+
+fn key_str(k: &Value) -> String {
+    match k {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim().to_owned())
+            .unwrap_or_default(),
+    }
+}
+
+fn child_path(path: &str, k: &Value) -> String {
+    if path.is_empty() {
+        key_str(k)
+    } else {
+        format!("{}.{}", path, key_str(k))
+    }
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() {
+        "<root>"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    const CONFIG: &str = r#"
+--- !<tag:barectf.org,2020/3/config>
+trace:
+  type:
+    $include: [stdint.yaml, stdmisc.yaml]
+    native-byte-order: le
+    uuid: auto
+    $field-type-aliases:
+      counter: {class: int, size: 16}
+      state:
+        class: enum
+        size: 8
+        mappings: {IDLE: [0], BUSY: [1]}
+    $log-level-aliases:
+      WARN: 4
+    clock-types:
+      default: {}
+    data-stream-types:
+      default:
+        $is-default: true
+        $default-clock-type-name: default
+        event-record-types:
+          tick:
+            log-level: WARN
+            payload-field-type:
+              class: struct
+              members:
+                - count: counter
+                - state: state
+                - name: str
+                - value:
+                    field-type:
+                      $inherit: uint32
+                      preferred-display-base: hex
+"#;
+
+    async fn resolve_str(config: &str, config_path: &Path) -> Result<Value, Error> {
+        resolve(serde_yaml::from_str(config).unwrap(), config_path).await
+    }
+
+    fn trace_type(cfg: &Value) -> &Value {
+        &cfg["trace"]["type"]
+    }
+
+    fn payload_member<'a>(cfg: &'a Value, idx: usize, name: &str) -> &'a Value {
+        &trace_type(cfg)["data-stream-types"]["default"]["event-record-types"]["tick"]
+            ["payload-field-type"]["members"][idx][name]["field-type"]
+    }
+
+    #[test]
+    fn effective_config_does_not_need_resolving() {
+        let doc: Value = serde_yaml::from_slice(fixtures::CONFIG_YAML).unwrap();
+        assert!(!needs_resolving(&doc));
+        let doc: Value = serde_yaml::from_str(CONFIG).unwrap();
+        assert!(needs_resolving(&doc));
+    }
+
+    #[tokio::test]
+    async fn resolves_aliases_and_defaults() {
+        let cfg = resolve_str(CONFIG, Path::new("config.yaml")).await.unwrap();
+        let tt = trace_type(&cfg);
+
+        assert_eq!(tt["native-byte-order"], "little-endian");
+        assert_eq!(tt["$features"]["magic-field-type"], uint_ft(32, 32));
+        assert_eq!(
+            tt["$features"]["data-stream-type-id-field-type"],
+            uint_ft(64, 8)
+        );
+        assert_eq!(tt["clock-types"]["default"]["frequency"], 1_000_000_000);
+        assert_eq!(tt["clock-types"]["default"]["$c-type"], "uint32_t");
+        assert!(tt.get("$field-type-aliases").is_none());
+        assert!(tt.get("$include").is_none());
+
+        let dst = &tt["data-stream-types"]["default"];
+        let packet = &dst["$features"]["packet"];
+        assert_eq!(packet["beginning-timestamp-field-type"], uint_ft(64, 8));
+        assert!(packet.get("sequence-number-field-type").is_none());
+        assert_eq!(dst["event-record-types"]["tick"]["log-level"], 4);
+
+        let count = payload_member(&cfg, 0, "count");
+        assert_eq!(count["class"], "unsigned-integer");
+        assert_eq!(count["size"], 16);
+        assert_eq!(count["alignment"], 8);
+        let state = payload_member(&cfg, 1, "state");
+        assert_eq!(state["class"], "unsigned-enumeration");
+        assert_eq!(state["mappings"]["BUSY"][0], 1);
+        assert_eq!(payload_member(&cfg, 2, "name")["class"], "string");
+        let value = payload_member(&cfg, 3, "value");
+        assert_eq!(value["class"], "unsigned-integer");
+        assert_eq!(value["size"], 32);
+        assert_eq!(value["preferred-display-base"], "hexadecimal");
+    }
+
+    #[tokio::test]
+    async fn auto_uuid_keeps_the_uuid_field() {
+        let cfg = resolve_str(CONFIG, Path::new("config.yaml")).await.unwrap();
+        let tt = trace_type(&cfg);
+        assert!(tt.get("uuid").is_none());
+        assert_eq!(tt["$features"]["uuid-field-type"], true);
+
+        let without_uuid = CONFIG.replace("    uuid: auto\n", "");
+        let cfg = resolve_str(&without_uuid, Path::new("config.yaml"))
+            .await
+            .unwrap();
+        assert_eq!(trace_type(&cfg)["$features"]["uuid-field-type"], false);
+    }
+
+    #[tokio::test]
+    async fn resolved_config_parses() {
+        let cfg = crate::parse_barectf_config(CONFIG.as_bytes(), Path::new("config.yaml"))
+            .await
+            .unwrap();
+        assert_eq!(cfg.trace.typ.data_stream_types.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn includes_are_relative_to_the_including_file() {
+        let dir = std::env::temp_dir().join(format!("modality-barectf-{}", std::process::id()));
+        tokio::fs::create_dir_all(dir.join("types")).await.unwrap();
+        tokio::fs::write(
+            dir.join("types/aliases.yaml"),
+            "$include: [more.yaml]\n$field-type-aliases:\n  counter: {class: uint, size: 64}\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(
+            dir.join("types/more.yaml"),
+            "$log-level-aliases:\n  WARN: 5\n",
+        )
+        .await
+        .unwrap();
+        let config = CONFIG.replace(
+            "$include: [stdint.yaml, stdmisc.yaml]",
+            "$include: [stdint.yaml, stdmisc.yaml, types/aliases.yaml]",
+        );
+
+        let res = resolve_str(&config, &dir.join("config.yaml")).await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        let cfg = res.unwrap();
+
+        // The including node's properties take precedence
+        assert_eq!(payload_member(&cfg, 0, "count")["size"], 16);
+        assert_eq!(
+            trace_type(&cfg)["data-stream-types"]["default"]["event-record-types"]["tick"]
+                ["log-level"],
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn recursive_includes_are_errors() {
+        let dir =
+            std::env::temp_dir().join(format!("modality-barectf-recursive-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join("a.yaml"), "$include: [b.yaml]\n")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("b.yaml"), "$include: [a.yaml]\n")
+            .await
+            .unwrap();
+        let config = CONFIG.replace(
+            "$include: [stdint.yaml, stdmisc.yaml]",
+            "$include: [stdint.yaml, stdmisc.yaml, a.yaml]",
+        );
+
+        let res = resolve_str(&config, &dir.join("config.yaml")).await;
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("recursive inclusion"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn unknown_alias() {
+        let config = CONFIG.replace("- count: counter", "- count: nope");
+        let err = resolve_str(&config, Path::new("config.yaml"))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field type alias 'nope'"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn reports_both_errors_for_broken_effective_configs() {
+        let config = "--- !<tag:barectf.org,2020/3/config>\ntrace:\n  environment: {}\n";
+        let err = crate::parse_barectf_config(config.as_bytes(), Path::new("config.yaml"))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("effective-configuration"), "{err}");
+        assert!(err.contains("missing 'type' node"), "{err}");
+    }
+}
@@ -0,0 +1,29 @@
+//! The integration test trace, for unit tests.
+//!
+//! `ctf_stream` has two packets: packet 0 (sequence number 0, beginning
+//! timestamp 0) with the `init`, `foobar`, `floats`, `enums` and `arrays`
+//! events at cycles 0 to 4, and packet 1 (sequence number 1, beginning
+//! timestamp 5) with the `shutdown` event at cycle 5.
+//! The clock runs at 1 GHz, so cycles are nanoseconds.
+
+use barectf_parser::{Config as BarectfConfig, Packet, Parser};
+use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+pub const CONFIG_YAML: &[u8] = include_bytes!("../integration-test/effective_config.yaml");
+pub const STREAM: &[u8] = include_bytes!("../integration-test/ctf_stream");
+pub const PACKET_SIZE: usize = 256;
+
+pub fn config() -> BarectfConfig {
+    serde_yaml::from_slice(CONFIG_YAML).unwrap()
+}
+
+pub fn packets() -> Vec<Packet> {
+    let mut decoder = Parser::new(&config()).unwrap().into_packet_decoder();
+    let mut buf = BytesMut::from(STREAM);
+    let mut pkts = Vec::new();
+    while let Some(pkt) = decoder.decode(&mut buf).unwrap() {
+        pkts.push(pkt);
+    }
+    assert_eq!(pkts.len(), 2);
+    pkts
+}
@@ -1,28 +1,130 @@
+use anyhow::anyhow;
 use auxon_sdk::{
     plugin_utils::serde::from_str,
     reflector_config::{envsub, EnvSubError},
 };
+use barectf_parser::Config as BarectfConfig;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
 
-pub use send::Sender;
+pub use output::{Output, OutputFormat};
+pub use send::{ConfigId, Sender, SourceId};
+pub use sink::Sink;
 
+pub mod chrome_trace;
 mod convert;
+pub mod effective_config;
+#[cfg(test)]
+mod fixtures;
+pub mod merge;
+mod metrics;
+pub mod otlp;
+pub mod output;
+pub mod pairs;
+pub mod record;
+pub mod reload;
+pub mod reorder;
+pub mod resync;
+pub mod routing;
 mod send;
+pub mod sink;
+pub mod tsdl;
 
 pub const PLUGIN_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct CommonConfig {
-    /// The barectf effective-configuration yaml file
+    /// The barectf effective-configuration yaml file, or a CTF TSDL metadata file
     #[serde(deserialize_with = "from_str")]
     pub config: Option<PathBuf>,
 
+    /// Additional barectf configuration files, for collecting traces
+    /// from several firmware variants.
+    /// Each stream uses the configuration matching the trace UUID of its first packet.
+    #[serde(deserialize_with = "from_list", alias = "additional_configs")]
+    pub additional_configs: Vec<PathBuf>,
+
     /// An event name to consider as the trace-start signal.
     /// Used to detect system restarts.
     #[serde(alias = "start_event")]
     pub start_event: Option<String>,
+
+    /// Order packets across streams by their beginning timestamp
+    /// before sending them.
+    #[serde(deserialize_with = "from_str", alias = "merge_streams")]
+    pub merge_streams: Option<bool>,
+
+    /// The maximum number of packets held back for reordering
+    /// when merging streams.
+    #[serde(deserialize_with = "from_str", alias = "merge_window")]
+    pub merge_window: Option<usize>,
+
+    /// What to do when a packet's trace UUID or magic number doesn't match
+    /// the barectf configuration: `error`, `warn` (the default) or `ignore`.
+    #[serde(alias = "trace_validation")]
+    pub trace_validation: Option<TraceValidation>,
+
+    /// Write the converted timelines and events to this file instead of
+    /// sending them to Modality
+    #[serde(deserialize_with = "from_str")]
+    pub output: Option<PathBuf>,
+
+    /// The output file format: `jsonl`, `csv` or `chrome-trace`.
+    /// The default is based on the output file extension, falling back to `jsonl`.
+    #[serde(deserialize_with = "from_str", alias = "output_format")]
+    pub output_format: Option<OutputFormat>,
+
+    /// Begin/end event pairs, as `begin:end` or `begin:end:key` where `key` is
+    /// an event attribute both events must have the same value for (i.e. a task id).
+    /// Paired events are exported as spans by the `chrome-trace` and OTLP outputs.
+    #[serde(deserialize_with = "from_list", alias = "event_pairs")]
+    pub event_pairs: Vec<String>,
+
+    /// Pair the `event-pairs` begin and end events when sending them, adding
+    /// a `duration` attribute and an interaction to the begin event on end events
+    #[serde(deserialize_with = "from_str", alias = "synthesize_spans")]
+    pub synthesize_spans: Option<bool>,
+
+    /// A context switch event name. Events following it on the same stream
+    /// are sent on a timeline for the task switched to.
+    #[serde(alias = "context_switch_event")]
+    pub context_switch_event: Option<String>,
+
+    /// The context switch event attribute holding the name of the task switched to
+    #[serde(alias = "context_switch_task_field")]
+    pub context_switch_task_field: Option<String>,
+
+    /// Send metrics summary events (event counts, rates, discarded events)
+    /// on each timeline when the plugin finishes
+    #[serde(deserialize_with = "from_str")]
+    pub metrics: Option<bool>,
+
+    /// Also send metrics summary events every this many packets of a stream
+    #[serde(deserialize_with = "from_str", alias = "metrics_packet_interval")]
+    pub metrics_packet_interval: Option<u64>,
+
+    /// Also send metrics summary events every this much trace time of a stream (i.e. `10s`)
+    #[serde(alias = "metrics_interval")]
+    pub metrics_interval: Option<String>,
+
+    /// Export events as logs, and event pairs as spans, to this OTLP/HTTP
+    /// endpoint (i.e. `http://localhost:4318`) instead of sending them to Modality
+    #[serde(alias = "otlp_endpoint")]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceValidation {
+    /// Stop with an error
+    Error,
+    /// Log a warning and annotate the timeline
+    #[default]
+    Warn,
+    /// Don't check packets against the configuration
+    Ignore,
 }
 
 pub trait HasCommonConfig {
@@ -39,4 +141,128 @@ impl CommonConfig {
             Ok(self.config.clone())
         }
     }
+
+    pub fn envsub_additional_config_paths(&self) -> Result<Vec<PathBuf>, EnvSubError> {
+        self.additional_configs
+            .iter()
+            .map(|p| match p.as_os_str().to_str() {
+                Some(s) => envsub(s).map(PathBuf::from),
+                None => Ok(p.clone()),
+            })
+            .collect()
+    }
+}
+
+/// Deserialize a list from either a sequence or a
+/// comma-separated string (i.e. from an environment variable)
+fn from_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de> + From<String>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List<T> {
+        List(Vec<T>),
+        Str(String),
+    }
+
+    Ok(match List::<T>::deserialize(deserializer)? {
+        List::List(items) => items,
+        List::Str(s) => s
+            .split(',')
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+            .map(|p| T::from(p.to_owned()))
+            .collect(),
+    })
+}
+
+/// Load the primary barectf configuration followed by any additional configurations,
+/// in the order expected by [`routing::RoutingDecoder`] and [`Sender::add_config`].
+pub async fn load_barectf_configs(
+    path: &Path,
+    additional_paths: &[PathBuf],
+) -> Result<Vec<BarectfConfig>, anyhow::Error> {
+    let mut cfgs = vec![load_barectf_config(path).await?];
+    for p in additional_paths.iter() {
+        cfgs.push(load_barectf_config(p).await?);
+    }
+    Ok(cfgs)
+}
+
+/// Read the barectf configuration from either a barectf configuration yaml file
+/// or the CTF TSDL metadata file barectf generates.
+///
+/// Hand-written (non-effective) configurations are resolved into the
+/// effective-configuration.
+pub async fn load_barectf_config(path: &Path) -> Result<BarectfConfig, anyhow::Error> {
+    let content = tokio::fs::read(path).await.map_err(|e| {
+        anyhow!(
+            "Failed to open barectf configuration file '{}'. {}",
+            path.display(),
+            e
+        )
+    })?;
+    parse_barectf_config(&content, path).await
+}
+
+/// Parse the content of a barectf configuration yaml file or CTF TSDL metadata file.
+///
+/// `path` is where the content came from, includes are resolved relative to it.
+pub async fn parse_barectf_config(
+    content: &[u8],
+    path: &Path,
+) -> Result<BarectfConfig, anyhow::Error> {
+    if tsdl::is_tsdl_metadata(content) {
+        info!(file = %path.display(), "Reading CTF metadata");
+        tsdl::config_from_metadata(content).map_err(|e| {
+            anyhow!(
+                "Failed to parse CTF metadata file '{}'. {}",
+                path.display(),
+                e
+            )
+        })
+    } else {
+        info!(file = %path.display(), "Reading effective-configuration yaml");
+        let doc: serde_yaml::Value = serde_yaml::from_slice(content).map_err(|e| {
+            anyhow!(
+                "Failed to parse barectf configuration yaml file '{}'. {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        let parse_err = if effective_config::needs_resolving(&doc) {
+            None
+        } else {
+            match serde_yaml::from_slice(content) {
+                Ok(cfg) => return Ok(cfg),
+                Err(e) => Some(e),
+            }
+        };
+
+        // Not an effective-configuration, resolve it the way barectf would
+        debug!(file = %path.display(), "Resolving barectf configuration yaml");
+        let resolved = effective_config::resolve(doc, path).await.map_err(|e| match &parse_err {
+            Some(parse_err) => anyhow!(
+                "Failed to parse barectf effective-configuration yaml file '{}'. {}. Failed to resolve it as a barectf configuration. {}",
+                path.display(),
+                parse_err,
+                e
+            ),
+            None => anyhow!(
+                "Failed to resolve barectf configuration yaml file '{}'. {}",
+                path.display(),
+                e
+            ),
+        })?;
+        serde_yaml::from_value(resolved).map_err(|e| {
+            anyhow!(
+                "Failed to parse barectf configuration yaml file '{}' after resolving includes and defaults. {}",
+                path.display(),
+                e
+            )
+        })
+    }
 }
@@ -0,0 +1,173 @@
+use crate::convert::ClockExt;
+use barectf_parser::Packet;
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+/// The default number of packets held back for reordering
+pub const DEFAULT_MERGE_WINDOW: usize = 64;
+
+/// Orders packets across streams by their beginning timestamp.
+///
+/// Up to `window` packets are buffered; once the window is full the
+/// earliest packet is released. Packets are only guaranteed to be in
+/// global order if no stream is delayed by more than the window.
+pub struct PacketMerger<T = ()> {
+    window: usize,
+    insertion_count: u64,
+    last_released: u64,
+    pending: BinaryHeap<Reverse<PendingPacket<T>>>,
+}
+
+struct PendingPacket<T> {
+    sort_key: u64,
+    insertion: u64,
+    tag: T,
+    pkt: Packet,
+}
+
+impl<T> PartialEq for PendingPacket<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for PendingPacket<T> {}
+
+impl<T> PartialOrd for PendingPacket<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for PendingPacket<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Ties are broken by arrival order so a single stream is never reordered
+        self.sort_key
+            .cmp(&other.sort_key)
+            .then(self.insertion.cmp(&other.insertion))
+    }
+}
+
+impl<T> PacketMerger<T> {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(1),
+            insertion_count: 0,
+            last_released: 0,
+            pending: BinaryHeap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// The tags of the buffered packets
+    pub fn tags(&self) -> impl Iterator<Item = &T> {
+        self.pending.iter().map(|Reverse(p)| &p.tag)
+    }
+
+    /// Buffer a packet, returning the earliest packet if the window is full
+    pub fn push(&mut self, tag: T, pkt: Packet) -> Option<(T, Packet)> {
+        // Packets without a comparable timestamp go out as soon as possible
+        let sort_key = packet_sort_key(&pkt).unwrap_or(self.last_released);
+        self.pending.push(Reverse(PendingPacket {
+            sort_key,
+            insertion: self.insertion_count,
+            tag,
+            pkt,
+        }));
+        self.insertion_count += 1;
+
+        if self.pending.len() > self.window {
+            self.pop()
+        } else {
+            None
+        }
+    }
+
+    /// Release the earliest buffered packet, used to drain the merger
+    pub fn pop(&mut self) -> Option<(T, Packet)> {
+        self.pending.pop().map(|Reverse(p)| {
+            self.last_released = p.sort_key;
+            (p.tag, p.pkt)
+        })
+    }
+}
+
+/// The key packets are ordered by when merging streams.
+///
+/// This is the packet's beginning timestamp (or first event timestamp if the
+/// packet context doesn't have one), in nanoseconds.
+/// Raw clock cycles aren't comparable across streams, so packets whose stream
+/// clock doesn't have a valid frequency don't have a key.
+pub fn packet_sort_key(pkt: &Packet) -> Option<u64> {
+    let cycles = pkt
+        .context
+        .beginning_timestamp
+        .or_else(|| pkt.events.first().map(|ev| ev.timestamp))?;
+    pkt.header
+        .clock_type
+        .as_deref()?
+        .timestamp_ns(cycles)
+        .map(|ns| ns.get_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn sort_key_is_in_nanoseconds() {
+        let pkts = fixtures::packets();
+        assert_eq!(packet_sort_key(&pkts[0]), Some(0));
+        assert_eq!(packet_sort_key(&pkts[1]), Some(5));
+    }
+
+    #[test]
+    fn no_sort_key_without_a_clock() {
+        let mut pkt = fixtures::packets().remove(1);
+        pkt.header.clock_type = None;
+        assert_eq!(packet_sort_key(&pkt), None);
+    }
+
+    #[test]
+    fn releases_the_earliest_packet_when_full() {
+        let pkts = fixtures::packets();
+        let mut merger = PacketMerger::new(1);
+        assert!(merger.push("late", pkts[1].clone()).is_none());
+        let (tag, _) = merger.push("early", pkts[0].clone()).unwrap();
+        assert_eq!(tag, "early");
+        let (tag, _) = merger.pop().unwrap();
+        assert_eq!(tag, "late");
+        assert!(merger.pop().is_none());
+        assert!(merger.is_empty());
+    }
+
+    #[test]
+    fn ties_keep_arrival_order() {
+        let pkt = fixtures::packets().remove(0);
+        let mut merger = PacketMerger::new(8);
+        for tag in 0..4 {
+            assert!(merger.push(tag, pkt.clone()).is_none());
+        }
+        let tags: Vec<_> = std::iter::from_fn(|| merger.pop().map(|(t, _)| t)).collect();
+        assert_eq!(tags, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn packets_without_a_key_are_not_held_back() {
+        let pkts = fixtures::packets();
+        let mut no_clock = pkts[1].clone();
+        no_clock.header.clock_type = None;
+
+        let mut merger = PacketMerger::new(1);
+        assert!(merger.push("timestamped", pkts[1].clone()).is_none());
+        // Sorts as the last released key (0), ahead of the buffered packet
+        let (tag, _) = merger.push("no-clock", no_clock).unwrap();
+        assert_eq!(tag, "no-clock");
+    }
+}
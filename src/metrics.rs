@@ -0,0 +1,290 @@
+use auxon_sdk::api::{AttrVal, Nanoseconds, TimelineId};
+use fxhash::FxHashMap;
+use internment::Intern;
+use std::collections::HashMap;
+
+/// The name of the metrics summary events
+pub const METRICS_EVENT_NAME: &str = "modality_barectf.metrics";
+
+/// When to send metrics summary events, besides when the sender is closed
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MetricsOptions {
+    /// Every this many packets of a stream
+    pub packet_interval: Option<u64>,
+    /// Every this many nanoseconds of trace time of a stream
+    pub interval_ns: Option<u64>,
+}
+
+/// Per-timeline event metrics
+pub struct Metrics {
+    options: MetricsOptions,
+    timelines: HashMap<TimelineId, TimelineMetrics>,
+}
+
+#[derive(Default)]
+struct TimelineMetrics {
+    event_counts: FxHashMap<Intern<String>, u64>,
+    events: u64,
+    events_discarded: u64,
+    packets: u64,
+    first_timestamp: Option<u64>,
+    last_timestamp: Option<u64>,
+    packets_since_summary: u64,
+    /// The last discarded events counter snapshot of the stream's packets
+    discarded_snapshot: Option<u64>,
+    /// The stream's packet time when the current interval started
+    interval_start: Option<u64>,
+    /// The time of the stream's last packet, its last event is on a task
+    /// timeline after a context switch
+    packet_timestamp: Option<u64>,
+}
+
+impl Metrics {
+    pub fn new(options: MetricsOptions) -> Self {
+        Self {
+            options,
+            timelines: Default::default(),
+        }
+    }
+
+    /// Record a packet of a stream, with its discarded events counter snapshot
+    /// (a running total) and the timestamp of its last event
+    pub fn record_packet(
+        &mut self,
+        timeline: TimelineId,
+        discarded_snapshot: Option<u64>,
+        timestamp_ns: Option<u64>,
+    ) {
+        let m = self.timelines.entry(timeline).or_default();
+        m.packets += 1;
+        m.packets_since_summary += 1;
+        if let Some(snapshot) = discarded_snapshot {
+            // The counter starts over when the trace restarts
+            m.events_discarded += match m.discarded_snapshot {
+                Some(last) if snapshot >= last => snapshot - last,
+                _ => snapshot,
+            };
+            m.discarded_snapshot = Some(snapshot);
+        }
+        if let Some(ns) = timestamp_ns {
+            match m.interval_start {
+                // Time went backwards, the trace restarted
+                Some(start) if ns >= start => (),
+                _ => m.interval_start = Some(ns),
+            }
+            m.packet_timestamp = Some(ns);
+        }
+    }
+
+    pub fn record_event(
+        &mut self,
+        timeline: TimelineId,
+        name: Intern<String>,
+        timestamp_ns: Option<u64>,
+    ) {
+        let m = self.timelines.entry(timeline).or_default();
+        m.events += 1;
+        *m.event_counts.entry(name).or_default() += 1;
+        if let Some(ns) = timestamp_ns {
+            m.first_timestamp.get_or_insert(ns);
+            m.last_timestamp = Some(ns);
+        }
+    }
+
+    /// Returns true when the packets or packet time since the last summary
+    /// of a stream's timeline reached the configured interval
+    pub fn is_due(&self, timeline: TimelineId) -> bool {
+        let Some(m) = self.timelines.get(&timeline) else {
+            return false;
+        };
+        let packets_due = self
+            .options
+            .packet_interval
+            .map(|n| m.packets_since_summary >= n)
+            .unwrap_or(false);
+        let time_due = match (
+            self.options.interval_ns,
+            m.interval_start,
+            m.packet_timestamp,
+        ) {
+            (Some(interval), Some(since), Some(last)) => last.saturating_sub(since) >= interval,
+            _ => false,
+        };
+        packets_due || time_due
+    }
+
+    /// Start a new interval for a stream's timeline
+    pub fn reset_interval(&mut self, timeline: TimelineId) {
+        if let Some(m) = self.timelines.get_mut(&timeline) {
+            m.packets_since_summary = 0;
+            m.interval_start = m.packet_timestamp;
+        }
+    }
+
+    /// Forget a timeline's metrics, once its summary was sent
+    pub fn remove_timeline(&mut self, timeline: TimelineId) {
+        self.timelines.remove(&timeline);
+    }
+
+    /// The attributes of a timeline's summary event, if it has any metrics
+    pub fn summary_attrs(
+        &self,
+        timeline: TimelineId,
+        is_final: bool,
+    ) -> Option<Vec<(String, AttrVal)>> {
+        let m = self.timelines.get(&timeline)?;
+
+        let mut attrs: Vec<(String, AttrVal)> = vec![
+            (
+                "modality_barectf.metrics.events".to_owned(),
+                m.events.into(),
+            ),
+            (
+                "modality_barectf.metrics.events_discarded".to_owned(),
+                m.events_discarded.into(),
+            ),
+            (
+                "modality_barectf.metrics.packets".to_owned(),
+                m.packets.into(),
+            ),
+            ("modality_barectf.metrics.final".to_owned(), is_final.into()),
+        ];
+        if let (Some(first), Some(last)) = (m.first_timestamp, m.last_timestamp) {
+            let duration_ns = last.saturating_sub(first);
+            attrs.push((
+                "modality_barectf.metrics.trace_duration".to_owned(),
+                duration_ns.into(),
+            ));
+            if duration_ns != 0 {
+                let rate = m.events as f64 / (duration_ns as f64 / 1_000_000_000.0);
+                attrs.push((
+                    "modality_barectf.metrics.events_per_second".to_owned(),
+                    rate.into(),
+                ));
+            }
+            attrs.push(("timestamp".to_owned(), Nanoseconds::from(last).into()));
+        }
+
+        let mut counts: Vec<_> = m.event_counts.iter().collect();
+        counts.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        for (name, count) in counts.into_iter() {
+            attrs.push((
+                format!("modality_barectf.metrics.count.{}", name.as_str()),
+                (*count).into(),
+            ));
+        }
+
+        Some(attrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(attrs: &[(String, AttrVal)], key: &str) -> Option<AttrVal> {
+        attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn events_discarded_counts_the_snapshot_increases() {
+        let tl = TimelineId::allocate();
+        let mut metrics = Metrics::new(MetricsOptions::default());
+        for snapshot in [0, 2, 2, 5, 9] {
+            metrics.record_packet(tl, Some(snapshot), None);
+        }
+        let attrs = metrics.summary_attrs(tl, true).unwrap();
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.events_discarded"),
+            Some(AttrVal::from(9_u64))
+        );
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.packets"),
+            Some(AttrVal::from(5_u64))
+        );
+    }
+
+    #[test]
+    fn events_discarded_allows_for_the_counter_restarting() {
+        let tl = TimelineId::allocate();
+        let mut metrics = Metrics::new(MetricsOptions::default());
+        for snapshot in [3, 7, 1, 4] {
+            metrics.record_packet(tl, Some(snapshot), None);
+        }
+        let attrs = metrics.summary_attrs(tl, true).unwrap();
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.events_discarded"),
+            Some(AttrVal::from(11_u64))
+        );
+    }
+
+    #[test]
+    fn packet_interval() {
+        let tl = TimelineId::allocate();
+        let mut metrics = Metrics::new(MetricsOptions {
+            packet_interval: Some(2),
+            interval_ns: None,
+        });
+        assert!(!metrics.is_due(tl));
+        metrics.record_packet(tl, None, None);
+        assert!(!metrics.is_due(tl));
+        metrics.record_packet(tl, None, None);
+        assert!(metrics.is_due(tl));
+        metrics.reset_interval(tl);
+        assert!(!metrics.is_due(tl));
+    }
+
+    #[test]
+    fn time_interval_follows_the_packet_time() {
+        let tl = TimelineId::allocate();
+        let mut metrics = Metrics::new(MetricsOptions {
+            packet_interval: None,
+            interval_ns: Some(100),
+        });
+        metrics.record_packet(tl, None, Some(1_000));
+        metrics.record_packet(tl, None, Some(1_050));
+        assert!(!metrics.is_due(tl));
+        metrics.record_packet(tl, None, Some(1_100));
+        assert!(metrics.is_due(tl));
+        metrics.reset_interval(tl);
+        assert!(!metrics.is_due(tl));
+
+        // Restarted, the interval starts over from the new time
+        metrics.record_packet(tl, None, Some(10));
+        assert!(!metrics.is_due(tl));
+        metrics.record_packet(tl, None, Some(110));
+        assert!(metrics.is_due(tl));
+    }
+
+    #[test]
+    fn event_counts_and_duration() {
+        let tl = TimelineId::allocate();
+        let mut metrics = Metrics::new(MetricsOptions::default());
+        assert!(metrics.summary_attrs(tl, false).is_none());
+        let (a, b) = (Intern::new("a".to_owned()), Intern::new("b".to_owned()));
+        metrics.record_event(tl, a, Some(0));
+        metrics.record_event(tl, b, Some(500_000_000));
+        metrics.record_event(tl, a, Some(1_000_000_000));
+        let attrs = metrics.summary_attrs(tl, false).unwrap();
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.events"),
+            Some(AttrVal::from(3_u64))
+        );
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.count.a"),
+            Some(AttrVal::from(2_u64))
+        );
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.trace_duration"),
+            Some(AttrVal::from(1_000_000_000_u64))
+        );
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.events_per_second"),
+            Some(AttrVal::from(3.0))
+        );
+        assert_eq!(
+            attr(&attrs, "modality_barectf.metrics.final"),
+            Some(AttrVal::from(false))
+        );
+    }
+}
@@ -0,0 +1,422 @@
+use crate::{
+    output::event_timestamp_ns,
+    pairs::{EventPair, PairMatch, PairMatcher},
+    sink::Sink,
+    PLUGIN_VERSION,
+};
+use anyhow::anyhow;
+use auxon_sdk::api::{AttrVal, TimelineId, Uuid};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, info};
+use url::Url;
+
+/// The number of log records and spans buffered before they're exported
+const EXPORT_BATCH_SIZE: usize = 512;
+
+const SCOPE_NAME: &str = "modality-barectf";
+
+/// Exports events as OTLP log records, and the configured begin/end event
+/// pairs as spans, to an OTLP/HTTP endpoint using the JSON encoding.
+///
+/// Each timeline is an OTLP resource, with the timeline attributes as
+/// resource attributes and its spans in a trace of their own.
+pub struct OtlpOutput {
+    client: reqwest::Client,
+    logs_url: Url,
+    traces_url: Url,
+    timelines: HashMap<TimelineId, usize>,
+    resources: Vec<Resource>,
+    current_timeline: usize,
+    pairs: PairMatcher<usize, OpenSpan>,
+    next_span_id: u64,
+    buffered: usize,
+}
+
+struct Resource {
+    attributes: Vec<Value>,
+    trace_id: String,
+    log_records: Vec<Value>,
+    spans: Vec<Value>,
+    /// The last timestamp of the timeline, for events without one
+    last_timestamp: u64,
+}
+
+/// A begin event waiting for its end event
+struct OpenSpan {
+    name: String,
+    timestamp_ns: u64,
+    attributes: Vec<Value>,
+}
+
+impl OtlpOutput {
+    pub fn new(endpoint: &str, event_pairs: Vec<EventPair>) -> Result<Self, anyhow::Error> {
+        let mut endpoint = Url::parse(endpoint)
+            .map_err(|e| anyhow!("Invalid OTLP endpoint '{}'. {}", endpoint, e))?;
+        // Join the signal paths onto the endpoint's path
+        if !endpoint.path().ends_with('/') {
+            endpoint.set_path(&format!("{}/", endpoint.path()));
+        }
+        let logs_url = endpoint.join("v1/logs")?;
+        let traces_url = endpoint.join("v1/traces")?;
+        info!(%endpoint, "Exporting to OTLP endpoint");
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            logs_url,
+            traces_url,
+            timelines: Default::default(),
+            resources: Vec::new(),
+            current_timeline: 0,
+            pairs: PairMatcher::new(event_pairs),
+            next_span_id: 1,
+            buffered: 0,
+        })
+    }
+
+    fn resource(&mut self) -> &mut Resource {
+        &mut self.resources[self.current_timeline]
+    }
+
+    async fn export(&mut self) -> Result<(), anyhow::Error> {
+        let scope = json!({ "name": SCOPE_NAME, "version": PLUGIN_VERSION });
+
+        let mut resource_logs = Vec::new();
+        let mut resource_spans = Vec::new();
+        for r in self.resources.iter_mut() {
+            let resource = json!({ "attributes": r.attributes });
+            if !r.log_records.is_empty() {
+                resource_logs.push(json!({
+                    "resource": resource,
+                    "scopeLogs": [{
+                        "scope": scope,
+                        "logRecords": std::mem::take(&mut r.log_records),
+                    }],
+                }));
+            }
+            if !r.spans.is_empty() {
+                resource_spans.push(json!({
+                    "resource": resource,
+                    "scopeSpans": [{
+                        "scope": scope,
+                        "spans": std::mem::take(&mut r.spans),
+                    }],
+                }));
+            }
+        }
+        self.buffered = 0;
+
+        if !resource_logs.is_empty() {
+            let body = json!({ "resourceLogs": resource_logs });
+            post(&self.client, &self.logs_url, &body).await?;
+        }
+        if !resource_spans.is_empty() {
+            let body = json!({ "resourceSpans": resource_spans });
+            post(&self.client, &self.traces_url, &body).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &Url, body: &Value) -> Result<(), anyhow::Error> {
+    debug!(%url, "Exporting to OTLP endpoint");
+    let resp = client
+        .post(url.clone())
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| anyhow!("Failed to export to OTLP endpoint '{}'. {}", url, e))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let details = resp.text().await.unwrap_or_default();
+        return Err(anyhow!(
+            "Failed to export to OTLP endpoint '{}'. {} {}",
+            url,
+            status,
+            details
+        ));
+    }
+    Ok(())
+}
+
+fn any_value(val: &AttrVal) -> Value {
+    match val {
+        AttrVal::String(s) => json!({ "stringValue": s.to_string() }),
+        // 64-bit integers are strings in the OTLP JSON encoding
+        AttrVal::Integer(i) => json!({ "intValue": i.to_string() }),
+        AttrVal::Bool(b) => json!({ "boolValue": b }),
+        AttrVal::Float(f) => json!({ "doubleValue": f64::from(*f) }),
+        AttrVal::Timestamp(ns) => json!({ "intValue": ns.get_raw().to_string() }),
+        other => json!({ "stringValue": other.to_string() }),
+    }
+}
+
+fn key_values<'a>(attrs: impl IntoIterator<Item = (&'a str, &'a AttrVal)>) -> Vec<Value> {
+    attrs
+        .into_iter()
+        .map(|(k, v)| json!({ "key": k, "value": any_value(v) }))
+        .collect()
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+impl Sink for OtlpOutput {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), anyhow::Error> {
+        let next = self.resources.len();
+        self.current_timeline = *self.timelines.entry(id).or_insert(next);
+        if self.current_timeline == next {
+            self.resources.push(Resource {
+                attributes: Vec::new(),
+                trace_id: format!("{:032x}", Uuid::new_v4().as_u128()),
+                log_records: Vec::new(),
+                spans: Vec::new(),
+                last_timestamp: 0,
+            });
+        }
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let name = AttrVal::from(name.to_owned());
+        let attributes = key_values(
+            std::iter::once(("service.name", &name)).chain(attrs.iter().map(|(k, v)| (*k, v))),
+        );
+        let resource = self.resource();
+        // Attributes sent later (i.e. validation results) replace earlier ones
+        resource
+            .attributes
+            .retain(|a| !attributes.iter().any(|new| new.get("key") == a.get("key")));
+        resource.attributes.extend(attributes);
+        Ok(())
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        _ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let timeline = self.current_timeline;
+        let timestamp_ns = match event_timestamp_ns(&attrs) {
+            Some(ns) => {
+                self.resource().last_timestamp = ns;
+                ns
+            }
+            None => self.resource().last_timestamp,
+        };
+        let attributes = key_values(attrs.iter().map(|(k, v)| (*k, v)));
+
+        let pair = self.pairs.handle(&timeline, name, &attrs, || OpenSpan {
+            name: name.to_owned(),
+            timestamp_ns,
+            attributes: attributes.clone(),
+        });
+        if let PairMatch::End(begin) = pair {
+            let span_id = format!("{:016x}", self.next_span_id);
+            self.next_span_id += 1;
+            let end_attributes = key_values(attrs.iter().map(|(k, v)| (*k, v)))
+                .into_iter()
+                .map(|mut kv| {
+                    if let Some(Value::String(k)) = kv.get_mut("key") {
+                        *k = format!("end.{k}");
+                    }
+                    kv
+                });
+            let resource = self.resource();
+            resource.spans.push(json!({
+                "traceId": resource.trace_id,
+                "spanId": span_id,
+                "name": begin.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": begin.timestamp_ns.to_string(),
+                "endTimeUnixNano": timestamp_ns.to_string(),
+                "attributes": begin.attributes.into_iter().chain(end_attributes).collect::<Vec<_>>(),
+            }));
+            self.buffered += 1;
+        }
+
+        self.resource().log_records.push(json!({
+            "timeUnixNano": timestamp_ns.to_string(),
+            "observedTimeUnixNano": now_ns().to_string(),
+            "body": { "stringValue": name },
+            "attributes": attributes,
+        }));
+        self.buffered += 1;
+
+        if self.buffered >= EXPORT_BATCH_SIZE {
+            self.export().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.export().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auxon_sdk::api::Nanoseconds;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    /// A minimal OTLP/HTTP collector, forwarding the path and JSON body of each request
+    async fn serve(listener: TcpListener, requests: mpsc::UnboundedSender<(String, Value)>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut reader = BufReader::new(stream);
+                loop {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    let path = request_line
+                        .split_whitespace()
+                        .nth(1)
+                        .unwrap_or_default()
+                        .to_owned();
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).await.unwrap();
+                        let line = line.trim_end();
+                        if line.is_empty() {
+                            break;
+                        }
+                        if let Some((k, v)) = line.split_once(':') {
+                            if k.eq_ignore_ascii_case("content-length") {
+                                content_length = v.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).await.unwrap();
+                    requests
+                        .send((path, serde_json::from_slice(&body).unwrap()))
+                        .unwrap();
+                    reader
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                }
+            });
+        }
+    }
+
+    fn timestamp(ns: u64) -> (&'static str, AttrVal) {
+        ("timestamp", Nanoseconds::from(ns).into())
+    }
+
+    fn log_records(body: &Value) -> &Vec<Value> {
+        body["resourceLogs"][0]["scopeLogs"][0]["logRecords"]
+            .as_array()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn exports_batches_and_flushes_the_rest() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(serve(listener, requests_tx));
+
+        let pairs = vec!["start:end".parse().unwrap()];
+        let mut out = OtlpOutput::new(&endpoint, pairs).unwrap();
+        out.switch_timeline(TimelineId::allocate()).await.unwrap();
+        out.send_timeline_attrs("stream", vec![("clock.name", "default".into())])
+            .await
+            .unwrap();
+
+        // A full batch is exported as soon as it's buffered
+        for n in 0..EXPORT_BATCH_SIZE as u64 {
+            out.send_event("tick", n.into(), vec![timestamp(n)])
+                .await
+                .unwrap();
+        }
+        let (path, body) = requests.recv().await.unwrap();
+        assert_eq!(path, "/v1/logs");
+        let records = log_records(&body);
+        assert_eq!(records.len(), EXPORT_BATCH_SIZE);
+        assert_eq!(records[3]["timeUnixNano"], "3");
+        assert_eq!(records[3]["body"]["stringValue"], "tick");
+        assert_eq!(
+            records[3]["attributes"][0],
+            json!({ "key": "timestamp", "value": { "intValue": "3" } })
+        );
+        let resource_attrs = &body["resourceLogs"][0]["resource"]["attributes"];
+        assert_eq!(
+            resource_attrs[0],
+            json!({ "key": "service.name", "value": { "stringValue": "stream" } })
+        );
+        assert_eq!(
+            resource_attrs[1],
+            json!({ "key": "clock.name", "value": { "stringValue": "default" } })
+        );
+        assert_eq!(
+            body["resourceLogs"][0]["scopeLogs"][0]["scope"]["name"],
+            SCOPE_NAME
+        );
+
+        // The rest waits for the flush
+        out.send_event("start", 0, vec![timestamp(1_000)])
+            .await
+            .unwrap();
+        out.send_event("end", 1, vec![timestamp(1_500)])
+            .await
+            .unwrap();
+        assert!(requests.try_recv().is_err());
+        out.flush().await.unwrap();
+
+        let (path, body) = requests.recv().await.unwrap();
+        assert_eq!(path, "/v1/logs");
+        let names: Vec<&Value> = log_records(&body)
+            .iter()
+            .map(|r| &r["body"]["stringValue"])
+            .collect();
+        assert_eq!(names, vec!["start", "end"]);
+
+        let (path, body) = requests.recv().await.unwrap();
+        assert_eq!(path, "/v1/traces");
+        let spans = body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        assert_eq!(span["name"], "start");
+        assert_eq!(span["kind"], 1);
+        assert_eq!(span["startTimeUnixNano"], "1000");
+        assert_eq!(span["endTimeUnixNano"], "1500");
+        assert_eq!(span["traceId"].as_str().unwrap().len(), 32);
+        assert_eq!(span["spanId"].as_str().unwrap().len(), 16);
+        let keys: Vec<&Value> = span["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|kv| &kv["key"])
+            .collect();
+        assert_eq!(keys, vec!["timestamp", "end.timestamp"]);
+
+        // Nothing left to export
+        out.flush().await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use anyhow::anyhow;
+use auxon_sdk::{
+    api::{AttrVal, TimelineId},
+    plugin_utils::ingest::{Client, Config},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::{
+    fs,
+    io::{AsyncWriteExt, BufWriter},
+};
+use tracing::info;
+
+use crate::{
+    chrome_trace::ChromeTraceOutput, otlp::OtlpOutput, pairs::parse_event_pairs, sink::Sink,
+    HasCommonConfig,
+};
+
+/// The file format used by the offline output
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// One JSON object per timeline or event, per line
+    Jsonl,
+    /// One row per timeline or event attribute
+    Csv,
+    /// Chrome Trace Event JSON, for `chrome://tracing` or the Perfetto UI
+    ChromeTrace,
+}
+
+impl OutputFormat {
+    /// The format implied by the output file extension, JSON Lines by default.
+    ///
+    /// Only `.trace.json` implies Chrome Trace, a plain `.json` could be
+    /// meant for any JSON tooling.
+    pub fn from_path(path: &Path) -> Self {
+        let file_name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        if file_name.ends_with(".csv") {
+            OutputFormat::Csv
+        } else if file_name.ends_with(".trace.json") {
+            OutputFormat::ChromeTrace
+        } else {
+            OutputFormat::Jsonl
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "jsonl" | "json-lines" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            "chrome-trace" | "perfetto" => Ok(OutputFormat::ChromeTrace),
+            _ => Err(format!(
+                "Invalid output format '{}', expected 'jsonl', 'csv' or 'chrome-trace'",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the converted timelines and events are sent
+pub enum Output {
+    /// A Modality backend
+    Modality(Client),
+    /// A local file, for inspecting decoded traces without a backend
+    File(FileOutput),
+    /// A Chrome Trace Event file, for a quick visual in a browser
+    ChromeTrace(ChromeTraceOutput),
+    /// An OpenTelemetry collector or backend
+    Otlp(OtlpOutput),
+}
+
+impl From<Client> for Output {
+    fn from(client: Client) -> Self {
+        Output::Modality(client)
+    }
+}
+
+impl Output {
+    /// Export to the OTLP endpoint when the `otlp-endpoint` option is set, open the
+    /// offline output file when the `output` option is set, otherwise connect to
+    /// the Modality backend
+    pub async fn open<C: HasCommonConfig>(config: &Config<C>) -> Result<Self, anyhow::Error> {
+        let common = config.plugin.common_config();
+        if let Some(endpoint) = common.otlp_endpoint.as_ref() {
+            let event_pairs = parse_event_pairs(&common.event_pairs)?;
+            return Ok(Output::Otlp(OtlpOutput::new(endpoint, event_pairs)?));
+        }
+        match common.output.as_ref() {
+            Some(path) => {
+                let format = common
+                    .output_format
+                    .unwrap_or_else(|| OutputFormat::from_path(path));
+                Ok(match format {
+                    OutputFormat::ChromeTrace => {
+                        let event_pairs = parse_event_pairs(&common.event_pairs)?;
+                        Output::ChromeTrace(ChromeTraceOutput::create(path, event_pairs).await?)
+                    }
+                    _ => Output::File(FileOutput::create(path, format).await?),
+                })
+            }
+            None => {
+                let client = config
+                    .connect_and_authenticate_ingest()
+                    .await
+                    .map_err(|e| anyhow!("Failed to connect to modality/reflector. {}", e))?;
+                info!("Connected to Modality backend");
+                Ok(Output::Modality(client))
+            }
+        }
+    }
+}
+
+impl Sink for Output {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), anyhow::Error> {
+        match self {
+            Output::Modality(client) => Sink::switch_timeline(client, id).await,
+            Output::File(f) => f.switch_timeline(id).await,
+            Output::ChromeTrace(c) => c.switch_timeline(id).await,
+            Output::Otlp(o) => o.switch_timeline(id).await,
+        }
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Output::Modality(client) => Sink::send_timeline_attrs(client, name, attrs).await,
+            Output::File(f) => f.send_timeline_attrs(name, attrs).await,
+            Output::ChromeTrace(c) => c.send_timeline_attrs(name, attrs).await,
+            Output::Otlp(o) => o.send_timeline_attrs(name, attrs).await,
+        }
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        match self {
+            Output::Modality(client) => Sink::send_event(client, name, ordering, attrs).await,
+            Output::File(f) => f.send_event(name, ordering, attrs).await,
+            Output::ChromeTrace(c) => c.send_event(name, ordering, attrs).await,
+            Output::Otlp(o) => o.send_event(name, ordering, attrs).await,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        match self {
+            Output::Modality(client) => Sink::flush(client).await,
+            Output::File(f) => f.flush().await,
+            Output::ChromeTrace(c) => c.flush().await,
+            Output::Otlp(o) => o.flush().await,
+        }
+    }
+}
+
+/// Writes timelines and events to a JSON Lines or CSV file.
+///
+/// Attribute keys are the ones sent to Modality, without the `timeline.`
+/// and `event.` prefixes. Timelines are numbered in the order they're
+/// first seen, and attributes are sorted by key, so the output of two
+/// runs over the same trace can be diffed.
+pub struct FileOutput {
+    path: PathBuf,
+    format: OutputFormat,
+    writer: BufWriter<fs::File>,
+    timelines: HashMap<TimelineId, usize>,
+    current_timeline: usize,
+}
+
+const CSV_HEADER: &str = "record,timeline,name,ordering,key,value\n";
+
+impl FileOutput {
+    pub async fn create(path: &Path, format: OutputFormat) -> Result<Self, anyhow::Error> {
+        let file = fs::File::create(path)
+            .await
+            .map_err(|e| anyhow!("Failed to create output file '{}'. {}", path.display(), e))?;
+        info!(file = %path.display(), ?format, "Writing to output file");
+        let mut writer = BufWriter::new(file);
+        if format == OutputFormat::Csv {
+            writer.write_all(CSV_HEADER.as_bytes()).await?;
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            format,
+            writer,
+            timelines: Default::default(),
+            current_timeline: 0,
+        })
+    }
+
+    async fn write(&mut self, data: &str) -> Result<(), anyhow::Error> {
+        self.writer.write_all(data.as_bytes()).await.map_err(|e| {
+            anyhow!(
+                "Failed to write to output file '{}'. {}",
+                self.path.display(),
+                e
+            )
+        })
+    }
+}
+
+impl Sink for FileOutput {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), anyhow::Error> {
+        let next = self.timelines.len();
+        self.current_timeline = *self.timelines.entry(id).or_insert(next);
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let attrs = sorted_attrs(attrs);
+        let line = match self.format {
+            OutputFormat::Jsonl => json_line(json!({
+                "record": "timeline",
+                "timeline": self.current_timeline,
+                "name": name,
+                "attrs": Value::Object(attrs.into_iter().collect()),
+            }))?,
+            OutputFormat::Csv => csv_rows("timeline", self.current_timeline, name, None, attrs),
+        };
+        self.write(&line).await
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let attrs = sorted_attrs(attrs);
+        let line = match self.format {
+            OutputFormat::Jsonl => json_line(json!({
+                "record": "event",
+                "timeline": self.current_timeline,
+                "name": name,
+                // Orderings can exceed what JSON numbers represent exactly
+                "ordering": ordering.to_string(),
+                "attrs": Value::Object(attrs.into_iter().collect()),
+            }))?,
+            OutputFormat::Csv => {
+                csv_rows("event", self.current_timeline, name, Some(ordering), attrs)
+            }
+        };
+        self.write(&line).await
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        self.writer.flush().await.map_err(|e| {
+            anyhow!(
+                "Failed to write to output file '{}'. {}",
+                self.path.display(),
+                e
+            )
+        })
+    }
+}
+
+fn sorted_attrs(attrs: Vec<(&str, AttrVal)>) -> Vec<(String, Value)> {
+    let mut attrs: Vec<(String, Value)> = attrs
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), attr_json(&v)))
+        .collect();
+    attrs.sort_by(|a, b| a.0.cmp(&b.0));
+    attrs
+}
+
+pub(crate) fn attr_json(val: &AttrVal) -> Value {
+    match val {
+        AttrVal::String(s) => Value::String(s.to_string()),
+        AttrVal::Integer(i) => Value::from(*i),
+        AttrVal::Bool(b) => Value::Bool(*b),
+        AttrVal::Float(f) => Value::from(f64::from(*f)),
+        AttrVal::Timestamp(ns) => Value::from(ns.get_raw()),
+        other => Value::String(other.to_string()),
+    }
+}
+
+/// The nanosecond `timestamp` attribute of an event, if it has one
+pub(crate) fn event_timestamp_ns(attrs: &[(&str, AttrVal)]) -> Option<u64> {
+    attrs.iter().find_map(|(k, v)| match v {
+        AttrVal::Timestamp(ns) if *k == "timestamp" => Some(ns.get_raw()),
+        _ => None,
+    })
+}
+
+fn json_line(value: Value) -> Result<String, anyhow::Error> {
+    let mut line = serde_json::to_string(&value)?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn csv_rows(
+    record: &str,
+    timeline: usize,
+    name: &str,
+    ordering: Option<u128>,
+    attrs: Vec<(String, Value)>,
+) -> String {
+    let ordering = ordering.map(|o| o.to_string()).unwrap_or_default();
+    if attrs.is_empty() {
+        return format!("{record},{timeline},{},{ordering},,\n", csv_field(name));
+    }
+    let mut rows = String::new();
+    for (key, val) in attrs.into_iter() {
+        let val = match val {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+        rows.push_str(&format!(
+            "{record},{timeline},{},{ordering},{},{}\n",
+            csv_field(name),
+            csv_field(&key),
+            csv_field(&val)
+        ));
+    }
+    rows
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use auxon_sdk::api::Nanoseconds;
+
+    #[test]
+    fn format_from_path() {
+        let format = |p: &str| OutputFormat::from_path(Path::new(p));
+        assert_eq!(format("out.csv"), OutputFormat::Csv);
+        assert_eq!(format("out.CSV"), OutputFormat::Csv);
+        assert_eq!(format("out.trace.json"), OutputFormat::ChromeTrace);
+        assert_eq!(format("dir/out.Trace.JSON"), OutputFormat::ChromeTrace);
+        assert_eq!(format("out.json"), OutputFormat::Jsonl);
+        assert_eq!(format("out.jsonl"), OutputFormat::Jsonl);
+        assert_eq!(format("out"), OutputFormat::Jsonl);
+    }
+
+    #[test]
+    fn timestamps_are_numbers() {
+        let ts = AttrVal::Timestamp(Nanoseconds::from(1_500_u64));
+        assert_eq!(attr_json(&ts), json!(1_500));
+        assert_eq!(attr_json(&AttrVal::Integer(-3)), json!(-3));
+        assert_eq!(attr_json(&AttrVal::from("x")), json!("x"));
+    }
+}
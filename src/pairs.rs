@@ -0,0 +1,226 @@
+use auxon_sdk::api::AttrVal;
+use std::{collections::HashMap, fmt, hash::Hash, str::FromStr};
+
+/// A begin/end event pair, configured as `begin:end[:key]`.
+///
+/// When `key` is set, only begin and end events with the same value for
+/// that attribute (i.e. a task id) are paired.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventPair {
+    pub begin: String,
+    pub end: String,
+    pub key: Option<String>,
+}
+
+impl FromStr for EventPair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split(':').map(str::trim).collect();
+        match parts.as_slice() {
+            [begin, end] | [begin, end, ""] if !begin.is_empty() && !end.is_empty() => {
+                Ok(EventPair {
+                    begin: begin.to_string(),
+                    end: end.to_string(),
+                    key: None,
+                })
+            }
+            [begin, end, key] if !begin.is_empty() && !end.is_empty() => Ok(EventPair {
+                begin: begin.to_string(),
+                end: end.to_string(),
+                key: Some(key.to_string()),
+            }),
+            _ => Err(format!(
+                "Invalid event pair '{}', expected 'begin:end' or 'begin:end:key'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EventPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.begin, self.end)?;
+        if let Some(key) = self.key.as_ref() {
+            write!(f, ":{}", key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the `event-pairs` option
+pub fn parse_event_pairs(pairs: &[String]) -> Result<Vec<EventPair>, anyhow::Error> {
+    pairs
+        .iter()
+        .map(|p| EventPair::from_str(p).map_err(|e| anyhow::anyhow!(e)))
+        .collect()
+}
+
+/// What an event is with regard to the configured pairs
+pub enum PairMatch<B> {
+    /// The event isn't part of a pair
+    Unpaired,
+    /// The event begins a pair
+    Begin,
+    /// The event ends a pair, with what was kept from its begin event
+    End(B),
+    /// The event ends a pair that wasn't begun
+    UnmatchedEnd,
+}
+
+/// Matches the begin and end events of the configured pairs.
+///
+/// Pairs are matched per timeline `T`, pair and key value. Pairs nest:
+/// an end event closes the most recent matching begin event.
+pub struct PairMatcher<T, B> {
+    pairs: Vec<EventPair>,
+    open: HashMap<(T, usize, Option<String>), Vec<B>>,
+}
+
+impl<T: Clone + Eq + Hash, B> PairMatcher<T, B> {
+    pub fn new(pairs: Vec<EventPair>) -> Self {
+        Self {
+            pairs,
+            open: Default::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Handle an event on the given timeline, `begin` provides what's
+    /// kept until the matching end event when the event begins a pair
+    pub fn handle(
+        &mut self,
+        timeline: &T,
+        name: &str,
+        attrs: &[(&str, AttrVal)],
+        begin: impl FnOnce() -> B,
+    ) -> PairMatch<B> {
+        let Some((idx, pair)) = self
+            .pairs
+            .iter()
+            .enumerate()
+            .find(|(_, p)| p.begin == name || p.end == name)
+        else {
+            return PairMatch::Unpaired;
+        };
+
+        let key_val = pair.key.as_ref().and_then(|key| {
+            attrs
+                .iter()
+                .find(|(k, _)| *k == key.as_str())
+                .map(|(_, v)| v.to_string())
+        });
+        let open_key = (timeline.clone(), idx, key_val);
+
+        if pair.begin == name {
+            self.open.entry(open_key).or_default().push(begin());
+            PairMatch::Begin
+        } else {
+            match self.open.get_mut(&open_key).and_then(|open| open.pop()) {
+                Some(b) => PairMatch::End(b),
+                None => PairMatch::UnmatchedEnd,
+            }
+        }
+    }
+
+    /// Forget the open pairs of a timeline, i.e. when its trace restarted
+    pub fn clear_timeline(&mut self, timeline: &T) {
+        self.open.retain(|(t, _, _), _| t != timeline);
+    }
+
+    /// The begin events that haven't been matched yet
+    pub fn open(&self) -> impl Iterator<Item = &B> {
+        self.open.values().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(pairs: &[&str]) -> PairMatcher<u32, i64> {
+        let pairs: Vec<String> = pairs.iter().map(|p| p.to_string()).collect();
+        PairMatcher::new(parse_event_pairs(&pairs).unwrap())
+    }
+
+    /// Returns the begin value for end events, `None` for begin events
+    fn handle(
+        m: &mut PairMatcher<u32, i64>,
+        name: &str,
+        attrs: &[(&str, AttrVal)],
+        begin: i64,
+    ) -> Option<Option<i64>> {
+        match m.handle(&0, name, attrs, || begin) {
+            PairMatch::Begin => Some(None),
+            PairMatch::End(b) => Some(Some(b)),
+            PairMatch::Unpaired | PairMatch::UnmatchedEnd => None,
+        }
+    }
+
+    #[test]
+    fn parse_pairs() {
+        assert_eq!(
+            "task_start:task_end:task_id".parse::<EventPair>().unwrap(),
+            EventPair {
+                begin: "task_start".to_owned(),
+                end: "task_end".to_owned(),
+                key: Some("task_id".to_owned()),
+            }
+        );
+        let pair: EventPair = " isr_enter : isr_exit ".parse().unwrap();
+        assert_eq!(pair.key, None);
+        assert_eq!(pair.to_string(), "isr_enter:isr_exit");
+        assert_eq!("a:b:".parse::<EventPair>().unwrap().key, None);
+    }
+
+    #[test]
+    fn bad_pairs() {
+        for spec in ["", "begin", ":end", "begin:", "a:b:c:d"] {
+            assert!(spec.parse::<EventPair>().is_err(), "{spec}");
+        }
+        let err = parse_event_pairs(&["a:b".to_owned(), "c".to_owned()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid event pair 'c', expected 'begin:end' or 'begin:end:key'"
+        );
+    }
+
+    #[test]
+    fn nested_pairs_close_the_most_recent_begin() {
+        let mut m = matcher(&["lock:unlock"]);
+        assert_eq!(handle(&mut m, "lock", &[], 1), Some(None));
+        assert_eq!(handle(&mut m, "lock", &[], 2), Some(None));
+        assert_eq!(handle(&mut m, "other", &[], 3), None);
+        assert_eq!(handle(&mut m, "unlock", &[], 4), Some(Some(2)));
+        assert_eq!(handle(&mut m, "unlock", &[], 5), Some(Some(1)));
+        assert_eq!(m.open().count(), 0);
+    }
+
+    #[test]
+    fn keyed_pairs() {
+        let mut m = matcher(&["start:end:id"]);
+        let id = |v: i64| [("id", AttrVal::from(v))];
+        assert_eq!(handle(&mut m, "start", &id(1), 10), Some(None));
+        assert_eq!(handle(&mut m, "start", &id(2), 20), Some(None));
+        assert_eq!(handle(&mut m, "end", &id(1), 0), Some(Some(10)));
+        assert_eq!(handle(&mut m, "end", &id(2), 0), Some(Some(20)));
+    }
+
+    #[test]
+    fn unmatched_end() {
+        let mut m = matcher(&["start:end"]);
+        assert!(matches!(
+            m.handle(&0, "end", &[], || 0),
+            PairMatch::UnmatchedEnd
+        ));
+        assert_eq!(handle(&mut m, "start", &[], 1), Some(None));
+        m.clear_timeline(&0);
+        assert!(matches!(
+            m.handle(&0, "end", &[], || 0),
+            PairMatch::UnmatchedEnd
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+use std::path::PathBuf;
+use tokio::{
+    fs,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tokio_util::{
+    bytes::{Bytes, BytesMut},
+    codec::Decoder,
+};
+use tracing::{debug, error, info};
+
+/// Where and how to record the raw packet data received by a collector
+#[derive(Clone, Debug)]
+pub struct RecordOptions {
+    pub path: PathBuf,
+    /// Start a new file once the current one reaches this many bytes
+    pub rotate_size: Option<u64>,
+    /// Start a new file once the current one has been written to for this long
+    pub rotate_interval: Option<Duration>,
+}
+
+impl RecordOptions {
+    fn rotates(&self) -> bool {
+        self.rotate_size.is_some() || self.rotate_interval.is_some()
+    }
+
+    /// The recording file path, `<stem>[-<name>][-<index>].<ext>`
+    fn file_path(&self, name: Option<&str>, index: usize) -> PathBuf {
+        let mut file_name = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(name) = name {
+            file_name.push('-');
+            file_name.extend(name.chars().map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            }));
+        }
+        if self.rotates() {
+            file_name.push_str(&format!("-{index:04}"));
+        }
+        if let Some(ext) = self.path.extension() {
+            file_name.push('.');
+            file_name.push_str(&ext.to_string_lossy());
+        }
+        self.path.with_file_name(file_name)
+    }
+}
+
+/// Records raw packet data to disk, rotating the files by size and/or time.
+///
+/// Files are only rotated between packets, so each file can be imported
+/// on its own. The writing is done by a background task; clones share it.
+#[derive(Clone)]
+pub struct Recorder {
+    tx: mpsc::UnboundedSender<Bytes>,
+}
+
+impl Recorder {
+    /// Start recording, `name` (i.e. a peer address) is added to the file names when
+    /// a collector records more than one connection
+    pub fn spawn(opts: &RecordOptions, name: Option<&str>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let opts = opts.clone();
+        let name = name.map(str::to_owned);
+        tokio::spawn(async move {
+            if let Err(e) = write_recording(rx, &opts, name.as_deref()).await {
+                error!(%e, "Failed to record raw packet data, recording stopped");
+            }
+        });
+        Self { tx }
+    }
+
+    pub fn record(&self, data: Bytes) {
+        // Recording failures are logged by the writer, they don't stop the collection
+        let _ = self.tx.send(data);
+    }
+}
+
+async fn write_recording(
+    mut rx: mpsc::UnboundedReceiver<Bytes>,
+    opts: &RecordOptions,
+    name: Option<&str>,
+) -> Result<(), std::io::Error> {
+    let mut index = 0;
+    let mut file: Option<RecordingFile> = None;
+
+    while let Some(data) = rx.recv().await {
+        let mut next = Some(data);
+        while let Some(data) = next.take() {
+            if let Some(f) = file.as_mut() {
+                let size_exceeded = opts
+                    .rotate_size
+                    .map(|max| f.size != 0 && f.size + data.len() as u64 > max)
+                    .unwrap_or(false);
+                let interval_elapsed = opts
+                    .rotate_interval
+                    .map(|max| f.opened_at.elapsed() >= max)
+                    .unwrap_or(false);
+                if size_exceeded || interval_elapsed {
+                    f.writer.flush().await?;
+                    file = None;
+                }
+            }
+
+            let f = match file.as_mut() {
+                Some(f) => f,
+                None => {
+                    let path = opts.file_path(name, index);
+                    index += 1;
+                    file.insert(RecordingFile::create(path).await?)
+                }
+            };
+            f.writer.write_all(&data).await?;
+            f.size += data.len() as u64;
+
+            next = rx.try_recv().ok();
+        }
+
+        // Caught up, make what was received so far available on disk
+        if let Some(f) = file.as_mut() {
+            f.writer.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
+struct RecordingFile {
+    writer: BufWriter<fs::File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RecordingFile {
+    async fn create(path: PathBuf) -> Result<Self, std::io::Error> {
+        info!(file = %path.display(), "Recording raw packet data");
+        let map_err = |e: std::io::Error| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to create recording file '{}'. {}",
+                    path.display(),
+                    e
+                ),
+            )
+        };
+        // Append to the recording of a previous run, packets are only
+        // written whole so the file stays importable
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(map_err)?;
+        let size = file.metadata().await.map_err(map_err)?.len();
+        Ok(Self {
+            writer: BufWriter::new(file),
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+}
+
+/// A decoder that records the bytes of each packet the inner decoder decodes.
+///
+/// Data the inner decoder fails to parse is recorded too, so it can be
+/// re-imported once the parser is fixed.
+pub struct RecordingDecoder<D> {
+    inner: D,
+    recorder: Option<Recorder>,
+    /// A copy of the caller's buffered data that wasn't recorded yet.
+    /// Only the data appended since the last call is copied, the inner
+    /// decoder consumes the buffer so the packets can't be copied afterwards.
+    unrecorded: BytesMut,
+}
+
+impl<D> RecordingDecoder<D> {
+    pub fn new(inner: D, recorder: Option<Recorder>) -> Self {
+        Self {
+            inner,
+            recorder,
+            unrecorded: BytesMut::new(),
+        }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    fn record_decode<T, E>(
+        &mut self,
+        src: &mut BytesMut,
+        decode: impl FnOnce(&mut D, &mut BytesMut) -> Result<Option<T>, E>,
+    ) -> Result<Option<T>, E> {
+        let Some(recorder) = self.recorder.as_ref() else {
+            return decode(&mut self.inner, src);
+        };
+
+        // The caller only appends to the buffer, unless it discarded what was buffered
+        if src.len() < self.unrecorded.len() {
+            self.unrecorded.clear();
+        }
+        let buffered = src.len();
+        self.unrecorded
+            .extend_from_slice(&src[self.unrecorded.len()..]);
+
+        match decode(&mut self.inner, src) {
+            Ok(item) => {
+                let consumed = buffered - src.len();
+                if consumed != 0 {
+                    recorder.record(self.unrecorded.split_to(consumed).freeze());
+                }
+                Ok(item)
+            }
+            Err(e) => {
+                debug!(
+                    bytes = self.unrecorded.len(),
+                    "Recording data that failed to parse"
+                );
+                recorder.record(self.unrecorded.split().freeze());
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<D: Decoder> Decoder for RecordingDecoder<D> {
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.record_decode(src, |d, src| d.decode(src))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.record_decode(src, |d, src| d.decode_eof(src))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, PACKET_SIZE};
+    use barectf_parser::Parser;
+
+    fn opts(dir: &tempfile::TempDir) -> RecordOptions {
+        RecordOptions {
+            path: dir.path().join("capture.bin"),
+            rotate_size: None,
+            rotate_interval: None,
+        }
+    }
+
+    fn packet(idx: usize) -> Bytes {
+        Bytes::from_static(&fixtures::STREAM[idx * PACKET_SIZE..(idx + 1) * PACKET_SIZE])
+    }
+
+    async fn read(opts: &RecordOptions, index: usize) -> Vec<u8> {
+        fs::read(opts.file_path(None, index)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn rotates_by_size_between_packets() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RecordOptions {
+            rotate_size: Some(PACKET_SIZE as u64 + 1),
+            ..opts(&dir)
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        for idx in [0, 1, 0] {
+            tx.send(packet(idx)).unwrap();
+        }
+        drop(tx);
+        write_recording(rx, &opts, None).await.unwrap();
+
+        assert_eq!(read(&opts, 0).await, packet(0));
+        assert_eq!(read(&opts, 1).await, packet(1));
+        assert_eq!(read(&opts, 2).await, packet(0));
+        assert!(!opts.file_path(None, 3).exists());
+    }
+
+    #[tokio::test]
+    async fn rotates_by_time_between_packets() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = RecordOptions {
+            rotate_interval: Some(Duration::from_millis(50)),
+            ..opts(&dir)
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn({
+            let opts = opts.clone();
+            async move { write_recording(rx, &opts, None).await }
+        });
+        tx.send(packet(0)).unwrap();
+        tx.send(packet(1)).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tx.send(packet(0)).unwrap();
+        drop(tx);
+        writer.await.unwrap().unwrap();
+
+        assert_eq!(read(&opts, 0).await, fixtures::STREAM);
+        assert_eq!(read(&opts, 1).await, packet(0));
+    }
+
+    #[tokio::test]
+    async fn appends_to_an_existing_recording() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = opts(&dir);
+        for idx in [0, 1] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tx.send(packet(idx)).unwrap();
+            drop(tx);
+            write_recording(rx, &opts, None).await.unwrap();
+        }
+        assert_eq!(read(&opts, 0).await, fixtures::STREAM);
+    }
+
+    #[test]
+    fn records_the_decoded_packets() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let decoder = Parser::new(&fixtures::config())
+            .unwrap()
+            .into_packet_decoder();
+        let mut dec = RecordingDecoder::new(decoder, Some(Recorder { tx }));
+
+        // The data arrives in chunks that don't line up with the packets
+        let mut buf = BytesMut::new();
+        let mut pkts = 0;
+        for chunk in fixtures::STREAM.chunks(100) {
+            buf.extend_from_slice(chunk);
+            while dec.decode(&mut buf).unwrap().is_some() {
+                pkts += 1;
+            }
+        }
+        assert_eq!(pkts, 2);
+        assert!(buf.is_empty());
+
+        drop(dec);
+        let mut recorded = Vec::new();
+        while let Some(data) = rx.blocking_recv() {
+            recorded.push(data);
+        }
+        assert_eq!(recorded, vec![packet(0), packet(1)]);
+    }
+}
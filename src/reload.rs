@@ -0,0 +1,186 @@
+use crate::{
+    record::{Recorder, RecordingDecoder},
+    routing::RoutingDecoder,
+    ConfigId,
+};
+use barectf_parser::{Config as BarectfConfig, Packet};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio::{
+    io::AsyncRead,
+    sync::watch,
+    time::{self, Duration, Interval, MissedTickBehavior},
+};
+use tokio_stream::StreamExt;
+use tokio_util::codec::FramedRead;
+use tracing::debug;
+
+/// The default interval between checks for barectf configuration file changes
+pub const DEFAULT_RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Signals when the barectf configuration files should be reloaded, either
+/// because one of them was modified or because the process received SIGHUP.
+pub struct ConfigReloader {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    interval: Interval,
+    #[cfg(unix)]
+    sighup: tokio::signal::unix::Signal,
+}
+
+impl ConfigReloader {
+    pub async fn new(paths: &[PathBuf], poll_interval: Duration) -> io::Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        for p in paths.iter() {
+            files.push((p.clone(), modified(p).await));
+        }
+
+        let mut interval = time::interval(poll_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Ok(Self {
+            files,
+            interval,
+            #[cfg(unix)]
+            sighup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    /// Wait until the configuration should be reloaded.
+    ///
+    /// This is cancel safe.
+    pub async fn changed(&mut self) {
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = self.sighup.recv() => {
+                    debug!("Received SIGHUP");
+                    self.refresh_modified().await;
+                    return;
+                }
+                _ = self.interval.tick() => {}
+            }
+            #[cfg(not(unix))]
+            self.interval.tick().await;
+
+            if self.refresh_modified().await {
+                return;
+            }
+        }
+    }
+
+    /// Update the modification times, returns true if any of them changed
+    async fn refresh_modified(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last_modified) in self.files.iter_mut() {
+            let m = modified(path).await;
+            // Skip files that are missing, i.e. in the middle of being replaced by an editor
+            if m.is_some() && m != *last_modified {
+                debug!(file = %path.display(), "barectf configuration file changed");
+                *last_modified = m;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// Wait until the configuration should be reloaded, or forever when
+/// reloading isn't enabled
+pub async fn changed(reloader: &mut Option<ConfigReloader>) {
+    match reloader {
+        Some(r) => r.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// barectf configurations registered with the sender, see [`crate::Sender::add_configs`]
+#[derive(Clone, Debug)]
+pub struct Configs {
+    /// The id of the first configuration
+    pub first_config: ConfigId,
+    pub configs: Vec<BarectfConfig>,
+}
+
+/// Reads and decodes the packets of a stream, switching to the configurations
+/// published on a watch channel when they're reloaded (see [`RoutingDecoder::reload`])
+pub struct PacketReader<R> {
+    reader: FramedRead<R, RecordingDecoder<RoutingDecoder>>,
+    /// No longer watched once the publisher is gone
+    configs_rx: Option<watch::Receiver<Arc<Configs>>>,
+    start_event: Option<String>,
+}
+
+enum ReaderEvent {
+    Packet(Option<Result<(ConfigId, Packet), anyhow::Error>>),
+    ConfigsChanged(bool),
+}
+
+impl<R: AsyncRead + Unpin> PacketReader<R> {
+    /// Read from `reader` with the currently published configurations.
+    ///
+    /// Reloaded configurations are used once the trace restarts with `start_event`,
+    /// or right away without one.
+    pub fn new(
+        reader: R,
+        mut configs_rx: watch::Receiver<Arc<Configs>>,
+        start_event: Option<String>,
+        recorder: Option<Recorder>,
+    ) -> Result<Self, anyhow::Error> {
+        let cfgs = configs_rx.borrow_and_update().clone();
+        let decoder = RoutingDecoder::with_first_config(cfgs.first_config, &cfgs.configs)?;
+        Ok(Self {
+            reader: FramedRead::new(reader, RecordingDecoder::new(decoder, recorder)),
+            configs_rx: Some(configs_rx),
+            start_event,
+        })
+    }
+
+    /// The next packet, `None` at the end of the stream.
+    ///
+    /// This is cancel safe.
+    pub async fn next(&mut self) -> Option<Result<(ConfigId, Packet), anyhow::Error>> {
+        loop {
+            let configs_rx = &mut self.configs_rx;
+            let configs_changed = async {
+                match configs_rx.as_mut() {
+                    Some(rx) => rx.changed().await.is_ok(),
+                    None => std::future::pending().await,
+                }
+            };
+            let ev = tokio::select! {
+                pkt = self.reader.next() => ReaderEvent::Packet(pkt),
+                is_open = configs_changed => ReaderEvent::ConfigsChanged(is_open),
+            };
+
+            match ev {
+                ReaderEvent::Packet(pkt) => return pkt,
+                ReaderEvent::ConfigsChanged(false) => self.configs_rx = None,
+                ReaderEvent::ConfigsChanged(true) => {
+                    let Some(rx) = self.configs_rx.as_mut() else {
+                        continue;
+                    };
+                    let cfgs = rx.borrow_and_update().clone();
+                    let decoder = self.reader.decoder_mut().inner_mut();
+                    if let Err(e) = decoder.reload(
+                        cfgs.first_config,
+                        &cfgs.configs,
+                        self.start_event.as_deref(),
+                    ) {
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use barectf_parser::{Packet, StreamId};
+use fxhash::FxHashMap;
+use internment::Intern;
+use std::collections::BTreeMap;
+use tracing::{debug, warn};
+
+/// The default number of out-of-order packets held back per stream
+pub const DEFAULT_REORDER_WINDOW: usize = 16;
+
+/// Puts the packets of each stream back in packet sequence number order,
+/// for transports that can lose or reorder packets (i.e. UDP).
+///
+/// Up to `window` packets are held back per stream waiting for a missing
+/// sequence number; once the window is full the missing packets are
+/// considered lost. Packets without a sequence number are released right away.
+///
+/// A packet containing the start event, or one whose sequence number is more
+/// than `window` behind, restarts the stream's sequence (i.e. the target
+/// rebooted) instead of being dropped as late.
+pub struct SequenceReorderer<T = ()> {
+    window: usize,
+    start_event: Option<Intern<String>>,
+    streams: FxHashMap<StreamId, StreamReorder<T>>,
+}
+
+struct StreamReorder<T> {
+    next_seqnum: u64,
+    pending: BTreeMap<u64, (T, Packet)>,
+}
+
+impl<T> SequenceReorderer<T> {
+    pub fn new(window: usize, start_event: Option<&str>) -> Self {
+        Self {
+            window: window.max(1),
+            start_event: start_event.map(|e| Intern::new(e.to_owned())),
+            streams: Default::default(),
+        }
+    }
+
+    /// Buffer a packet, returning the packets that are now in order
+    pub fn push(&mut self, tag: T, pkt: Packet) -> Vec<(T, Packet)> {
+        let Some(seqnum) = pkt.context.sequence_number else {
+            return vec![(tag, pkt)];
+        };
+
+        let is_start = self
+            .start_event
+            .map(|start_event| pkt.events.iter().any(|ev| ev.name == start_event))
+            .unwrap_or(false);
+
+        let mut released = Vec::new();
+        let stream = self
+            .streams
+            .entry(pkt.header.stream_id)
+            .or_insert_with(|| StreamReorder {
+                next_seqnum: seqnum,
+                pending: BTreeMap::new(),
+            });
+
+        if is_start && seqnum != stream.next_seqnum {
+            // The trace restarted, the packets still waiting belong to the previous run
+            debug!(
+                seqnum,
+                expected = stream.next_seqnum,
+                "Packet sequence number restarted with the start event"
+            );
+            released.extend(std::mem::take(&mut stream.pending).into_values());
+            stream.next_seqnum = seqnum;
+        } else if seqnum < stream.next_seqnum {
+            if stream.next_seqnum - seqnum <= self.window as u64 {
+                debug!(seqnum, "Dropping duplicate or late packet");
+                return released;
+            }
+            // Too far behind to be late, the sequence restarted (i.e. the target rebooted)
+            debug!(
+                seqnum,
+                expected = stream.next_seqnum,
+                "Packet sequence number restarted"
+            );
+            released.extend(std::mem::take(&mut stream.pending).into_values());
+            stream.next_seqnum = seqnum;
+        }
+
+        stream.pending.insert(seqnum, (tag, pkt));
+
+        loop {
+            while let Some(p) = stream.pending.remove(&stream.next_seqnum) {
+                released.push(p);
+                stream.next_seqnum += 1;
+            }
+
+            if stream.pending.len() <= self.window {
+                break;
+            }
+
+            // Give up on the missing packets
+            if let Some(first) = stream.pending.keys().next().copied() {
+                warn!(
+                    lost_packets = first - stream.next_seqnum,
+                    "Packets were lost"
+                );
+                stream.next_seqnum = first;
+            }
+        }
+
+        released
+    }
+
+    /// Release all of the buffered packets, in order
+    pub fn drain(&mut self) -> Vec<(T, Packet)> {
+        self.streams
+            .values_mut()
+            .flat_map(|s| std::mem::take(&mut s.pending).into_values())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    /// A packet with the given sequence number, packet 0 of the fixture
+    /// starts with the `init` event
+    fn packet(seqnum: u64, is_start: bool) -> Packet {
+        let mut pkt = fixtures::packets().remove(if is_start { 0 } else { 1 });
+        pkt.context.sequence_number = Some(seqnum);
+        pkt
+    }
+
+    fn push(reorderer: &mut SequenceReorderer<u64>, seqnum: u64, is_start: bool) -> Vec<u64> {
+        reorderer
+            .push(seqnum, packet(seqnum, is_start))
+            .into_iter()
+            .map(|(tag, _)| tag)
+            .collect()
+    }
+
+    #[test]
+    fn in_order_packets_are_released_right_away() {
+        let mut r = SequenceReorderer::new(4, None);
+        assert_eq!(push(&mut r, 0, false), vec![0]);
+        assert_eq!(push(&mut r, 1, false), vec![1]);
+    }
+
+    #[test]
+    fn packets_without_a_sequence_number_are_released_right_away() {
+        let mut r = SequenceReorderer::new(4, None);
+        let mut pkt = packet(0, false);
+        pkt.context.sequence_number = None;
+        assert_eq!(r.push(7, pkt).len(), 1);
+    }
+
+    #[test]
+    fn waits_for_the_missing_packet() {
+        let mut r = SequenceReorderer::new(4, None);
+        assert_eq!(push(&mut r, 0, false), vec![0]);
+        assert!(push(&mut r, 2, false).is_empty());
+        assert!(push(&mut r, 3, false).is_empty());
+        assert_eq!(push(&mut r, 1, false), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn missing_packets_are_lost_once_the_window_is_full() {
+        let mut r = SequenceReorderer::new(2, None);
+        assert_eq!(push(&mut r, 0, false), vec![0]);
+        assert!(push(&mut r, 2, false).is_empty());
+        assert!(push(&mut r, 3, false).is_empty());
+        assert_eq!(push(&mut r, 4, false), vec![2, 3, 4]);
+        // Already released
+        assert!(push(&mut r, 3, false).is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut r = SequenceReorderer::new(4, None);
+        assert_eq!(push(&mut r, 0, false), vec![0]);
+        assert!(push(&mut r, 0, false).is_empty());
+    }
+
+    #[test]
+    fn large_backwards_jump_restarts_the_sequence() {
+        let mut r = SequenceReorderer::new(2, None);
+        for seqnum in 10..13 {
+            assert_eq!(push(&mut r, seqnum, false), vec![seqnum]);
+        }
+        assert!(push(&mut r, 14, false).is_empty());
+        assert_eq!(push(&mut r, 0, false), vec![14, 0]);
+        assert_eq!(push(&mut r, 1, false), vec![1]);
+    }
+
+    #[test]
+    fn start_event_restarts_the_sequence() {
+        let mut r = SequenceReorderer::new(4, Some("init"));
+        for seqnum in 0..3 {
+            assert_eq!(push(&mut r, seqnum, false), vec![seqnum]);
+        }
+        // Within the window, but the trace restarted
+        assert_eq!(push(&mut r, 0, true), vec![0]);
+        assert_eq!(push(&mut r, 1, false), vec![1]);
+    }
+
+    #[test]
+    fn small_backwards_jump_without_the_start_event_is_late() {
+        let mut r = SequenceReorderer::new(4, None);
+        for seqnum in 0..3 {
+            assert_eq!(push(&mut r, seqnum, false), vec![seqnum]);
+        }
+        assert!(push(&mut r, 0, true).is_empty());
+    }
+
+    #[test]
+    fn drain_releases_the_held_back_packets_in_order() {
+        let mut r = SequenceReorderer::new(4, None);
+        assert_eq!(push(&mut r, 0, false), vec![0]);
+        assert!(push(&mut r, 3, false).is_empty());
+        assert!(push(&mut r, 2, false).is_empty());
+        let tags: Vec<u64> = r.drain().into_iter().map(|(tag, _)| tag).collect();
+        assert_eq!(tags, vec![2, 3]);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fmt;
+use tokio_util::{
+    bytes::{Buf, BytesMut},
+    codec::Decoder,
+};
+use tracing::{debug, warn};
+
+/// The CTF packet header magic number
+const CTF_MAGIC: u32 = 0xC1FC1FC1;
+
+const MAGIC_SIZE: usize = 4;
+
+/// The default size of the largest packet waited for, in bytes
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// A packet decoder for byte streams that can contain garbage, i.e. a UART
+/// line with noise or a target that reset in the middle of a packet.
+///
+/// Bytes are skipped until the packet header magic number is found, and
+/// again after a packet fails to parse. This requires the packet header
+/// magic number field (the trace type's `magic-field-type` feature).
+///
+/// A packet still incomplete after `max_packet_size` bytes is considered
+/// garbage (i.e. a corrupted packet size field), so the decoder doesn't
+/// wait forever for it.
+pub struct ResyncDecoder<D> {
+    inner: D,
+    max_packet_size: usize,
+    discarded: usize,
+}
+
+impl<D> ResyncDecoder<D> {
+    pub fn new(inner: D, max_packet_size: usize) -> Self {
+        Self {
+            inner,
+            max_packet_size: max_packet_size.max(MAGIC_SIZE),
+            discarded: 0,
+        }
+    }
+
+    /// Skip the magic number at the start of the data, to look for the next packet
+    fn skip_magic(&mut self, src: &mut BytesMut) {
+        src.advance(MAGIC_SIZE);
+        self.discarded += MAGIC_SIZE;
+    }
+
+    /// Skip to the next magic number, returns false if more data is needed
+    fn align(&mut self, src: &mut BytesMut) -> bool {
+        match find_magic(src) {
+            Some(0) => true,
+            Some(offset) => {
+                src.advance(offset);
+                self.discarded += offset;
+                true
+            }
+            None => {
+                // Keep what could be the start of a magic number
+                let keep = src.len().min(MAGIC_SIZE - 1);
+                let skip = src.len() - keep;
+                src.advance(skip);
+                self.discarded += skip;
+                false
+            }
+        }
+    }
+}
+
+impl<D> Decoder for ResyncDecoder<D>
+where
+    D: Decoder,
+    D::Error: fmt::Display,
+{
+    type Item = D::Item;
+    type Error = D::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            if !self.align(src) {
+                return Ok(None);
+            }
+
+            match self.inner.decode(src) {
+                Ok(Some(item)) => {
+                    if self.discarded != 0 {
+                        warn!(
+                            bytes = self.discarded,
+                            "Discarded bytes to resynchronize on a packet"
+                        );
+                        self.discarded = 0;
+                    }
+                    return Ok(Some(item));
+                }
+                Ok(None) => {
+                    if src.len() < self.max_packet_size {
+                        return Ok(None);
+                    }
+                    debug!(
+                        max_packet_size = self.max_packet_size,
+                        "CTF packet exceeds the maximum packet size, resynchronizing"
+                    );
+                    self.skip_magic(src);
+                }
+                Err(e) => {
+                    debug!(%e, "Failed to parse CTF packet, resynchronizing");
+                    // Look for the next packet past this magic number
+                    if src.len() >= MAGIC_SIZE {
+                        self.skip_magic(src);
+                    } else {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let item = self.decode(src)?;
+        if item.is_none() && !src.is_empty() {
+            // Whatever is left is an incomplete packet
+            warn!(bytes = src.len(), "Discarding incomplete packet");
+            src.clear();
+        }
+        Ok(item)
+    }
+}
+
+fn find_magic(src: &[u8]) -> Option<usize> {
+    let le = CTF_MAGIC.to_le_bytes();
+    let be = CTF_MAGIC.to_be_bytes();
+    src.windows(MAGIC_SIZE).position(|w| w == le || w == be)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures, routing::RoutingDecoder};
+
+    /// Packets made of the magic number, a total length byte and a payload
+    struct TestDecoder;
+
+    impl Decoder for TestDecoder {
+        type Item = Vec<u8>;
+        type Error = String;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+            if src.len() <= MAGIC_SIZE {
+                return Ok(None);
+            }
+            assert_eq!(src[..MAGIC_SIZE], CTF_MAGIC.to_le_bytes());
+            let len = usize::from(src[MAGIC_SIZE]);
+            if len <= MAGIC_SIZE {
+                return Err(format!("Invalid packet length {len}"));
+            }
+            if src.len() < len {
+                return Ok(None);
+            }
+            Ok(Some(src.split_to(len)[MAGIC_SIZE + 1..].to_vec()))
+        }
+    }
+
+    fn packet(payload: &[u8]) -> Vec<u8> {
+        let mut pkt = CTF_MAGIC.to_le_bytes().to_vec();
+        pkt.push((MAGIC_SIZE + 1 + payload.len()) as u8);
+        pkt.extend_from_slice(payload);
+        pkt
+    }
+
+    fn decode_all(max_packet_size: usize, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut decoder = ResyncDecoder::new(TestDecoder, max_packet_size);
+        let mut buf = BytesMut::from(data);
+        let mut items = Vec::new();
+        while let Some(item) = decoder.decode_eof(&mut buf).unwrap() {
+            items.push(item);
+        }
+        assert!(buf.is_empty());
+        items
+    }
+
+    #[test]
+    fn skips_garbage_before_a_packet() {
+        let mut data = vec![0xAA; 7];
+        data.extend(packet(b"one"));
+        data.extend([0xC1, 0xFC]);
+        data.extend(packet(b"two"));
+        assert_eq!(
+            decode_all(64, &data),
+            vec![b"one".to_vec(), b"two".to_vec()]
+        );
+    }
+
+    #[test]
+    fn resyncs_after_a_parse_error() {
+        let mut data = CTF_MAGIC.to_le_bytes().to_vec();
+        data.push(0);
+        data.extend(packet(b"one"));
+        assert_eq!(decode_all(64, &data), vec![b"one".to_vec()]);
+    }
+
+    #[test]
+    fn resyncs_past_an_oversized_packet() {
+        let mut data = CTF_MAGIC.to_le_bytes().to_vec();
+        data.push(u8::MAX);
+        data.extend([0xAA; 16]);
+        data.extend(packet(b"one"));
+        assert_eq!(decode_all(16, &data), vec![b"one".to_vec()]);
+    }
+
+    #[test]
+    fn waits_for_a_packet_within_the_maximum_size() {
+        let pkt = packet(b"a longer payload");
+        let mut decoder = ResyncDecoder::new(TestDecoder, pkt.len());
+        let mut buf = BytesMut::from(&pkt[..pkt.len() - 1]);
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), pkt.len() - 1);
+        buf.extend_from_slice(&pkt[pkt.len() - 1..]);
+        assert_eq!(
+            decoder.decode(&mut buf).unwrap(),
+            Some(b"a longer payload".to_vec())
+        );
+    }
+
+    #[test]
+    fn discards_an_incomplete_packet_at_eof() {
+        let mut data = packet(b"one");
+        data.extend(&packet(b"two")[..6]);
+        assert_eq!(decode_all(64, &data), vec![b"one".to_vec()]);
+    }
+
+    #[test]
+    fn decodes_the_stream_after_garbage() {
+        let mut data = vec![0x55; 13];
+        data.extend_from_slice(fixtures::STREAM);
+        let inner = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        let mut decoder = ResyncDecoder::new(inner, DEFAULT_MAX_PACKET_SIZE);
+        let mut buf = BytesMut::from(data.as_slice());
+        let mut seqnums = Vec::new();
+        while let Some((_, pkt)) = decoder.decode_eof(&mut buf).unwrap() {
+            seqnums.push(pkt.context.sequence_number);
+        }
+        assert_eq!(seqnums, vec![Some(0), Some(1)]);
+    }
+}
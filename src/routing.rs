@@ -0,0 +1,405 @@
+use crate::send::ConfigId;
+use anyhow::anyhow;
+use auxon_sdk::api::Uuid;
+use barectf_parser::{Config as BarectfConfig, Packet, PacketDecoder, Parser};
+use tokio_util::{
+    bytes::{Buf, BytesMut},
+    codec::Decoder,
+};
+use tracing::{debug, info, warn};
+
+/// The CTF packet header magic number
+const CTF_MAGIC: u32 = 0xC1FC1FC1;
+
+const MAGIC_SIZE: usize = 4;
+const UUID_SIZE: usize = 16;
+
+/// A packet decoder that selects which barectf configuration to use based
+/// on the trace UUID found in the first packet header of the stream.
+///
+/// The selected configuration is used for the remainder of the stream.
+/// When no configuration's trace UUID matches, the first configuration without
+/// a trace UUID is used, and it's an error if they all have one.
+/// Decoded packets are tagged with the [`ConfigId`] of the configuration
+/// at the same index in the list of configurations; the first configuration
+/// is the one given to [`crate::Sender::new`], followed by those registered
+/// with [`crate::Sender::add_config`].
+///
+/// Reloaded configurations (see [`RoutingDecoder::reload`]) replace the
+/// current ones at the stream boundary: right away when nothing was decoded
+/// yet, otherwise starting with the packet containing the trace-start event.
+pub struct RoutingDecoder {
+    /// The id of the first configuration
+    first_config: ConfigId,
+    configs: Vec<(Option<Uuid>, BarectfConfig)>,
+    selected: Option<(ConfigId, PacketDecoder)>,
+    has_decoded: bool,
+    pending: Option<PendingReload>,
+}
+
+/// Reloaded configurations waiting for the trace to restart
+struct PendingReload {
+    decoder: Box<RoutingDecoder>,
+    start_event: String,
+    /// A copy of the caller's buffered data, from the next packet on
+    buffered: BytesMut,
+}
+
+impl RoutingDecoder {
+    pub fn new(configs: &[BarectfConfig]) -> Result<Self, anyhow::Error> {
+        Self::with_first_config(ConfigId::default(), configs)
+    }
+
+    /// Create a decoder for configurations registered with
+    /// [`crate::Sender::add_configs`], starting at `first_config`
+    pub fn with_first_config(
+        first_config: ConfigId,
+        configs: &[BarectfConfig],
+    ) -> Result<Self, anyhow::Error> {
+        if configs.is_empty() {
+            return Err(anyhow!("Missing barectf configuration"));
+        }
+        let mut dec = Self {
+            first_config,
+            configs: configs
+                .iter()
+                .map(|cfg| (cfg.trace.typ.uuid, cfg.clone()))
+                .collect(),
+            selected: None,
+            has_decoded: false,
+            pending: None,
+        };
+        // Nothing to route with a single configuration
+        if configs.len() == 1 {
+            dec.select(0)?;
+        }
+        Ok(dec)
+    }
+
+    /// Switch to reloaded configurations, registered with [`crate::Sender::add_configs`].
+    ///
+    /// They're used right away if nothing was decoded yet or there's no
+    /// `start_event`, otherwise once a packet containing the `start_event`
+    /// is received, that packet included.
+    pub fn reload(
+        &mut self,
+        first_config: ConfigId,
+        configs: &[BarectfConfig],
+        start_event: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let decoder = Self::with_first_config(first_config, configs)?;
+        match start_event.filter(|_| self.has_decoded) {
+            Some(start_event) => {
+                info!("barectf configuration changed, it will be used when the trace restarts");
+                self.pending = Some(PendingReload {
+                    decoder: Box::new(decoder),
+                    start_event: start_event.to_owned(),
+                    buffered: BytesMut::new(),
+                });
+            }
+            None => {
+                debug!("Using the reloaded barectf configuration");
+                *self = decoder;
+            }
+        }
+        Ok(())
+    }
+
+    /// The configuration used for the stream, once known
+    pub fn selected_config(&self) -> Option<ConfigId> {
+        self.selected.as_ref().map(|(id, _)| *id)
+    }
+
+    fn select(&mut self, idx: usize) -> Result<(), anyhow::Error> {
+        let decoder = Parser::new(&self.configs[idx].1)?.into_packet_decoder();
+        self.selected = Some((ConfigId(self.first_config.0 + idx), decoder));
+        Ok(())
+    }
+
+    /// Find the configuration whose trace UUID matches the packet header,
+    /// falling back to the first configuration without a trace UUID.
+    ///
+    /// The UUID field follows the magic number field when present,
+    /// otherwise it's the first field of the packet header.
+    fn route(&self, header: &[u8]) -> Result<usize, anyhow::Error> {
+        let has_magic = header[..MAGIC_SIZE] == CTF_MAGIC.to_le_bytes()
+            || header[..MAGIC_SIZE] == CTF_MAGIC.to_be_bytes();
+        let offset = if has_magic { MAGIC_SIZE } else { 0 };
+        let uuid = Uuid::from_slice(&header[offset..offset + UUID_SIZE])?;
+
+        if let Some(idx) = self
+            .configs
+            .iter()
+            .position(|(cfg_uuid, _)| *cfg_uuid == Some(uuid))
+        {
+            debug!(config = idx, %uuid, "Selected barectf configuration by trace UUID");
+            return Ok(idx);
+        }
+
+        match self
+            .configs
+            .iter()
+            .position(|(cfg_uuid, _)| cfg_uuid.is_none())
+        {
+            Some(idx) => {
+                warn!(
+                    config = idx,
+                    %uuid,
+                    "No barectf configuration matches the stream's trace UUID, using the first configuration without a trace UUID"
+                );
+                Ok(idx)
+            }
+            None => Err(anyhow!(
+                "No barectf configuration matches the stream's trace UUID {}",
+                uuid
+            )),
+        }
+    }
+
+    fn is_pending_restart(&self, pkt: &Packet) -> bool {
+        self.pending
+            .as_ref()
+            .map(|p| {
+                pkt.events
+                    .iter()
+                    .any(|ev| ev.name.as_str() == p.start_event)
+            })
+            .unwrap_or(false)
+    }
+
+    fn decode_current(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<(ConfigId, Packet)>, anyhow::Error> {
+        if self.selected.is_none() {
+            if src.len() < MAGIC_SIZE + UUID_SIZE {
+                return Ok(None);
+            }
+            let idx = self.route(src)?;
+            self.select(idx)?;
+        }
+
+        let (id, decoder) = self.selected.as_mut().expect("Configuration is selected");
+        let item = decoder.decode(src)?.map(|pkt| (*id, pkt));
+        self.has_decoded |= item.is_some();
+        Ok(item)
+    }
+}
+
+impl Decoder for RoutingDecoder {
+    type Item = (ConfigId, Packet);
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.pending.is_none() {
+            return self.decode_current(src);
+        }
+
+        // Keep a copy of the data appended since the last call, so the packet the
+        // trace restarts with can be decoded again with the reloaded configuration.
+        // The caller only appends to the buffer, unless it discarded what was buffered.
+        if let Some(pending) = self.pending.as_mut() {
+            if src.len() < pending.buffered.len() {
+                pending.buffered.clear();
+            }
+            let copied = pending.buffered.len();
+            pending.buffered.extend_from_slice(&src[copied..]);
+        }
+
+        let len = src.len();
+        let item = match self.decode_current(src) {
+            Ok(None) => return Ok(None),
+            Ok(Some(item)) if !self.is_pending_restart(&item.1) => Some(item),
+            // Data the current configuration can't parse is from the new firmware
+            _ => None,
+        };
+        match item {
+            Some(item) => {
+                let pending = self.pending.as_mut().expect("Reload is pending");
+                pending.buffered.advance(len - src.len());
+                Ok(Some(item))
+            }
+            None => {
+                info!("Trace restarted, using the reloaded barectf configuration");
+                let pending = self.pending.take().expect("Reload is pending");
+                *src = pending.buffered;
+                *self = *pending.decoder;
+                self.decode_current(src)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{convert::EventExt, fixtures};
+
+    fn config_with_uuid(uuid: Option<Uuid>) -> BarectfConfig {
+        let mut cfg = fixtures::config();
+        cfg.trace.typ.uuid = uuid;
+        cfg
+    }
+
+    fn decode_all(dec: &mut RoutingDecoder) -> Result<Vec<(ConfigId, Packet)>, anyhow::Error> {
+        let mut buf = BytesMut::from(fixtures::STREAM);
+        let mut pkts = Vec::new();
+        while let Some(item) = dec.decode(&mut buf)? {
+            pkts.push(item);
+        }
+        Ok(pkts)
+    }
+
+    fn assert_fixture_packets(pkts: &[(ConfigId, Packet)], expected_config: ConfigId) {
+        let expected = fixtures::packets();
+        assert_eq!(pkts.len(), expected.len());
+        for ((cfg_id, pkt), expected) in pkts.iter().zip(expected.iter()) {
+            assert_eq!(*cfg_id, expected_config);
+            let attrs: Vec<_> = pkt.events.iter().map(|ev| ev.event_attrs()).collect();
+            let expected_attrs: Vec<_> =
+                expected.events.iter().map(|ev| ev.event_attrs()).collect();
+            assert_eq!(attrs, expected_attrs);
+        }
+    }
+
+    #[test]
+    fn missing_config() {
+        assert!(RoutingDecoder::new(&[]).is_err());
+    }
+
+    #[test]
+    fn single_config_is_selected_up_front() {
+        let mut dec = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        assert_eq!(dec.selected_config(), Some(ConfigId(0)));
+        let pkts = decode_all(&mut dec).unwrap();
+        assert_fixture_packets(&pkts, ConfigId(0));
+    }
+
+    #[test]
+    fn routes_by_trace_uuid() {
+        let configs = [config_with_uuid(Some(Uuid::new_v4())), fixtures::config()];
+        let mut dec = RoutingDecoder::new(&configs).unwrap();
+        assert_eq!(dec.selected_config(), None);
+        let pkts = decode_all(&mut dec).unwrap();
+        assert_eq!(dec.selected_config(), Some(ConfigId(1)));
+        assert_fixture_packets(&pkts, ConfigId(1));
+    }
+
+    #[test]
+    fn waits_for_the_trace_uuid() {
+        let configs = [config_with_uuid(Some(Uuid::new_v4())), fixtures::config()];
+        let mut dec = RoutingDecoder::new(&configs).unwrap();
+        let mut buf = BytesMut::from(&fixtures::STREAM[..MAGIC_SIZE + UUID_SIZE - 1]);
+        assert!(dec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(dec.selected_config(), None);
+    }
+
+    #[test]
+    fn falls_back_to_a_config_without_a_trace_uuid() {
+        let configs = [
+            config_with_uuid(Some(Uuid::new_v4())),
+            config_with_uuid(None),
+            config_with_uuid(Some(Uuid::new_v4())),
+        ];
+        let mut dec = RoutingDecoder::new(&configs).unwrap();
+        let pkts = decode_all(&mut dec).unwrap();
+        assert_fixture_packets(&pkts, ConfigId(1));
+    }
+
+    #[test]
+    fn no_matching_trace_uuid() {
+        let configs = [
+            config_with_uuid(Some(Uuid::new_v4())),
+            config_with_uuid(Some(Uuid::new_v4())),
+        ];
+        let mut dec = RoutingDecoder::new(&configs).unwrap();
+        let err = decode_all(&mut dec).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("79e49040-21b5-42d4-a83b-646f78666b62"));
+        assert_eq!(dec.selected_config(), None);
+    }
+
+    fn decode_next(dec: &mut RoutingDecoder, buf: &mut BytesMut) -> (ConfigId, Packet) {
+        dec.decode(buf).unwrap().expect("A packet")
+    }
+
+    #[test]
+    fn reload_before_decoding_is_used_right_away() {
+        let mut dec = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        dec.reload(ConfigId(1), &[fixtures::config()], Some("init"))
+            .unwrap();
+        let pkts = decode_all(&mut dec).unwrap();
+        assert_fixture_packets(&pkts, ConfigId(1));
+    }
+
+    #[test]
+    fn reload_is_used_from_the_restart_packet() {
+        let mut dec = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        let mut buf = BytesMut::from(fixtures::STREAM);
+        buf.extend_from_slice(fixtures::STREAM);
+
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(0));
+        dec.reload(ConfigId(1), &[fixtures::config()], Some("init"))
+            .unwrap();
+
+        // The shutdown packet is still from the running trace
+        let (cfg_id, pkt) = decode_next(&mut dec, &mut buf);
+        assert_eq!(cfg_id, ConfigId(0));
+        assert_eq!(pkt.events[0].name.as_str(), "shutdown");
+
+        // The trace restarts with the init event
+        let (cfg_id, pkt) = decode_next(&mut dec, &mut buf);
+        assert_eq!(cfg_id, ConfigId(1));
+        assert_eq!(pkt.events[0].name.as_str(), "init");
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(1));
+        assert!(dec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reload_without_a_start_event_is_used_right_away() {
+        let mut dec = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        let mut buf = BytesMut::from(fixtures::STREAM);
+
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(0));
+        dec.reload(ConfigId(1), &[fixtures::config()], None)
+            .unwrap();
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(1));
+    }
+
+    #[test]
+    fn pending_reload_waits_for_more_data() {
+        let mut dec = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        let mut buf = BytesMut::from(fixtures::STREAM);
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(0));
+        dec.reload(ConfigId(1), &[fixtures::config()], Some("init"))
+            .unwrap();
+
+        let mut partial = buf.split_to(fixtures::PACKET_SIZE / 2);
+        assert!(dec.decode(&mut partial).unwrap().is_none());
+        assert_eq!(partial.len(), fixtures::PACKET_SIZE / 2);
+        partial.unsplit(buf);
+        assert_eq!(decode_next(&mut dec, &mut partial).0, ConfigId(0));
+    }
+
+    #[test]
+    fn restart_packet_arriving_in_chunks() {
+        let mut dec = RoutingDecoder::new(&[fixtures::config()]).unwrap();
+        let mut buf = BytesMut::from(fixtures::STREAM);
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(0));
+        assert_eq!(decode_next(&mut dec, &mut buf).0, ConfigId(0));
+        dec.reload(ConfigId(1), &[fixtures::config()], Some("init"))
+            .unwrap();
+
+        let mut pkts = Vec::new();
+        for chunk in fixtures::STREAM.chunks(100) {
+            buf.extend_from_slice(chunk);
+            while let Some(item) = dec.decode(&mut buf).unwrap() {
+                pkts.push(item);
+            }
+        }
+        assert_fixture_packets(&pkts, ConfigId(1));
+        assert!(buf.is_empty());
+    }
+}
@@ -1,9 +1,15 @@
 use crate::{
     convert::{ClockExt, EventExt, TimelineExt},
-    HasCommonConfig,
+    merge::{PacketMerger, DEFAULT_MERGE_WINDOW},
+    metrics::{Metrics, MetricsOptions, METRICS_EVENT_NAME},
+    output::{event_timestamp_ns, Output},
+    pairs::{parse_event_pairs, PairMatch, PairMatcher},
+    sink::Sink,
+    HasCommonConfig, TraceValidation,
 };
+use anyhow::anyhow;
 use auxon_sdk::{
-    api::{AttrKey, AttrVal, TimelineId, Uuid},
+    api::{AttrKey, AttrVal, Nanoseconds, TimelineId, Uuid},
     plugin_utils::ingest::Config,
 };
 use barectf_parser::{
@@ -12,37 +18,201 @@ use barectf_parser::{
 use fxhash::FxHashMap;
 use internment::Intern;
 use std::collections::{hash_map::Entry, HashMap};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
-pub struct Sender<C: HasCommonConfig> {
-    client: auxon_sdk::plugin_utils::ingest::Client,
-    common_timeline_attrs: Vec<(AttrKey, AttrVal)>,
+pub struct Sender<C: HasCommonConfig, S: Sink = Output> {
+    sink: S,
+    common_timeline_attrs: HashMap<AttrKey, AttrVal>,
     _config: Config<C>,
-    known_timelines: HashMap<StreamId, TimelineId>,
+    known_timelines: HashMap<StreamKey, TimelineId>,
     current_timeline: Option<TimelineId>,
+    /// The next event ordering of each timeline, kept across restarts and reconnects
+    orderings: HashMap<TimelineId, u128>,
     start_event: Option<Intern<String>>,
+    configs: FxHashMap<ConfigId, TraceConfig>,
+    next_config: usize,
+    /// The first configuration of each registration, the initial configurations
+    /// and each reload, see [`Sender::add_configs`]
+    config_generations: Vec<ConfigId>,
+    streams_state: FxHashMap<StreamKey, StreamState>,
+    /// Packets held back for reordering, with the configuration they were decoded with
+    merger: Option<PacketMerger<(SourceId, ConfigId)>>,
+    trace_validation: TraceValidation,
+    sources: Vec<SourceState>,
+    spans: Option<PairMatcher<TimelineId, OpenSpan>>,
+    next_nonce: i64,
+    context_switch: Option<ContextSwitch>,
+    task_timelines: HashMap<(StreamKey, String), TimelineId>,
+    /// The last event timestamp of each timeline, for context switch interactions
+    last_timestamps: HashMap<TimelineId, u64>,
+    metrics: Option<Metrics>,
+}
+
+type StreamName = Intern<String>;
+
+/// The default event attribute holding the name of the task switched to
+const DEFAULT_CONTEXT_SWITCH_TASK_FIELD: &str = "task";
+
+/// The CTF packet header magic number
+const CTF_MAGIC: u32 = 0xC1FC1FC1;
+
+/// The name of the event marking where a source reconnected
+const RECONNECT_EVENT_NAME: &str = "modality_barectf.reconnect";
+
+/// Identifies where packets came from (a stream file, a connection, etc).
+/// Streams from different sources are kept on separate timelines.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SourceId(usize);
+
+type StreamKey = (SourceId, StreamId);
+
+/// Identifies one of the barectf configurations registered with the sender.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ConfigId(pub(crate) usize);
+
+/// The parts of a barectf configuration needed to send its packets
+struct TraceConfig {
+    trace_uuid: Option<Uuid>,
     clock_uuids: FxHashMap<StreamName, Uuid>,
     timestamp_field_types: FxHashMap<StreamName, UnsignedIntegerFieldType>,
-    streams_state: FxHashMap<StreamId, StreamState>,
+    timeline_attrs: Vec<(AttrKey, AttrVal)>,
 }
 
-type StreamName = Intern<String>;
+struct SourceState {
+    timeline_attrs: Vec<(AttrKey, AttrVal)>,
+    /// The configuration of the source's last packet
+    config: ConfigId,
+    /// Whether the source holds on to its configuration, until it's removed.
+    /// The default source only does once it has a packet, other collectors
+    /// register their own sources.
+    in_use: bool,
+}
+
+/// How to recognize context switches, for per-task timelines
+struct ContextSwitch {
+    event: Intern<String>,
+    task_field: String,
+}
+
+/// A begin event waiting for its end event, when synthesizing spans
+struct OpenSpan {
+    nonce: i64,
+    timestamp_ns: Option<u64>,
+}
 
 struct StreamState {
     timestamp_tracker: Option<TrackingInstant>,
     clock_attrs: Vec<(AttrKey, AttrVal)>,
     event_count: u64,
     packet_seqnum: Option<u64>,
-    event_ordering: u128,
+    validation_attrs: Vec<(AttrKey, AttrVal)>,
+    /// The timeline of the task running on the stream, after a context switch
+    task_timeline: Option<TimelineId>,
 }
 
-impl<C: HasCommonConfig> Sender<C> {
+impl<C: HasCommonConfig, S: Sink> Sender<C, S> {
+    /// Create a sender for the given sink, usually an [`Output`]
     pub fn new(
-        client: auxon_sdk::plugin_utils::ingest::Client,
+        sink: S,
         bctf_config: &BarectfConfig,
-        mut common_timeline_attrs: HashMap<AttrKey, AttrVal>,
+        common_timeline_attrs: HashMap<AttrKey, AttrVal>,
         config: Config<C>,
-    ) -> Self {
+    ) -> Result<Self, anyhow::Error> {
+        let common = config.plugin.common_config();
+
+        let start_event = common
+            .start_event
+            .as_ref()
+            .map(|ev| Intern::new(ev.clone()));
+
+        let trace_validation = common.trace_validation.unwrap_or_default();
+
+        let merger = if common.merge_streams.unwrap_or(false) {
+            let window = common.merge_window.unwrap_or(DEFAULT_MERGE_WINDOW);
+            debug!(window, "Merging streams by packet timestamp");
+            Some(PacketMerger::new(window))
+        } else {
+            None
+        };
+
+        let spans = if common.synthesize_spans.unwrap_or(false) {
+            let pairs = parse_event_pairs(&common.event_pairs)?;
+            if pairs.is_empty() {
+                warn!("Span synthesis is enabled but no event pairs are configured");
+            }
+            debug!(pairs = pairs.len(), "Synthesizing spans from event pairs");
+            Some(PairMatcher::new(pairs))
+        } else {
+            None
+        };
+
+        let context_switch = common.context_switch_event.as_ref().map(|ev| {
+            let task_field = common
+                .context_switch_task_field
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONTEXT_SWITCH_TASK_FIELD.to_owned());
+            debug!(event = ev, task_field, "Using per-task timelines");
+            ContextSwitch {
+                event: Intern::new(ev.clone()),
+                task_field,
+            }
+        });
+
+        let metrics = if common.metrics.unwrap_or(false)
+            || common.metrics_packet_interval.is_some()
+            || common.metrics_interval.is_some()
+        {
+            let interval_ns = common
+                .metrics_interval
+                .as_deref()
+                .map(humantime::parse_duration)
+                .transpose()
+                .map_err(|e| anyhow!("Invalid metrics-interval. {}", e))?
+                .map(|d| d.as_nanos() as u64);
+            let options = MetricsOptions {
+                packet_interval: common.metrics_packet_interval.filter(|n| *n != 0),
+                interval_ns: interval_ns.filter(|ns| *ns != 0),
+            };
+            debug!(?options, "Sending metrics summary events");
+            Some(Metrics::new(options))
+        } else {
+            None
+        };
+
+        let mut sender = Self {
+            sink,
+            common_timeline_attrs,
+            _config: config,
+            known_timelines: Default::default(),
+            current_timeline: None,
+            orderings: Default::default(),
+            start_event,
+            configs: FxHashMap::default(),
+            next_config: 0,
+            config_generations: vec![ConfigId::default()],
+            streams_state: FxHashMap::default(),
+            merger,
+            trace_validation,
+            // The default source doesn't have any additional attributes
+            sources: vec![SourceState {
+                timeline_attrs: Vec::new(),
+                config: ConfigId::default(),
+                in_use: false,
+            }],
+            spans,
+            next_nonce: 0,
+            context_switch,
+            task_timelines: Default::default(),
+            last_timestamps: Default::default(),
+            metrics,
+        };
+        sender.add_config(bctf_config);
+        Ok(sender)
+    }
+
+    /// Register an additional barectf configuration, used by sources
+    /// whose packets were decoded with it (see [`crate::routing::RoutingDecoder`])
+    pub fn add_config(&mut self, bctf_config: &BarectfConfig) -> ConfigId {
         let mut clock_uuids = FxHashMap::default();
         for (clock_name, clock) in bctf_config.trace.typ.clock_types.iter() {
             // Make sure we have a stable clock UUID for time-domain
@@ -62,67 +232,413 @@ impl<C: HasCommonConfig> Sender<C> {
             );
         }
 
+        let mut timeline_attrs = self.common_timeline_attrs.clone();
         for (k, v) in bctf_config.trace.timeline_attrs() {
-            common_timeline_attrs.insert(k, v);
+            timeline_attrs.insert(k, v);
         }
 
-        let start_event = config
-            .plugin
-            .common_config()
-            .start_event
-            .as_ref()
-            .map(|ev| Intern::new(ev.clone()));
+        let id = ConfigId(self.next_config);
+        self.next_config += 1;
+        self.configs.insert(
+            id,
+            TraceConfig {
+                trace_uuid: bctf_config.trace.typ.uuid,
+                clock_uuids,
+                timestamp_field_types,
+                timeline_attrs: timeline_attrs.into_iter().collect(),
+            },
+        );
+        id
+    }
 
-        Self {
-            client,
-            common_timeline_attrs: common_timeline_attrs.into_iter().collect(),
-            _config: config,
-            known_timelines: Default::default(),
-            current_timeline: None,
-            start_event,
-            clock_uuids,
-            timestamp_field_types,
-            streams_state: FxHashMap::default(),
+    /// Register reloaded barectf configurations, returning the id of the first one.
+    ///
+    /// Sources switch to them when their packets decoded with them arrive
+    /// (see [`crate::routing::RoutingDecoder::reload`]), which resets their
+    /// stream state; existing timelines are reused. The previous configurations
+    /// stay registered until no source uses them anymore.
+    pub fn add_configs(&mut self, bctf_configs: &[BarectfConfig]) -> ConfigId {
+        let first = ConfigId(self.next_config);
+        self.config_generations.push(first);
+        for cfg in bctf_configs.iter() {
+            self.add_config(cfg);
         }
+        info!(
+            configs = bctf_configs.len(),
+            "Registered reloaded barectf configuration"
+        );
+        self.drop_unused_configs();
+        first
+    }
+
+    /// The first configuration of the registration a configuration is part of
+    fn config_generation(&self, config: ConfigId) -> ConfigId {
+        self.config_generations
+            .iter()
+            .rev()
+            .find(|first| first.0 <= config.0)
+            .copied()
+            .unwrap_or_default()
     }
 
-    pub async fn close(self) -> Result<(), anyhow::Error> {
-        let mut client = self.client;
-        client.flush().await?;
+    /// Forget the configurations registered before the oldest one still in use.
+    ///
+    /// Sources only move on to newer configurations, so a registration older
+    /// than every source's current configuration and held back packet is unused.
+    fn drop_unused_configs(&mut self) {
+        let latest = *self
+            .config_generations
+            .last()
+            .expect("The initial configurations are registered");
+        let held = self
+            .merger
+            .iter()
+            .flat_map(|merger| merger.tags())
+            .map(|(_, config)| *config);
+        let oldest = self
+            .sources
+            .iter()
+            .filter(|state| state.in_use)
+            .map(|state| state.config)
+            .chain(held)
+            .map(|config| self.config_generation(config))
+            .min()
+            .unwrap_or(latest);
 
-        if let Ok(status) = client.status().await {
+        let count = self.configs.len();
+        self.configs.retain(|id, _| id.0 >= oldest.0);
+        self.config_generations.retain(|first| first.0 >= oldest.0);
+        if self.configs.len() != count {
             debug!(
-                events_received = status.events_received,
-                events_written = status.events_written,
-                events_pending = status.events_pending,
-                "Ingest status"
+                configs = count - self.configs.len(),
+                "Dropped barectf configurations no source uses anymore"
             );
         }
+    }
+
+    /// Mark the boundary of a source that reconnected, i.e. after the
+    /// target rebooted or the connection dropped.
+    ///
+    /// The source's stream state is reset like when a trace restart is detected.
+    /// When `marker_event` is set, a marker event is sent on each of the
+    /// source's timelines.
+    pub async fn handle_source_reconnect(
+        &mut self,
+        source: SourceId,
+        marker_event: bool,
+    ) -> Result<(), anyhow::Error> {
+        // Everything from before the reconnect goes first
+        self.flush_merger().await?;
+
+        if marker_event {
+            let timelines: Vec<TimelineId> = self
+                .known_timelines
+                .iter()
+                .filter(|((src, _), _)| *src == source)
+                .map(|(_, tl_id)| *tl_id)
+                .collect();
+            for tl_id in timelines.into_iter() {
+                if self.current_timeline != Some(tl_id) {
+                    self.sink.switch_timeline(tl_id).await?;
+                    self.current_timeline = Some(tl_id);
+                }
+                let ordering = next_ordering(&mut self.orderings, tl_id);
+                let attrs: Vec<(&str, AttrVal)> = Vec::new();
+                self.sink
+                    .send_event(RECONNECT_EVENT_NAME, ordering, attrs)
+                    .await?;
+            }
+        }
 
+        self.reset_source_state(source);
+        Ok(())
+    }
+
+    /// Forget a source's stream state (timestamp rollover tracking, counters)
+    /// and its open spans, its timelines are kept
+    fn reset_source_state(&mut self, source: SourceId) {
+        self.streams_state.retain(|(src, _), _| *src != source);
+        self.clear_source_spans(source);
+    }
+
+    /// Forget the begin events waiting for their end event on a source's
+    /// timelines, they won't be ended after a restart
+    fn clear_source_spans(&mut self, source: SourceId) {
+        if let Some(spans) = self.spans.as_mut() {
+            let task_timelines = self
+                .task_timelines
+                .iter()
+                .map(|((stream_key, _), tl_id)| (stream_key, tl_id));
+            for ((src, _), tl_id) in self.known_timelines.iter().chain(task_timelines) {
+                if *src == source {
+                    spans.clear_timeline(tl_id);
+                }
+            }
+        }
+    }
+
+    /// Send any packets held back for reordering
+    async fn flush_merger(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(mut merger) = self.merger.take() {
+            let res = async {
+                while let Some(((source, config), pkt)) = merger.pop() {
+                    self.send_packet(source, config, &pkt).await?;
+                }
+                Ok::<(), anyhow::Error>(())
+            }
+            .await;
+            self.merger = Some(merger);
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Register a new packet source, the given timeline attributes are
+    /// added to each of its timelines
+    pub fn add_source(&mut self, timeline_attrs: Vec<(AttrKey, AttrVal)>) -> SourceId {
+        let id = SourceId(self.sources.len());
+        // New sources decode with the latest configurations
+        let config = *self
+            .config_generations
+            .last()
+            .expect("The initial configurations are registered");
+        self.sources.push(SourceState {
+            timeline_attrs,
+            config,
+            in_use: true,
+        });
+        id
+    }
+
+    /// Remove a source whose packets ended (i.e. a closed connection).
+    ///
+    /// Its held back packets and final metrics summaries are sent, then its
+    /// timelines and stream state are forgotten.
+    pub async fn remove_source(&mut self, source: SourceId) -> Result<(), anyhow::Error> {
+        self.flush_merger().await?;
+
+        let streams: Vec<StreamKey> = self
+            .known_timelines
+            .keys()
+            .filter(|(src, _)| *src == source)
+            .copied()
+            .collect();
+        for stream_key in streams.into_iter() {
+            self.send_stream_metrics(stream_key, true).await?;
+        }
+
+        self.reset_source_state(source);
+        let task_timelines = self
+            .task_timelines
+            .iter()
+            .filter(|((stream_key, _), _)| stream_key.0 == source)
+            .map(|(_, tl_id)| *tl_id);
+        let timelines: Vec<TimelineId> = self
+            .known_timelines
+            .iter()
+            .filter(|((src, _), _)| *src == source)
+            .map(|(_, tl_id)| *tl_id)
+            .chain(task_timelines)
+            .collect();
+        for tl_id in timelines.iter() {
+            self.orderings.remove(tl_id);
+            self.last_timestamps.remove(tl_id);
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.remove_timeline(*tl_id);
+            }
+        }
+        self.known_timelines.retain(|(src, _), _| *src != source);
+        self.task_timelines
+            .retain(|(stream_key, _), _| stream_key.0 != source);
+        if let Some(state) = self.sources.get_mut(source.0) {
+            state.timeline_attrs.clear();
+            state.in_use = false;
+        }
+        self.drop_unused_configs();
+        Ok(())
+    }
+
+    /// Handle a packet decoded with the given configuration.
+    ///
+    /// A source's stream state is reset when its configuration changes.
+    pub async fn handle_routed_packet(
+        &mut self,
+        source: SourceId,
+        config: ConfigId,
+        pkt: &Packet,
+    ) -> Result<(), anyhow::Error> {
+        if self.merger.is_none() {
+            return self.send_packet(source, config, pkt).await;
+        }
+
+        // Timestamps start over after a restart, send everything from before it first
+        // so post-restart packets don't sort ahead of the ones still in the window
+        if self.is_start_packet(pkt) {
+            self.flush_merger().await?;
+        }
+
+        let released = self
+            .merger
+            .as_mut()
+            .and_then(|merger| merger.push((source, config), pkt.clone()));
+        if let Some(((source, config), pkt)) = released {
+            self.send_packet(source, config, &pkt).await?;
+        }
+        Ok(())
+    }
+
+    /// The sink the timelines and events are sent to
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub async fn close(mut self) -> Result<(), anyhow::Error> {
+        // Drain any packets held back for reordering
+        if let Some(mut merger) = self.merger.take() {
+            while let Some(((source, config), pkt)) = merger.pop() {
+                self.send_packet(source, config, &pkt).await?;
+            }
+        }
+
+        let streams: Vec<StreamKey> = self.known_timelines.keys().copied().collect();
+        for stream_key in streams.into_iter() {
+            self.send_stream_metrics(stream_key, true).await?;
+        }
+
+        self.sink.flush().await
+    }
+
+    /// Send a metrics summary event on each of a stream's timelines
+    async fn send_stream_metrics(
+        &mut self,
+        stream_key: StreamKey,
+        is_final: bool,
+    ) -> Result<(), anyhow::Error> {
+        let Some(stream_timeline) = self.known_timelines.get(&stream_key).copied() else {
+            return Ok(());
+        };
+        let task_timelines = self
+            .task_timelines
+            .iter()
+            .filter(|((k, _), _)| *k == stream_key)
+            .map(|(_, tl_id)| *tl_id);
+        let timelines: Vec<TimelineId> = std::iter::once(stream_timeline)
+            .chain(task_timelines)
+            .collect();
+
+        for tl_id in timelines.into_iter() {
+            let Some(attrs) = self
+                .metrics
+                .as_ref()
+                .and_then(|m| m.summary_attrs(tl_id, is_final))
+            else {
+                continue;
+            };
+            if self.current_timeline != Some(tl_id) {
+                self.sink.switch_timeline(tl_id).await?;
+                self.current_timeline = Some(tl_id);
+            }
+            let ordering = next_ordering(&mut self.orderings, tl_id);
+            let attrs: Vec<_> = attrs.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+            self.sink
+                .send_event(METRICS_EVENT_NAME, ordering, attrs)
+                .await?;
+        }
+
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.reset_interval(stream_timeline);
+        }
         Ok(())
     }
 
     pub async fn handle_packet(&mut self, pkt: &Packet) -> Result<(), anyhow::Error> {
+        self.handle_source_packet(SourceId::default(), pkt).await
+    }
+
+    /// Handle a packet decoded with the source's current configuration
+    pub async fn handle_source_packet(
+        &mut self,
+        source: SourceId,
+        pkt: &Packet,
+    ) -> Result<(), anyhow::Error> {
+        let config = self.sources[source.0].config;
+        self.handle_routed_packet(source, config, pkt).await
+    }
+
+    /// Consider a source started if we have any streams from it
+    fn has_source_state(&self, source: SourceId) -> bool {
+        self.streams_state.keys().any(|(src, _)| *src == source)
+    }
+
+    /// Returns true if the packet contains the trace-start event
+    fn is_start_packet(&self, pkt: &Packet) -> bool {
+        self.start_event
+            .map(|start_event| pkt.events.iter().any(|event| event.name == start_event))
+            .unwrap_or(false)
+    }
+
+    async fn send_packet(
+        &mut self,
+        source: SourceId,
+        config: ConfigId,
+        pkt: &Packet,
+    ) -> Result<(), anyhow::Error> {
+        // The stream state (i.e. timestamp field types) belongs to the previous configuration
+        let switched_config = self.sources[source.0].config != config;
+        if switched_config && self.has_source_state(source) {
+            info!(
+                config = config.0,
+                "Source switched barectf configuration, resetting its stream state"
+            );
+            self.reset_source_state(source);
+        }
+        self.sources[source.0].config = config;
+        self.sources[source.0].in_use = true;
+        if switched_config {
+            self.drop_unused_configs();
+        }
+
         // Check for restarts
-        if let Some(start_event) = self.start_event {
-            // Consider started if we have any streams
-            if !self.streams_state.is_empty()
-                && pkt.events.iter().any(|event| event.name == start_event)
-            {
-                warn!("Trace restart detected");
-                self.streams_state.clear();
-                self.current_timeline = None;
-            }
+        if self.is_start_packet(pkt) && self.has_source_state(source) {
+            warn!("Trace restart detected");
+            self.reset_source_state(source);
+            self.current_timeline = None;
         }
 
-        let stream = match self.streams_state.entry(pkt.header.stream_id) {
+        let trace_cfg = &self.configs[&config];
+
+        // Check the packet belongs to the trace described by the configuration
+        let trace_uuid_mismatch = match (trace_cfg.trace_uuid, pkt.header.trace_uuid) {
+            (Some(expected), Some(actual)) if expected != actual => Some(actual),
+            _ => None,
+        };
+        let magic_mismatch = pkt.header.magic_number.filter(|m| *m != CTF_MAGIC);
+        let is_mismatched = self.trace_validation != TraceValidation::Ignore
+            && (trace_uuid_mismatch.is_some() || magic_mismatch.is_some());
+        if is_mismatched && self.trace_validation == TraceValidation::Error {
+            return Err(anyhow!(
+                "Packet from stream '{}' doesn't match the barectf configuration (trace UUID {}, magic {}). Check that the configuration matches the firmware that produced the trace",
+                pkt.header.stream_name,
+                pkt.header
+                    .trace_uuid
+                    .map(|id| id.to_string())
+                    .unwrap_or_else(|| "NA".to_owned()),
+                pkt.header
+                    .magic_number
+                    .map(|m| format!("{:#X}", m))
+                    .unwrap_or_else(|| "NA".to_owned()),
+            ));
+        }
+
+        let stream_key = (source, pkt.header.stream_id);
+        let stream = match self.streams_state.entry(stream_key) {
             Entry::Vacant(v) => {
                 // Use clock UUID as time domain
                 let clock_uuid = pkt
                     .header
                     .clock_type
                     .and_then(|c| c.uuid)
-                    .or_else(|| self.clock_uuids.get(&pkt.header.stream_name).cloned());
+                    .or_else(|| trace_cfg.clock_uuids.get(&pkt.header.stream_name).cloned());
                 let mut clock_attrs = if let Some(clock) = &pkt.header.clock_type {
                     clock.as_ref().timeline_attrs()
                 } else {
@@ -133,7 +649,7 @@ impl<C: HasCommonConfig> Sender<C> {
                 }
 
                 v.insert(StreamState {
-                    timestamp_tracker: self
+                    timestamp_tracker: trace_cfg
                         .timestamp_field_types
                         .get(&pkt.header.stream_name)
                         .map(TrackingInstant::new)
@@ -141,12 +657,47 @@ impl<C: HasCommonConfig> Sender<C> {
                     clock_attrs,
                     event_count: 0,
                     packet_seqnum: None,
-                    event_ordering: 0,
+                    validation_attrs: Vec::new(),
+                    task_timeline: None,
                 })
             }
             Entry::Occupied(o) => o.into_mut(),
         };
 
+        // Only report the first mismatch of each stream
+        let report_mismatch = is_mismatched && stream.validation_attrs.is_empty();
+        if report_mismatch {
+            if let Some(actual) = trace_uuid_mismatch {
+                warn!(
+                    stream = %pkt.header.stream_name,
+                    expected = ?trace_cfg.trace_uuid,
+                    %actual,
+                    "Packet trace UUID doesn't match the barectf configuration"
+                );
+                stream.validation_attrs.push((
+                    "modality_barectf.validation.trace_uuid_mismatch".into(),
+                    true.into(),
+                ));
+                if let Some(expected) = trace_cfg.trace_uuid {
+                    stream.validation_attrs.push((
+                        "modality_barectf.validation.expected_trace_uuid".into(),
+                        expected.to_string().into(),
+                    ));
+                }
+            }
+            if let Some(magic) = magic_mismatch {
+                warn!(
+                    stream = %pkt.header.stream_name,
+                    magic = format!("{:#X}", magic),
+                    "Packet magic number isn't the CTF magic number"
+                );
+                stream.validation_attrs.push((
+                    "modality_barectf.validation.magic_mismatch".into(),
+                    true.into(),
+                ));
+            }
+        }
+
         if let Some(events_discarded) = pkt.context.events_discarded {
             if events_discarded != 0 {
                 warn!(events_discarded, "Detected discarded events");
@@ -171,36 +722,53 @@ impl<C: HasCommonConfig> Sender<C> {
             }
         }
 
-        match self.known_timelines.get(&pkt.header.stream_id) {
+        match self.known_timelines.get(&stream_key) {
             Some(tl_id) => {
                 // It's a known timeline; switch to it if necessary
                 if self.current_timeline != Some(*tl_id) {
-                    self.client.switch_timeline(*tl_id).await?;
+                    self.sink.switch_timeline(*tl_id).await?;
                     self.current_timeline = Some(*tl_id);
                 }
+
+                if report_mismatch {
+                    let attrs: Vec<_> = stream
+                        .validation_attrs
+                        .iter()
+                        .map(|(k, v)| (k.as_ref(), v.clone()))
+                        .collect();
+                    self.sink
+                        .send_timeline_attrs(&pkt.header.stream_name, attrs)
+                        .await?;
+                }
             }
             None => {
                 // We've never seen this timeline before; allocate an
                 // id, and send its attrs.
                 let tl_id = TimelineId::allocate();
 
-                self.client.switch_timeline(tl_id).await?;
+                self.sink.switch_timeline(tl_id).await?;
                 self.current_timeline = Some(tl_id);
 
-                let attrs: Vec<_> = self
-                    .common_timeline_attrs
+                let attrs: Vec<_> = trace_cfg
+                    .timeline_attrs
                     .iter()
                     .chain(stream.clock_attrs.iter())
+                    .chain(self.sources[source.0].timeline_attrs.iter())
+                    .chain(stream.validation_attrs.iter())
                     .map(|(k, v)| (k.as_ref(), v.clone()))
                     //.chain(tl_key.timeline_attrs(&self.dbc))
                     .collect();
-                self.client
+                self.sink
                     .send_timeline_attrs(&pkt.header.stream_name, attrs)
                     .await?;
-                self.known_timelines.insert(pkt.header.stream_id, tl_id);
+                self.known_timelines.insert(stream_key, tl_id);
             }
         };
 
+        let stream_timeline = self.known_timelines[&stream_key];
+        // The time of the packet's last event, wherever it went
+        let mut packet_timestamp_ns = None;
+
         let pkt_header_attrs = pkt.header.event_attrs();
         let pkt_ctx_attrs = pkt.context.event_attrs();
 
@@ -229,19 +797,569 @@ impl<C: HasCommonConfig> Sender<C> {
                 stream.event_count.into(),
             ));
 
-            let ev_attrs: Vec<_> = event_attrs
+            let mut ev_attrs: Vec<_> = event_attrs
                 .iter()
                 .chain(pkt_header_attrs.iter())
                 .chain(pkt_ctx_attrs.iter())
                 .map(|(k, v)| (k.as_ref(), v.clone()))
                 .collect();
-            self.client
-                .send_event(&event.name, stream.event_ordering, ev_attrs)
+            let timestamp_ns = event_timestamp_ns(&ev_attrs);
+
+            // Move the stream's events onto the timeline of the task switched to
+            if let Some(switch) = self
+                .context_switch
+                .as_ref()
+                .filter(|s| event.name == s.event)
+            {
+                let task = ev_attrs
+                    .iter()
+                    .find(|(k, _)| *k == switch.task_field.as_str())
+                    .map(|(_, v)| v.to_string());
+                if let Some(task) = task {
+                    let prev_tl = stream.task_timeline.unwrap_or(stream_timeline);
+                    let task_tl = match self.task_timelines.get(&(stream_key, task.clone())) {
+                        Some(tl_id) => *tl_id,
+                        None => {
+                            let tl_id = TimelineId::allocate();
+                            self.sink.switch_timeline(tl_id).await?;
+                            self.current_timeline = Some(tl_id);
+
+                            let task_attrs = [
+                                (
+                                    AttrKey::from("modality_barectf.task.name"),
+                                    AttrVal::from(task.clone()),
+                                ),
+                                (
+                                    AttrKey::from("modality_barectf.task.stream_name"),
+                                    AttrVal::from(pkt.header.stream_name.to_string()),
+                                ),
+                            ];
+                            let attrs: Vec<_> = trace_cfg
+                                .timeline_attrs
+                                .iter()
+                                .chain(stream.clock_attrs.iter())
+                                .chain(self.sources[source.0].timeline_attrs.iter())
+                                .chain(stream.validation_attrs.iter())
+                                .chain(task_attrs.iter())
+                                .map(|(k, v)| (k.as_ref(), v.clone()))
+                                .collect();
+                            self.sink.send_timeline_attrs(&task, attrs).await?;
+                            self.task_timelines.insert((stream_key, task), tl_id);
+                            tl_id
+                        }
+                    };
+                    stream.task_timeline = Some(task_tl);
+
+                    // Interaction from the last event of the task switched from
+                    if task_tl != prev_tl {
+                        if let Some(remote_ns) = self.last_timestamps.get(&prev_tl) {
+                            ev_attrs.push(("interaction.remote_timeline_id", prev_tl.into()));
+                            ev_attrs.push((
+                                "interaction.remote_timestamp",
+                                Nanoseconds::from(*remote_ns).into(),
+                            ));
+                        }
+                    }
+                } else {
+                    warn!(
+                        event = %event.name,
+                        field = switch.task_field,
+                        "Context switch event doesn't have the task field"
+                    );
+                }
+            }
+
+            let event_tl = stream.task_timeline.unwrap_or(stream_timeline);
+            if self.current_timeline != Some(event_tl) {
+                self.sink.switch_timeline(event_tl).await?;
+                self.current_timeline = Some(event_tl);
+            }
+            if let (Some(_), Some(ns)) = (self.context_switch.as_ref(), timestamp_ns) {
+                self.last_timestamps.insert(event_tl, ns);
+            }
+            if let Some(metrics) = self.metrics.as_mut() {
+                metrics.record_event(event_tl, event.name, timestamp_ns);
+            }
+            if timestamp_ns.is_some() {
+                packet_timestamp_ns = timestamp_ns;
+            }
+
+            // Link the end event of a pair back to its begin event
+            if let (Some(spans), Some(tl_id)) = (self.spans.as_mut(), self.current_timeline) {
+                let nonce = self.next_nonce;
+                match spans.handle(&tl_id, &event.name, &ev_attrs, || OpenSpan {
+                    nonce,
+                    timestamp_ns,
+                }) {
+                    PairMatch::Begin => {
+                        self.next_nonce += 1;
+                        ev_attrs.push(("nonce", nonce.into()));
+                    }
+                    PairMatch::End(begin) => {
+                        if let (Some(begin_ns), Some(end_ns)) = (begin.timestamp_ns, timestamp_ns) {
+                            ev_attrs.push(("duration", end_ns.saturating_sub(begin_ns).into()));
+                        }
+                        // A context switch interaction takes precedence, events have one interaction
+                        let has_interaction = ev_attrs
+                            .iter()
+                            .any(|(k, _)| *k == "interaction.remote_timeline_id");
+                        if !has_interaction {
+                            ev_attrs.push(("interaction.remote_timeline_id", tl_id.into()));
+                            ev_attrs.push(("interaction.remote_nonce", begin.nonce.into()));
+                        }
+                    }
+                    PairMatch::Unpaired => (),
+                    PairMatch::UnmatchedEnd => {
+                        debug!(event = %event.name, "End event without a begin event");
+                    }
+                }
+            }
+
+            let ordering = next_ordering(&mut self.orderings, event_tl);
+            self.sink
+                .send_event(&event.name, ordering, ev_attrs)
                 .await?;
+        }
 
-            stream.event_ordering += 1;
+        if let Some(metrics) = self.metrics.as_mut() {
+            metrics.record_packet(
+                stream_timeline,
+                pkt.context.events_discarded,
+                packet_timestamp_ns,
+            );
+        }
+        if self
+            .metrics
+            .as_ref()
+            .map(|m| m.is_due(stream_timeline))
+            .unwrap_or(false)
+        {
+            self.send_stream_metrics(stream_key, false).await?;
         }
 
         Ok(())
     }
 }
+
+/// Take the next event ordering of a timeline
+fn next_ordering(orderings: &mut HashMap<TimelineId, u128>, timeline: TimelineId) -> u128 {
+    let next = orderings.entry(timeline).or_default();
+    let ordering = *next;
+    *next += 1;
+    ordering
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures,
+        sink::{MemorySink, Record},
+        CommonConfig,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[serde(default)]
+    struct TestConfig {
+        #[serde(flatten)]
+        common: CommonConfig,
+    }
+
+    impl HasCommonConfig for TestConfig {
+        fn common_config(&self) -> &CommonConfig {
+            &self.common
+        }
+    }
+
+    fn sender(common: CommonConfig) -> Sender<TestConfig, MemorySink> {
+        let mut config = Config::<TestConfig>::load("MODALITY_BARECTF_TEST_").unwrap();
+        config.plugin.common = common;
+        Sender::new(
+            MemorySink::new(),
+            &fixtures::config(),
+            Default::default(),
+            config,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_event_pair() {
+        let mut config = Config::<TestConfig>::load("MODALITY_BARECTF_TEST_").unwrap();
+        config.plugin.common.synthesize_spans = Some(true);
+        config.plugin.common.event_pairs = vec!["task_start".to_owned()];
+        let res = Sender::new(
+            MemorySink::new(),
+            &fixtures::config(),
+            Default::default(),
+            config,
+        );
+        let err = res.err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid event pair 'task_start'"));
+    }
+
+    #[test]
+    fn invalid_metrics_interval() {
+        let mut config = Config::<TestConfig>::load("MODALITY_BARECTF_TEST_").unwrap();
+        config.plugin.common.metrics_interval = Some("10 parsecs".to_owned());
+        let res = Sender::new(
+            MemorySink::new(),
+            &fixtures::config(),
+            Default::default(),
+            config,
+        );
+        let err = res.err().unwrap();
+        assert!(err.to_string().starts_with("Invalid metrics-interval."));
+    }
+
+    /// The timeline, name and ordering of each event
+    fn events(sender: &Sender<TestConfig, MemorySink>) -> Vec<(TimelineId, &str, u128)> {
+        sender
+            .sink
+            .records()
+            .iter()
+            .filter_map(|r| match r {
+                Record::Event {
+                    timeline,
+                    name,
+                    ordering,
+                    ..
+                } => Some((*timeline, name.as_str(), *ordering)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The timelines with their name, in the order they were first sent
+    fn timelines(sender: &Sender<TestConfig, MemorySink>) -> Vec<(TimelineId, &str)> {
+        sender
+            .sink
+            .records()
+            .iter()
+            .filter_map(|r| match r {
+                Record::Timeline { id, name, .. } => Some((*id, name.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// An attribute of each event with the given name
+    fn event_attr(
+        sender: &Sender<TestConfig, MemorySink>,
+        event: &str,
+        key: &str,
+    ) -> Vec<Option<AttrVal>> {
+        sender
+            .sink
+            .records()
+            .iter()
+            .filter_map(|r| match r {
+                Record::Event { name, attrs, .. } if name == event => {
+                    Some(attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn timestamps(sender: &Sender<TestConfig, MemorySink>) -> Vec<Option<u64>> {
+        sender
+            .sink
+            .records()
+            .iter()
+            .filter_map(|r| match r {
+                Record::Event { attrs, .. } => {
+                    let attrs: Vec<_> =
+                        attrs.iter().map(|(k, v)| (k.as_str(), v.clone())).collect();
+                    Some(event_timestamp_ns(&attrs))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn send_all(sender: &mut Sender<TestConfig, MemorySink>, pkts: &[&Packet]) {
+        for pkt in pkts.iter() {
+            sender.handle_packet(pkt).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_ordered_on_the_stream_timeline() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig::default());
+        send_all(&mut s, &[&pkts[0], &pkts[1]]).await;
+
+        let tls = timelines(&s);
+        assert_eq!(tls.len(), 1);
+        assert_eq!(tls[0].1, pkts[0].header.stream_name.as_str());
+        let tl = tls[0].0;
+        assert_eq!(
+            events(&s),
+            vec![
+                (tl, "init", 0),
+                (tl, "foobar", 1),
+                (tl, "floats", 2),
+                (tl, "enums", 3),
+                (tl, "arrays", 4),
+                (tl, "shutdown", 5),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn timestamps_are_in_nanoseconds() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig::default());
+        send_all(&mut s, &[&pkts[0], &pkts[1]]).await;
+        assert_eq!(
+            timestamps(&s),
+            (0..6).map(Some).collect::<Vec<Option<u64>>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn restart_keeps_the_timeline_and_its_ordering() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig {
+            start_event: Some("init".to_owned()),
+            ..Default::default()
+        });
+        send_all(&mut s, &[&pkts[0], &pkts[1], &pkts[0]]).await;
+
+        assert_eq!(timelines(&s).len(), 1);
+        let orderings: Vec<u128> = events(&s).iter().map(|(_, _, o)| *o).collect();
+        assert_eq!(orderings, (0..11).collect::<Vec<u128>>());
+
+        // Timestamp tracking and event counts start over
+        let expected: Vec<Option<u64>> = (0..6).chain(0..5).map(Some).collect();
+        assert_eq!(timestamps(&s), expected);
+        assert_eq!(
+            event_attr(&s, "init", "internal.barectf.event.count"),
+            vec![Some(AttrVal::from(1_u64)), Some(AttrVal::from(1_u64))]
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_marker_continues_the_ordering() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig::default());
+        let source = s.add_source(vec![("peer".into(), "a".into())]);
+        s.handle_source_packet(source, &pkts[0]).await.unwrap();
+        s.handle_source_reconnect(source, true).await.unwrap();
+        s.handle_source_packet(source, &pkts[0]).await.unwrap();
+
+        let evs = events(&s);
+        assert_eq!(evs.len(), 11);
+        assert_eq!(evs[5].1, RECONNECT_EVENT_NAME);
+        assert!(evs.iter().all(|(tl, _, _)| *tl == evs[0].0));
+        let orderings: Vec<u128> = evs.iter().map(|(_, _, o)| *o).collect();
+        assert_eq!(orderings, (0..11).collect::<Vec<u128>>());
+        // Stream state was reset, the timestamps start over
+        assert_eq!(timestamps(&s)[6], Some(0));
+    }
+
+    fn config_ids(sender: &Sender<TestConfig, MemorySink>) -> Vec<usize> {
+        let mut ids: Vec<usize> = sender.configs.keys().map(|id| id.0).collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn reloaded_configs_replace_the_unused_ones() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig::default());
+        let a = s.add_source(Vec::new());
+        let b = s.add_source(Vec::new());
+        s.handle_routed_packet(a, ConfigId(0), &pkts[0])
+            .await
+            .unwrap();
+
+        // Both sources still use the initial configuration
+        assert_eq!(s.add_configs(&[fixtures::config()]), ConfigId(1));
+        assert_eq!(config_ids(&s), vec![0, 1]);
+        s.handle_routed_packet(a, ConfigId(1), &pkts[1])
+            .await
+            .unwrap();
+        assert_eq!(config_ids(&s), vec![0, 1]);
+
+        // Sources added after a reload start with the reloaded configuration
+        s.remove_source(b).await.unwrap();
+        assert_eq!(config_ids(&s), vec![1]);
+        let c = s.add_source(Vec::new());
+        assert_eq!(s.add_configs(&[fixtures::config()]), ConfigId(2));
+        assert_eq!(config_ids(&s), vec![1, 2]);
+
+        s.handle_routed_packet(c, ConfigId(2), &pkts[0])
+            .await
+            .unwrap();
+        s.remove_source(a).await.unwrap();
+        assert_eq!(config_ids(&s), vec![2]);
+        assert_eq!(events(&s).len(), 11);
+    }
+
+    #[tokio::test]
+    async fn spans_link_the_end_event_to_its_begin_event() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig {
+            synthesize_spans: Some(true),
+            event_pairs: vec!["init:shutdown".to_owned()],
+            ..Default::default()
+        });
+        send_all(&mut s, &[&pkts[0], &pkts[1]]).await;
+
+        let tl = timelines(&s)[0].0;
+        assert_eq!(
+            event_attr(&s, "init", "nonce"),
+            vec![Some(AttrVal::from(0_i64))]
+        );
+        assert_eq!(
+            event_attr(&s, "shutdown", "interaction.remote_nonce"),
+            vec![Some(AttrVal::from(0_i64))]
+        );
+        assert_eq!(
+            event_attr(&s, "shutdown", "interaction.remote_timeline_id"),
+            vec![Some(AttrVal::from(tl))]
+        );
+        assert_eq!(
+            event_attr(&s, "shutdown", "duration"),
+            vec![Some(AttrVal::from(5_u64))]
+        );
+    }
+
+    #[tokio::test]
+    async fn restart_forgets_open_spans() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig {
+            start_event: Some("init".to_owned()),
+            synthesize_spans: Some(true),
+            event_pairs: vec!["shutdown:foobar".to_owned()],
+            ..Default::default()
+        });
+        send_all(&mut s, &[&pkts[1], &pkts[0]]).await;
+
+        assert_eq!(
+            event_attr(&s, "shutdown", "nonce"),
+            vec![Some(AttrVal::from(0_i64))]
+        );
+        assert_eq!(
+            event_attr(&s, "foobar", "interaction.remote_nonce"),
+            vec![None]
+        );
+    }
+
+    #[tokio::test]
+    async fn context_switch_moves_events_to_the_task_timeline() {
+        let pkts = fixtures::packets();
+        let task = pkts[0].events[1]
+            .event_attrs()
+            .into_iter()
+            .find(|(k, _)| k.as_ref() == "val")
+            .map(|(_, v)| v.to_string())
+            .unwrap();
+        let mut s = sender(CommonConfig {
+            context_switch_event: Some("foobar".to_owned()),
+            context_switch_task_field: Some("val".to_owned()),
+            ..Default::default()
+        });
+        send_all(&mut s, &[&pkts[0], &pkts[1]]).await;
+
+        let tls = timelines(&s);
+        assert_eq!(tls.len(), 2);
+        let (stream_tl, task_tl) = (tls[0].0, tls[1].0);
+        assert_eq!(tls[1].1, task);
+        assert_eq!(
+            events(&s),
+            vec![
+                (stream_tl, "init", 0),
+                (task_tl, "foobar", 0),
+                (task_tl, "floats", 1),
+                (task_tl, "enums", 2),
+                (task_tl, "arrays", 3),
+                (task_tl, "shutdown", 4),
+            ]
+        );
+
+        // Interaction from the last event of the stream's timeline
+        assert_eq!(
+            event_attr(&s, "foobar", "interaction.remote_timeline_id"),
+            vec![Some(AttrVal::from(stream_tl))]
+        );
+        assert_eq!(
+            event_attr(&s, "foobar", "interaction.remote_timestamp"),
+            vec![Some(AttrVal::from(Nanoseconds::from(0_u64)))]
+        );
+    }
+
+    #[tokio::test]
+    async fn context_switch_interaction_takes_precedence_over_the_span_interaction() {
+        let pkts = fixtures::packets();
+        let mut task_1 = pkts[0].clone();
+        task_1.context.sequence_number = Some(1);
+        let mut s = sender(CommonConfig {
+            context_switch_event: Some("foobar".to_owned()),
+            context_switch_task_field: Some("packet_context.sequence_number".to_owned()),
+            synthesize_spans: Some(true),
+            event_pairs: vec!["arrays:foobar".to_owned()],
+            ..Default::default()
+        });
+        // Switch to task 0, task 1, then back to task 0 where a span is open
+        send_all(&mut s, &[&pkts[0], &task_1, &pkts[0]]).await;
+
+        let tls = timelines(&s);
+        assert_eq!(tls.len(), 3);
+        let task_1_tl = tls[2].0;
+        let foobar = s
+            .sink
+            .records()
+            .iter()
+            .rev()
+            .find_map(|r| match r {
+                Record::Event { name, attrs, .. } if name == "foobar" => Some(attrs),
+                _ => None,
+            })
+            .unwrap();
+        let interactions: Vec<_> = foobar
+            .iter()
+            .filter(|(k, _)| k.starts_with("interaction."))
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect();
+        assert_eq!(interactions.len(), 2);
+        assert_eq!(
+            interactions[0],
+            ("interaction.remote_timeline_id", AttrVal::from(task_1_tl))
+        );
+        assert_eq!(interactions[1].0, "interaction.remote_timestamp");
+        assert!(foobar.iter().any(|(k, _)| k == "duration"));
+    }
+
+    #[tokio::test]
+    async fn metrics_interval_with_context_switches() {
+        let pkts = fixtures::packets();
+        let mut s = sender(CommonConfig {
+            context_switch_event: Some("foobar".to_owned()),
+            context_switch_task_field: Some("val".to_owned()),
+            metrics_interval: Some("1ns".to_owned()),
+            ..Default::default()
+        });
+        // The stream timeline only has the init event, the rest is on the task timeline
+        send_all(&mut s, &[&pkts[0]]).await;
+        assert!(event_attr(&s, METRICS_EVENT_NAME, "timestamp").is_empty());
+        send_all(&mut s, &[&pkts[1]]).await;
+
+        let tls = timelines(&s);
+        let summaries: Vec<TimelineId> = events(&s)
+            .into_iter()
+            .filter(|(_, name, _)| *name == METRICS_EVENT_NAME)
+            .map(|(tl, _, _)| tl)
+            .collect();
+        assert_eq!(summaries, vec![tls[0].0, tls[1].0]);
+        assert_eq!(
+            event_attr(&s, METRICS_EVENT_NAME, "modality_barectf.metrics.packets"),
+            vec![Some(AttrVal::from(2_u64)), Some(AttrVal::from(0_u64))]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use auxon_sdk::{
+    api::{AttrVal, TimelineId},
+    plugin_utils::ingest::Client,
+};
+use std::future::Future;
+use tracing::debug;
+
+/// Where the [`crate::Sender`] sends the converted timelines and events.
+///
+/// Implemented by the Modality ingest client, the offline
+/// [`crate::output::FileOutput`] and the [`MemorySink`].
+pub trait Sink {
+    /// Make the given timeline the current one, the following attributes
+    /// and events are for it
+    fn switch_timeline(
+        &mut self,
+        id: TimelineId,
+    ) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+
+    /// Set the name and attributes of the current timeline
+    fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+
+    /// Add an event to the current timeline
+    fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+
+    /// Make sure everything sent so far was delivered
+    fn flush(&mut self) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+}
+
+impl Sink for Client {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), anyhow::Error> {
+        Client::switch_timeline(self, id).await?;
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        Client::send_timeline_attrs(self, name, attrs).await?;
+        Ok(())
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        Client::send_event(self, name, ordering, attrs).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        Client::flush(self).await?;
+
+        if let Ok(status) = self.status().await {
+            debug!(
+                events_received = status.events_received,
+                events_written = status.events_written,
+                events_pending = status.events_pending,
+                "Ingest status"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Something a [`MemorySink`] received
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Timeline {
+        id: TimelineId,
+        name: String,
+        attrs: Vec<(String, AttrVal)>,
+    },
+    Event {
+        timeline: TimelineId,
+        name: String,
+        ordering: u128,
+        attrs: Vec<(String, AttrVal)>,
+    },
+}
+
+/// Keeps everything it receives in memory, in order
+#[derive(Clone, Debug, Default)]
+pub struct MemorySink {
+    records: Vec<Record>,
+    current_timeline: Option<TimelineId>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn into_records(self) -> Vec<Record> {
+        self.records
+    }
+
+    fn current_timeline(&self) -> Result<TimelineId, anyhow::Error> {
+        self.current_timeline
+            .ok_or_else(|| anyhow::anyhow!("No timeline selected"))
+    }
+}
+
+fn owned_attrs(attrs: Vec<(&str, AttrVal)>) -> Vec<(String, AttrVal)> {
+    attrs.into_iter().map(|(k, v)| (k.to_owned(), v)).collect()
+}
+
+impl Sink for MemorySink {
+    async fn switch_timeline(&mut self, id: TimelineId) -> Result<(), anyhow::Error> {
+        self.current_timeline = Some(id);
+        Ok(())
+    }
+
+    async fn send_timeline_attrs(
+        &mut self,
+        name: &str,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let id = self.current_timeline()?;
+        self.records.push(Record::Timeline {
+            id,
+            name: name.to_owned(),
+            attrs: owned_attrs(attrs),
+        });
+        Ok(())
+    }
+
+    async fn send_event(
+        &mut self,
+        name: &str,
+        ordering: u128,
+        attrs: Vec<(&str, AttrVal)>,
+    ) -> Result<(), anyhow::Error> {
+        let timeline = self.current_timeline()?;
+        self.records.push(Record::Event {
+            timeline,
+            name: name.to_owned(),
+            ordering,
+            attrs: owned_attrs(attrs),
+        });
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,1173 @@
+//! A reader for the CTF 1.8 TSDL metadata generated by barectf.
+//!
+//! The metadata is converted into the equivalent barectf effective-configuration
+//! so traces can be decoded when only the `metadata` file is available.
+//! Only the subset of TSDL that barectf generates is supported.
+
+use anyhow::{anyhow, bail, Error};
+use barectf_parser::Config as BarectfConfig;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+
+/// Magic number at the start of each packet of packetized metadata
+const PACKETIZED_METADATA_MAGIC: u32 = 0x75D11D57;
+
+/// Environment entries barectf adds to the metadata itself
+const GENERATED_ENV_KEYS: &[&str] = &[
+    "domain",
+    "tracer_name",
+    "tracer_major",
+    "tracer_minor",
+    "tracer_patch",
+    "tracer_pre",
+    "barectf_gen_date",
+];
+
+/// Returns true if the content looks like CTF metadata rather than a yaml config
+pub fn is_tsdl_metadata(content: &[u8]) -> bool {
+    is_packetized(content)
+        || String::from_utf8_lossy(content)
+            .trim_start()
+            .starts_with("/* CTF 1.8")
+}
+
+fn is_packetized(content: &[u8]) -> bool {
+    content.starts_with(&PACKETIZED_METADATA_MAGIC.to_le_bytes())
+        || content.starts_with(&PACKETIZED_METADATA_MAGIC.to_be_bytes())
+}
+
+/// Read the barectf configuration from plain-text TSDL metadata
+pub fn config_from_metadata(content: &[u8]) -> Result<BarectfConfig, Error> {
+    if is_packetized(content) {
+        bail!("Packetized CTF metadata is not supported");
+    }
+    let text = std::str::from_utf8(content)
+        .map_err(|e| anyhow!("CTF metadata is not valid UTF-8. {}", e))?;
+
+    let tokens = tokenize(text)?;
+    let metadata = MetadataParser::new(tokens).parse()?;
+    let effective_config = metadata.into_effective_config()?;
+    serde_yaml::from_value(effective_config).map_err(|e| {
+        anyhow!(
+            "Failed to convert CTF metadata to a barectf configuration. {}",
+            e
+        )
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Int(i128),
+    Str(String),
+    Comment(String),
+    Punct(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    tok: Tok,
+    line: usize,
+}
+
+const PUNCTUATION: &[&str] = &[
+    ":=", "...", "{", "}", "(", ")", "[", "]", ";", ",", "=", ":", ".", "-",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '\n' {
+            line += 1;
+            rest = &rest[1..];
+        } else if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            let end = body
+                .find("*/")
+                .ok_or_else(|| anyhow!("line {}: unterminated comment", line))?;
+            let comment = &body[..end];
+            tokens.push(Token {
+                tok: Tok::Comment(comment.trim().to_owned()),
+                line,
+            });
+            line += comment.matches('\n').count();
+            rest = &body[end + 2..];
+        } else if rest.starts_with("//") {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            rest = &rest[end..];
+        } else if c == '"' {
+            let mut s = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((idx, '"')) => break idx + 2,
+                    Some((_, '\\')) => {
+                        if let Some((_, esc)) = chars.next() {
+                            s.push(match esc {
+                                'n' => '\n',
+                                't' => '\t',
+                                other => other,
+                            });
+                        }
+                    }
+                    Some((_, ch)) => s.push(ch),
+                    None => bail!("line {}: unterminated string literal", line),
+                }
+            };
+            tokens.push(Token {
+                tok: Tok::Str(s),
+                line,
+            });
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|ch: char| !ch.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let literal = rest[..end].trim_end_matches(['u', 'U', 'l', 'L']);
+            let val = if let Some(hex) = literal
+                .strip_prefix("0x")
+                .or_else(|| literal.strip_prefix("0X"))
+            {
+                i128::from_str_radix(hex, 16)
+            } else if literal.len() > 1 && literal.starts_with('0') {
+                i128::from_str_radix(&literal[1..], 8)
+            } else {
+                literal.parse::<i128>()
+            }
+            .map_err(|e| anyhow!("line {}: invalid integer '{}'. {}", line, literal, e))?;
+            tokens.push(Token {
+                tok: Tok::Int(val),
+                line,
+            });
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '$' {
+            let end = rest
+                .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_' || ch == '$'))
+                .unwrap_or(rest.len());
+            tokens.push(Token {
+                tok: Tok::Ident(rest[..end].to_owned()),
+                line,
+            });
+            rest = &rest[end..];
+        } else if let Some(p) = PUNCTUATION.iter().find(|p| rest.starts_with(**p)) {
+            tokens.push(Token {
+                tok: Tok::Punct(*p),
+                line,
+            });
+            rest = &rest[p.len()..];
+        } else {
+            bail!("line {}: unexpected character '{}'", line, c);
+        }
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+enum TypeSpec {
+    Integer(Vec<(String, Val)>),
+    FloatingPoint(Vec<(String, Val)>),
+    String,
+    Enum {
+        container: Box<TypeSpec>,
+        mappings: Vec<(String, i128, i128)>,
+    },
+    Struct(Vec<Member>),
+    Alias(String),
+}
+
+#[derive(Clone, Debug)]
+struct Member {
+    name: String,
+    typ: TypeSpec,
+    array_len: Option<ArrayLen>,
+}
+
+#[derive(Clone, Debug)]
+enum ArrayLen {
+    Static(u64),
+    Dynamic(String),
+}
+
+#[derive(Clone, Debug)]
+enum Val {
+    Int(i128),
+    Str(String),
+    Ident(String),
+}
+
+#[derive(Debug, Default)]
+struct Block {
+    /// The comment preceding the block, barectf names data stream types there
+    comment: Option<String>,
+    values: Vec<(String, Val)>,
+    types: Vec<(String, TypeSpec)>,
+}
+
+impl Block {
+    fn value(&self, key: &str) -> Option<&Val> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn int(&self, key: &str) -> Option<i128> {
+        match self.value(key) {
+            Some(Val::Int(i)) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn string(&self, key: &str) -> Option<String> {
+        match self.value(key) {
+            Some(Val::Str(s)) | Some(Val::Ident(s)) => Some(s.clone()),
+            Some(Val::Int(i)) => Some(i.to_string()),
+            None => None,
+        }
+    }
+
+    fn typ(&self, key: &str) -> Option<&TypeSpec> {
+        self.types.iter().find(|(k, _)| k == key).map(|(_, t)| t)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Metadata {
+    aliases: BTreeMap<String, TypeSpec>,
+    trace: Option<Block>,
+    env: Option<Block>,
+    clocks: Vec<Block>,
+    streams: Vec<Block>,
+    events: Vec<Block>,
+}
+
+struct MetadataParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl MetadataParser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens, pos: 0 }
+    }
+
+    fn parse(mut self) -> Result<Metadata, Error> {
+        let mut metadata = Metadata::default();
+        let mut last_comment = None;
+
+        while let Some(token) = self.tokens.get(self.pos).cloned() {
+            match token.tok {
+                Tok::Comment(c) => {
+                    last_comment = Some(c);
+                    self.pos += 1;
+                    continue;
+                }
+                Tok::Punct(";") => {
+                    self.pos += 1;
+                    continue;
+                }
+                Tok::Ident(ref kw) if kw == "typealias" => {
+                    self.pos += 1;
+                    let typ = self.type_spec()?;
+                    self.expect(":=")?;
+                    let mut names = Vec::new();
+                    while let Some(name) = self.try_ident() {
+                        names.push(name);
+                    }
+                    self.expect(";")?;
+                    metadata.aliases.insert(names.join(" "), typ);
+                }
+                Tok::Ident(kw) => {
+                    self.pos += 1;
+                    let mut block = self.block()?;
+                    block.comment = last_comment.take();
+                    match kw.as_str() {
+                        "trace" => metadata.trace = Some(block),
+                        "env" => metadata.env = Some(block),
+                        "clock" => metadata.clocks.push(block),
+                        "stream" => metadata.streams.push(block),
+                        "event" => metadata.events.push(block),
+                        // Callsites and other declarations aren't needed
+                        _ => (),
+                    }
+                }
+                other => bail!("line {}: unexpected token {:?}", token.line, other),
+            }
+            last_comment = None;
+        }
+
+        Ok(metadata)
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        while let Some(token) = self.tokens.get(self.pos) {
+            self.pos += 1;
+            if !matches!(token.tok, Tok::Comment(_)) {
+                return Some(token.clone());
+            }
+        }
+        None
+    }
+
+    fn peek(&mut self) -> Option<&Tok> {
+        while let Some(Token {
+            tok: Tok::Comment(_),
+            ..
+        }) = self.tokens.get(self.pos)
+        {
+            self.pos += 1;
+        }
+        self.tokens.get(self.pos).map(|t| &t.tok)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map(|t| t.line)
+            .unwrap_or(0)
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Tok::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), Error> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            bail!(
+                "line {}: expected '{}', found {:?}",
+                self.line(),
+                punct,
+                self.peek()
+            )
+        }
+    }
+
+    fn try_ident(&mut self) -> Option<String> {
+        match self.peek() {
+            Some(Tok::Ident(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        let line = self.line();
+        self.try_ident()
+            .ok_or_else(|| anyhow!("line {}: expected an identifier", line))
+    }
+
+    fn int(&mut self) -> Result<i128, Error> {
+        let negative = self.eat("-");
+        match self.next_token() {
+            Some(Token {
+                tok: Tok::Int(i), ..
+            }) => Ok(if negative { -i } else { i }),
+            other => bail!(
+                "line {}: expected an integer, found {:?}",
+                self.line(),
+                other.map(|t| t.tok)
+            ),
+        }
+    }
+
+    /// Parses `{ key = value; key := type; ... }`
+    fn block(&mut self) -> Result<Block, Error> {
+        let mut block = Block::default();
+        self.expect("{")?;
+        while !self.eat("}") {
+            let mut key = self.ident()?;
+            while self.eat(".") {
+                key.push('.');
+                key.push_str(&self.ident()?);
+            }
+            if self.eat(":=") {
+                let typ = self.type_spec()?;
+                block.types.push((key, typ));
+            } else {
+                self.expect("=")?;
+                let val = self.value()?;
+                block.values.push((key, val));
+            }
+            self.expect(";")?;
+        }
+        Ok(block)
+    }
+
+    fn value(&mut self) -> Result<Val, Error> {
+        match self.peek() {
+            Some(Tok::Str(s)) => {
+                let s = s.clone();
+                self.pos += 1;
+                Ok(Val::Str(s))
+            }
+            Some(Tok::Ident(_)) => {
+                let mut path = self.ident()?;
+                while self.eat(".") {
+                    path.push('.');
+                    path.push_str(&self.ident()?);
+                }
+                Ok(Val::Ident(path))
+            }
+            _ => Ok(Val::Int(self.int()?)),
+        }
+    }
+
+    fn type_spec(&mut self) -> Result<TypeSpec, Error> {
+        let kw = self.ident()?;
+        match kw.as_str() {
+            "integer" => Ok(TypeSpec::Integer(self.block()?.values)),
+            "floating_point" => Ok(TypeSpec::FloatingPoint(self.block()?.values)),
+            "string" => {
+                if matches!(self.peek(), Some(Tok::Punct("{"))) {
+                    self.block()?;
+                }
+                Ok(TypeSpec::String)
+            }
+            "struct" => {
+                // Optional struct name
+                let _ = self.try_ident();
+                let mut members = Vec::new();
+                self.expect("{")?;
+                while !self.eat("}") {
+                    members.push(self.member()?);
+                }
+                if matches!(self.peek(), Some(Tok::Ident(s)) if s == "align") {
+                    self.pos += 1;
+                    self.expect("(")?;
+                    self.int()?;
+                    self.expect(")")?;
+                }
+                Ok(TypeSpec::Struct(members))
+            }
+            "enum" => {
+                // Optional enum name
+                if matches!(self.peek(), Some(Tok::Ident(_))) {
+                    self.pos += 1;
+                }
+                self.expect(":")?;
+                let container = self.type_spec()?;
+                let mut mappings = Vec::new();
+                let mut next_val = 0;
+                self.expect("{")?;
+                while !self.eat("}") {
+                    let label = match self.next_token().map(|t| t.tok) {
+                        Some(Tok::Ident(s)) | Some(Tok::Str(s)) => s,
+                        other => bail!(
+                            "line {}: expected an enumeration label, found {:?}",
+                            self.line(),
+                            other
+                        ),
+                    };
+                    let (lower, upper) = if self.eat("=") {
+                        let lower = self.int()?;
+                        let upper = if self.eat("...") { self.int()? } else { lower };
+                        (lower, upper)
+                    } else {
+                        (next_val, next_val)
+                    };
+                    next_val = upper + 1;
+                    mappings.push((label, lower, upper));
+                    if !self.eat(",") {
+                        self.expect("}")?;
+                        break;
+                    }
+                }
+                Ok(TypeSpec::Enum {
+                    container: Box::new(container),
+                    mappings,
+                })
+            }
+            _ => {
+                // A type alias, possibly several words
+                let mut name = kw;
+                loop {
+                    let next = match self.peek() {
+                        Some(Tok::Ident(next)) => next.clone(),
+                        _ => break,
+                    };
+                    // Only consume words followed by another word, the last one
+                    // is the member or alias name
+                    if !matches!(
+                        self.tokens.get(self.pos + 1).map(|t| &t.tok),
+                        Some(Tok::Ident(_))
+                    ) {
+                        break;
+                    }
+                    name.push(' ');
+                    name.push_str(&next);
+                    self.pos += 1;
+                }
+                Ok(TypeSpec::Alias(name))
+            }
+        }
+    }
+
+    fn member(&mut self) -> Result<Member, Error> {
+        let typ = self.type_spec()?;
+        let name = self.ident()?;
+        let array_len = if self.eat("[") {
+            let len = match self.peek() {
+                Some(Tok::Ident(_)) => {
+                    let mut path = self.ident()?;
+                    while self.eat(".") {
+                        path.push('.');
+                        path.push_str(&self.ident()?);
+                    }
+                    ArrayLen::Dynamic(path)
+                }
+                _ => ArrayLen::Static(self.int()? as u64),
+            };
+            self.expect("]")?;
+            Some(len)
+        } else {
+            None
+        };
+        self.expect(";")?;
+        Ok(Member {
+            name,
+            typ,
+            array_len,
+        })
+    }
+}
+
+/// Packet context members that map onto barectf data stream type features
+const PACKET_FEATURES: &[(&str, &str)] = &[
+    ("packet_size", "total-size-field-type"),
+    ("content_size", "content-size-field-type"),
+    ("timestamp_begin", "beginning-timestamp-field-type"),
+    ("timestamp_end", "end-timestamp-field-type"),
+    (
+        "events_discarded",
+        "discarded-event-records-counter-snapshot-field-type",
+    ),
+    ("packet_seq_num", "sequence-number-field-type"),
+];
+
+impl Metadata {
+    fn resolve<'a>(&'a self, typ: &'a TypeSpec) -> Result<&'a TypeSpec, Error> {
+        let mut typ = typ;
+        // Bound the lookups in case of alias cycles
+        for _ in 0..32 {
+            match typ {
+                TypeSpec::Alias(name) => {
+                    typ = self
+                        .aliases
+                        .get(name)
+                        .ok_or_else(|| anyhow!("Unknown type alias '{}'", name))?;
+                }
+                _ => return Ok(typ),
+            }
+        }
+        bail!("Type alias cycle detected")
+    }
+
+    /// Returns the clock name mapped by an integer type (`map = clock.NAME.value`)
+    fn mapped_clock(&self, typ: &TypeSpec) -> Option<String> {
+        match self.resolve(typ).ok()? {
+            TypeSpec::Integer(attrs) => attrs.iter().find_map(|(k, v)| match v {
+                Val::Ident(path) if k == "map" => path
+                    .strip_prefix("clock.")
+                    .and_then(|p| p.strip_suffix(".value"))
+                    .map(str::to_owned),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    fn field_type(&self, typ: &TypeSpec) -> Result<Value, Error> {
+        let mut ft = Mapping::new();
+        match self.resolve(typ)? {
+            TypeSpec::Integer(attrs) => {
+                let class = if is_signed(attrs) {
+                    "signed-integer"
+                } else {
+                    "unsigned-integer"
+                };
+                ft.insert("class".into(), class.into());
+                self.integer_attrs(attrs, &mut ft)?;
+            }
+            TypeSpec::FloatingPoint(attrs) => {
+                let get = |key: &str| {
+                    attrs.iter().find_map(|(k, v)| match v {
+                        Val::Int(i) if k == key => Some(*i as u64),
+                        _ => None,
+                    })
+                };
+                let size = get("exp_dig").unwrap_or(0) + get("mant_dig").unwrap_or(0);
+                ft.insert("class".into(), "real".into());
+                ft.insert("size".into(), size.into());
+                ft.insert("alignment".into(), get("align").unwrap_or(8).into());
+            }
+            TypeSpec::String => {
+                ft.insert("class".into(), "string".into());
+            }
+            TypeSpec::Enum {
+                container,
+                mappings,
+            } => {
+                let attrs = match self.resolve(container)? {
+                    TypeSpec::Integer(attrs) => attrs,
+                    _ => bail!("Enumeration container type must be an integer"),
+                };
+                let class = if is_signed(attrs) {
+                    "signed-enumeration"
+                } else {
+                    "unsigned-enumeration"
+                };
+                ft.insert("class".into(), class.into());
+                self.integer_attrs(attrs, &mut ft)?;
+
+                let mut labels = Mapping::new();
+                for (label, lower, upper) in mappings.iter() {
+                    let val: Value = if lower == upper {
+                        int_value(*lower)
+                    } else {
+                        Value::Sequence(vec![int_value(*lower), int_value(*upper)])
+                    };
+                    let key = Value::from(label.as_str());
+                    match labels.get_mut(&key) {
+                        Some(Value::Sequence(vals)) => vals.push(val),
+                        _ => {
+                            labels.insert(key, Value::Sequence(vec![val]));
+                        }
+                    }
+                }
+                ft.insert("mappings".into(), Value::Mapping(labels));
+            }
+            TypeSpec::Struct(members) => {
+                ft.insert("class".into(), "structure".into());
+                ft.insert("members".into(), Value::Sequence(self.members(members)?));
+            }
+            TypeSpec::Alias(_) => unreachable!(),
+        }
+        Ok(Value::Mapping(ft))
+    }
+
+    fn integer_attrs(&self, attrs: &[(String, Val)], ft: &mut Mapping) -> Result<(), Error> {
+        let get = |key: &str| {
+            attrs.iter().find_map(|(k, v)| match v {
+                Val::Int(i) if k == key => Some(*i),
+                _ => None,
+            })
+        };
+        let size = get("size").ok_or_else(|| anyhow!("Integer type is missing a size"))?;
+        ft.insert("size".into(), int_value(size));
+        ft.insert("alignment".into(), int_value(get("align").unwrap_or(8)));
+        let base = attrs.iter().find_map(|(k, v)| match v {
+            Val::Int(i) if k == "base" => Some(i.to_string()),
+            Val::Ident(s) if k == "base" => Some(s.clone()),
+            _ => None,
+        });
+        let display_base = match base.as_deref() {
+            Some("2") | Some("b") | Some("binary") => Some("binary"),
+            Some("8") | Some("o") | Some("oct") | Some("octal") => Some("octal"),
+            Some("16") | Some("x") | Some("X") | Some("p") | Some("hex") | Some("hexadecimal") => {
+                Some("hexadecimal")
+            }
+            _ => None,
+        };
+        if let Some(b) = display_base {
+            ft.insert("preferred-display-base".into(), b.into());
+        }
+        Ok(())
+    }
+
+    fn members(&self, members: &[Member]) -> Result<Vec<Value>, Error> {
+        // barectf generates the length fields of dynamic arrays itself
+        let length_fields: Vec<&str> = members
+            .iter()
+            .filter_map(|m| match &m.array_len {
+                Some(ArrayLen::Dynamic(len)) => Some(len.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        let mut out = Vec::new();
+        for member in members
+            .iter()
+            .filter(|m| !length_fields.contains(&m.name.as_str()))
+        {
+            let ft = self.field_type(&member.typ)?;
+            let ft = match &member.array_len {
+                None => ft,
+                Some(ArrayLen::Static(len)) => {
+                    let mut arr = Mapping::new();
+                    arr.insert("class".into(), "static-array".into());
+                    arr.insert("length".into(), (*len).into());
+                    arr.insert("element-field-type".into(), ft);
+                    Value::Mapping(arr)
+                }
+                Some(ArrayLen::Dynamic(_)) => {
+                    let mut arr = Mapping::new();
+                    arr.insert("class".into(), "dynamic-array".into());
+                    arr.insert("element-field-type".into(), ft);
+                    Value::Mapping(arr)
+                }
+            };
+            let mut field = Mapping::new();
+            field.insert("field-type".into(), ft);
+            let mut entry = Mapping::new();
+            entry.insert(member.name.as_str().into(), Value::Mapping(field));
+            out.push(Value::Mapping(entry));
+        }
+        Ok(out)
+    }
+
+    fn struct_members<'a>(&'a self, typ: &'a TypeSpec) -> Result<&'a [Member], Error> {
+        match self.resolve(typ)? {
+            TypeSpec::Struct(members) => Ok(members),
+            _ => bail!("Expected a structure type"),
+        }
+    }
+
+    fn into_effective_config(self) -> Result<Value, Error> {
+        let trace = self
+            .trace
+            .as_ref()
+            .ok_or_else(|| anyhow!("CTF metadata is missing the trace block"))?;
+
+        // Trace type
+        let mut trace_type = Mapping::new();
+        let byte_order = match trace.string("byte_order").as_deref() {
+            Some("be") | Some("network") => "big-endian",
+            _ => "little-endian",
+        };
+        trace_type.insert("native-byte-order".into(), byte_order.into());
+        if let Some(uuid) = trace.string("uuid") {
+            trace_type.insert("uuid".into(), uuid.into());
+        }
+        let mut trace_features = Mapping::new();
+        if let Some(header) = trace.typ("packet.header") {
+            for member in self.struct_members(header)? {
+                match member.name.as_str() {
+                    "magic" => {
+                        trace_features
+                            .insert("magic-field-type".into(), self.field_type(&member.typ)?);
+                    }
+                    "uuid" => {
+                        trace_features.insert("uuid-field-type".into(), true.into());
+                    }
+                    "stream_id" => {
+                        trace_features.insert(
+                            "data-stream-type-id-field-type".into(),
+                            self.field_type(&member.typ)?,
+                        );
+                    }
+                    _ => (),
+                }
+            }
+        }
+        trace_type.insert("$features".into(), Value::Mapping(trace_features));
+
+        // Clock types
+        let mut clock_types = Mapping::new();
+        for clock in self.clocks.iter() {
+            let name = clock
+                .string("name")
+                .ok_or_else(|| anyhow!("CTF metadata clock is missing a name"))?;
+            let mut ct = Mapping::new();
+            if let Some(uuid) = clock.string("uuid") {
+                ct.insert("uuid".into(), uuid.into());
+            }
+            if let Some(desc) = clock.string("description") {
+                ct.insert("description".into(), desc.into());
+            }
+            ct.insert("$c-type".into(), "uint64_t".into());
+            ct.insert(
+                "frequency".into(),
+                int_value(clock.int("freq").unwrap_or(1_000_000_000)),
+            );
+            ct.insert(
+                "precision".into(),
+                int_value(clock.int("precision").unwrap_or(0)),
+            );
+            let offset_seconds = clock.int("offset_s").unwrap_or(0);
+            let offset_cycles = clock.int("offset").unwrap_or(0);
+            if offset_seconds != 0 || offset_cycles != 0 {
+                let mut offset = Mapping::new();
+                offset.insert("seconds".into(), int_value(offset_seconds));
+                offset.insert("cycles".into(), int_value(offset_cycles));
+                ct.insert("offset".into(), Value::Mapping(offset));
+            }
+            let absolute = clock.value("absolute").map(is_true).unwrap_or(false);
+            ct.insert("origin-is-unix-epoch".into(), absolute.into());
+            clock_types.insert(name.into(), Value::Mapping(ct));
+        }
+        trace_type.insert("clock-types".into(), Value::Mapping(clock_types));
+
+        // Data stream types, in ID order
+        let mut streams: Vec<(i128, &Block)> = self
+            .streams
+            .iter()
+            .map(|s| (s.int("id").unwrap_or(0), s))
+            .collect();
+        streams.sort_by_key(|(id, _)| *id);
+
+        let mut data_stream_types = Mapping::new();
+        for (stream_id, stream) in streams.into_iter() {
+            let name = stream
+                .comment
+                .as_deref()
+                .and_then(data_stream_type_name)
+                .unwrap_or_else(|| format!("stream_{}", stream_id));
+            let mut dst = Mapping::new();
+            let mut default_clock = None;
+
+            let mut packet_features = Mapping::new();
+            let mut extra_members = Vec::new();
+            if let Some(ctx) = stream.typ("packet.context") {
+                for member in self.struct_members(ctx)? {
+                    if let Some((_, feature)) =
+                        PACKET_FEATURES.iter().find(|(m, _)| *m == member.name)
+                    {
+                        if default_clock.is_none() {
+                            default_clock = self.mapped_clock(&member.typ);
+                        }
+                        packet_features.insert((*feature).into(), self.field_type(&member.typ)?);
+                    } else {
+                        extra_members.extend(self.members(std::slice::from_ref(member))?);
+                    }
+                }
+            }
+
+            let mut event_record_features = Mapping::new();
+            if let Some(header) = stream.typ("event.header") {
+                for member in self.struct_members(header)? {
+                    match member.name.as_str() {
+                        "id" => {
+                            event_record_features
+                                .insert("type-id-field-type".into(), self.field_type(&member.typ)?);
+                        }
+                        "timestamp" => {
+                            if let Some(clock) = self.mapped_clock(&member.typ) {
+                                default_clock = Some(clock);
+                            }
+                            event_record_features.insert(
+                                "timestamp-field-type".into(),
+                                self.field_type(&member.typ)?,
+                            );
+                        }
+                        _ => (),
+                    }
+                }
+            }
+
+            if let Some(clock) = default_clock {
+                dst.insert("$default-clock-type-name".into(), clock.into());
+            }
+            let mut dst_features = Mapping::new();
+            dst_features.insert("packet".into(), Value::Mapping(packet_features));
+            dst_features.insert("event-record".into(), Value::Mapping(event_record_features));
+            dst.insert("$features".into(), Value::Mapping(dst_features));
+            if !extra_members.is_empty() {
+                dst.insert(
+                    "packet-context-field-type-extra-members".into(),
+                    Value::Sequence(extra_members),
+                );
+            }
+            if let Some(ctx) = stream.typ("event.context") {
+                dst.insert(
+                    "event-record-common-context-field-type".into(),
+                    self.field_type(ctx)?,
+                );
+            }
+
+            // Event record types, in ID order
+            let mut events: Vec<(i128, &Block)> = self
+                .events
+                .iter()
+                .filter(|ev| ev.int("stream_id").unwrap_or(0) == stream_id)
+                .map(|ev| (ev.int("id").unwrap_or(0), ev))
+                .collect();
+            events.sort_by_key(|(id, _)| *id);
+
+            let mut event_record_types = Mapping::new();
+            for (_, event) in events.into_iter() {
+                let ev_name = event
+                    .string("name")
+                    .ok_or_else(|| anyhow!("CTF metadata event is missing a name"))?;
+                let mut ert = Mapping::new();
+                if let Some(ll) = event.int("loglevel") {
+                    ert.insert("log-level".into(), int_value(ll));
+                }
+                if let Some(ctx) = event.typ("context") {
+                    ert.insert("specific-context-field-type".into(), self.field_type(ctx)?);
+                }
+                if let Some(fields) = event.typ("fields") {
+                    // barectf emits an empty structure for events without a payload
+                    if !self.struct_members(fields)?.is_empty() {
+                        ert.insert("payload-field-type".into(), self.field_type(fields)?);
+                    }
+                }
+                event_record_types.insert(ev_name.into(), Value::Mapping(ert));
+            }
+            dst.insert(
+                "event-record-types".into(),
+                Value::Mapping(event_record_types),
+            );
+
+            data_stream_types.insert(name.into(), Value::Mapping(dst));
+        }
+        trace_type.insert(
+            "data-stream-types".into(),
+            Value::Mapping(data_stream_types),
+        );
+
+        // Environment
+        let mut environment = Mapping::new();
+        if let Some(env) = self.env.as_ref() {
+            for (key, val) in env.values.iter() {
+                if GENERATED_ENV_KEYS.contains(&key.as_str()) {
+                    continue;
+                }
+                let val = match val {
+                    Val::Int(i) => int_value(*i),
+                    Val::Str(s) | Val::Ident(s) => s.as_str().into(),
+                };
+                environment.insert(key.as_str().into(), val);
+            }
+        }
+
+        let mut trace_cfg = Mapping::new();
+        trace_cfg.insert("environment".into(), Value::Mapping(environment));
+        trace_cfg.insert("type".into(), Value::Mapping(trace_type));
+        let mut cfg = Mapping::new();
+        cfg.insert("trace".into(), Value::Mapping(trace_cfg));
+        Ok(Value::Mapping(cfg))
+    }
+}
+
+fn is_true(val: &Val) -> bool {
+    match val {
+        Val::Ident(s) => s == "true" || s == "TRUE",
+        Val::Int(i) => *i != 0,
+        Val::Str(_) => false,
+    }
+}
+
+fn is_signed(integer_attrs: &[(String, Val)]) -> bool {
+    integer_attrs
+        .iter()
+        .any(|(k, v)| k == "signed" && is_true(v))
+}
+
+/// barectf precedes each stream block with a "Data stream type `NAME`" comment
+fn data_stream_type_name(comment: &str) -> Option<String> {
+    let rest = comment.split("Data stream type `").nth(1)?;
+    rest.split('`').next().map(str::to_owned)
+}
+
+fn int_value(i: i128) -> Value {
+    if let Ok(u) = u64::try_from(i) {
+        u.into()
+    } else {
+        (i as i64).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{convert::EventExt, fixtures};
+    use barectf_parser::Parser;
+    use tokio_util::{bytes::BytesMut, codec::Decoder};
+
+    const METADATA: &[u8] = include_bytes!("../integration-test/metadata");
+
+    fn effective_config(metadata: &[u8]) -> Value {
+        let text = std::str::from_utf8(metadata).unwrap();
+        MetadataParser::new(tokenize(text).unwrap())
+            .parse()
+            .unwrap()
+            .into_effective_config()
+            .unwrap()
+    }
+
+    /// The integration test effective-configuration the metadata was generated from
+    fn fixture_effective_config() -> Value {
+        let yaml = std::str::from_utf8(fixtures::CONFIG_YAML)
+            .unwrap()
+            .replace("--- !<tag:barectf.org,2020/3/config>", "---");
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    fn stream_type(cfg: &Value) -> &Value {
+        stream_type_named(&cfg["trace"]["type"], "default")
+    }
+
+    fn stream_type_named<'a>(trace_type: &'a Value, name: &str) -> &'a Value {
+        &trace_type["data-stream-types"][name]
+    }
+
+    #[test]
+    fn detects_metadata() {
+        assert!(is_tsdl_metadata(METADATA));
+        assert!(is_tsdl_metadata(&PACKETIZED_METADATA_MAGIC.to_le_bytes()));
+        assert!(!is_tsdl_metadata(fixtures::CONFIG_YAML));
+    }
+
+    #[test]
+    fn packetized_metadata_is_not_supported() {
+        let err = config_from_metadata(&PACKETIZED_METADATA_MAGIC.to_be_bytes()).unwrap_err();
+        assert!(err.to_string().contains("Packetized"));
+    }
+
+    #[test]
+    fn trace_type() {
+        let cfg = effective_config(METADATA);
+        let expected = fixture_effective_config();
+        let trace_type = &cfg["trace"]["type"];
+        let expected_trace_type = &expected["trace"]["type"];
+        for key in ["native-byte-order", "uuid", "$features"] {
+            assert_eq!(trace_type[key], expected_trace_type[key], "{key}");
+        }
+        assert_eq!(
+            trace_type["clock-types"]["default"]["frequency"],
+            expected_trace_type["clock-types"]["default"]["frequency"]
+        );
+        assert_eq!(
+            trace_type["clock-types"]["default"]["uuid"],
+            expected_trace_type["clock-types"]["default"]["uuid"]
+        );
+    }
+
+    #[test]
+    fn environment_excludes_generated_entries() {
+        let cfg = effective_config(METADATA);
+        let expected = fixture_effective_config();
+        assert_eq!(
+            cfg["trace"]["environment"],
+            expected["trace"]["environment"]
+        );
+    }
+
+    #[test]
+    fn data_stream_type() {
+        let cfg = effective_config(METADATA);
+        let expected = fixture_effective_config();
+        let dst = stream_type(&cfg);
+        let expected_dst = stream_type(&expected);
+
+        assert_eq!(
+            dst["$default-clock-type-name"],
+            expected_dst["$default-clock-type-name"]
+        );
+        for feature in ["packet", "event-record"] {
+            let expected_features = expected_dst["$features"][feature].as_mapping().unwrap();
+            assert_eq!(
+                dst["$features"][feature].as_mapping().unwrap().len(),
+                expected_features.len()
+            );
+            for (key, ft) in expected_features.iter() {
+                assert_eq!(&dst["$features"][feature][key], ft, "{key:?}");
+            }
+        }
+        for key in [
+            "packet-context-field-type-extra-members",
+            "event-record-common-context-field-type",
+        ] {
+            assert_eq!(dst[key], expected_dst[key], "{key}");
+        }
+    }
+
+    #[test]
+    fn event_record_types() {
+        let cfg = effective_config(METADATA);
+        let expected = fixture_effective_config();
+        let erts = stream_type(&cfg)["event-record-types"]
+            .as_mapping()
+            .unwrap();
+        let expected_erts = stream_type(&expected)["event-record-types"]
+            .as_mapping()
+            .unwrap();
+
+        assert_eq!(erts.len(), expected_erts.len());
+        for (name, ert) in expected_erts.iter() {
+            assert_eq!(&erts[name], ert, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn decodes_the_stream_like_the_effective_config() {
+        let from_metadata = config_from_metadata(METADATA).unwrap();
+        let mut decoder = Parser::new(&from_metadata).unwrap().into_packet_decoder();
+        let mut buf = BytesMut::from(fixtures::STREAM);
+
+        for expected in fixtures::packets().into_iter() {
+            let pkt = decoder.decode(&mut buf).unwrap().unwrap();
+            assert_eq!(pkt.header.stream_name, expected.header.stream_name);
+            assert_eq!(pkt.context.event_attrs(), expected.context.event_attrs());
+            assert_eq!(pkt.events.len(), expected.events.len());
+            for (ev, expected_ev) in pkt.events.iter().zip(expected.events.iter()) {
+                assert_eq!(ev.event_attrs(), expected_ev.event_attrs());
+            }
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn stream_name_defaults_to_the_id() {
+        let metadata = String::from_utf8_lossy(METADATA).replace("Data stream type", "Stream");
+        let cfg = effective_config(metadata.as_bytes());
+        assert!(cfg["trace"]["type"]["data-stream-types"]
+            .get("stream_0")
+            .is_some());
+    }
+
+    #[test]
+    fn type_aliases() {
+        let metadata = r#"/* CTF 1.8 */
+            typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+            typealias integer { size = 16; align = 16; signed = true; } := int16_t;
+            trace { byte_order = be; packet.header := struct { uint8_t stream_id; }; };
+            stream { id = 0; event.header := struct { uint8_t id; }; };
+            event { name = "ev"; id = 0; stream_id = 0; fields := struct { int16_t val; }; };
+        "#;
+        let cfg = effective_config(metadata.as_bytes());
+        let trace_type = &cfg["trace"]["type"];
+        assert_eq!(trace_type["native-byte-order"], "big-endian");
+        assert_eq!(
+            trace_type["$features"]["data-stream-type-id-field-type"]["size"],
+            8
+        );
+        let val = &stream_type_named(trace_type, "stream_0")["event-record-types"]["ev"]
+            ["payload-field-type"]["members"][0]["val"]["field-type"];
+        assert_eq!(val["class"], "signed-integer");
+        assert_eq!(val["size"], 16);
+        assert_eq!(val["alignment"], 16);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let err = config_from_metadata(b"/* CTF 1.8 */\ntrace {\n  byte_order = le\n};")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 4"), "{err}");
+
+        let err = config_from_metadata(b"/* CTF 1.8 */\n\ntrace { uuid = \"abc };")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 3"), "{err}");
+    }
+
+    #[test]
+    fn missing_trace_block() {
+        let err = config_from_metadata(b"/* CTF 1.8 */ env { a = 1; };").unwrap_err();
+        assert!(err.to_string().contains("trace block"));
+    }
+}